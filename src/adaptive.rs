@@ -0,0 +1,79 @@
+// Adaptive execution: for small tables generating code takes longer than just interpreting the query,
+// for large ones the interpreter is much slower than the generated code. So the query is interpreted
+// morsel by morsel while its code is generated on a background thread, and once the code is ready
+// it does the remaining rows. The aggregate state of the rows the interpreter already did is handed
+// over to the generated code, so the result is the same as if either of them did all of the rows.
+
+use std::{fmt::{self, Display, Formatter}, thread, time::{Duration, Instant}};
+
+use crate::{codegen::{CodegenCFunctionSignature, GeneratedCode}, query::{run_rows, AggregateFunc, AggregateState, Atom, Query}, query_codegen::{check_results_not_null, consume_result, generate_resumable_code, CodeGenError, CodeGenOptions}, table::Table};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveStats {
+    pub interpreted_rows: usize,
+    pub compiled_rows: usize,
+    /// How long generating the code took, if it was used
+    pub codegen_time: Option<Duration>,
+}
+
+impl Display for AdaptiveStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.codegen_time {
+            Some(codegen_time) => write!(f, "Interpreted {} rows, then switched to the code generated in {:?} for the remaining {} rows", self.interpreted_rows, codegen_time, self.compiled_rows),
+            None => write!(f, "Interpreted all {} rows before the code was generated", self.interpreted_rows),
+        }
+    }
+}
+
+/// Runs a bound query without parameters on `table`, `morsel_size` rows at a time until the code
+/// for it is ready. Results (of the interpreter as well as of the generated code) go to `result_consumer`.
+pub fn run_adaptive(query: &Query, table: &Table, options: &CodeGenOptions, morsel_size: usize, result_consumer: CodegenCFunctionSignature) -> Result<AdaptiveStats, CodeGenError> {
+    // Errors have to show up before the interpreter produces any results
+    query.check_types(table.schema(), None).map_err(CodeGenError::TypeError)?;
+    check_results_not_null(query, table.schema())?;
+    if let Some(name) = query.parameters().into_iter().next() {
+        return Err(CodeGenError::UnboundParameter(name));
+    }
+    // The generated code would have to resume sorting or counting results where the interpreter left
+    // off, or to build the hash table of a join again
+    if query.order_by.is_some() || query.limit.is_some() || query.join.is_some() {
+        return Err(CodeGenError::Unsupported("Queries with a join, `order by` or `limit` can't switch to generated code"));
+    }
+
+    let codegen = {
+        let (query, schema, layout, options) = (query.clone(), table.schema().clone(), table.layout(), options.clone());
+        thread::spawn(move || {
+            let start = Instant::now();
+            generate_resumable_code(&query, &schema, layout, &options, result_consumer).map(|code| (code, start.elapsed()))
+        })
+    };
+
+    let mut consume = |result: Atom| consume_result(result_consumer, result);
+    let mut state = AggregateState::new(query.aggregate);
+    let mut row = 0;
+    while row < table.rows() && !codegen.is_finished() {
+        let end = (row + morsel_size.max(1)).min(table.rows());
+        run_rows(query, table, row..end, &mut state, &mut consume).map_err(CodeGenError::Eval)?;
+        row = end;
+    }
+    if row == table.rows() {
+        // The code isn't needed anymore, the thread generating it finishes on its own
+        state.finish(&mut consume);
+        return Ok(AdaptiveStats { interpreted_rows: row, compiled_rows: 0, codegen_time: None });
+    }
+
+    let (code, codegen_time) = codegen.join().expect("Generating code panicked")?;
+    run_resumable(&code, table, row, state);
+    Ok(AdaptiveStats { interpreted_rows: row, compiled_rows: table.rows() - row, codegen_time: Some(codegen_time) })
+}
+
+/// Runs code generated by `generate_resumable_code` for the rows from `start` on, continuing the
+/// aggregation of the rows before them
+pub fn run_resumable(code: &GeneratedCode, table: &Table, start: usize, mut state: AggregateState) {
+    let mut args = table.call_args_for(start..table.rows());
+    args.push(match state.aggregate {
+        Some(AggregateFunc::CountDistinct) => &mut state.distinct as *mut _ as usize,
+        _ => state.values.as_mut_ptr() as usize,
+    });
+    code.call(&args);
+}
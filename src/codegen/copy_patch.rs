@@ -5,7 +5,7 @@ use crate::codegen::stencils::{compile_all_stencils, Stencil, RelocType};
 #[cfg(feature = "print-asm")]
 use super::disassemble;
 
-use super::{ir::{ConstValue, DataType}, stencils::{StencilOperation, StencilType}, GeneratedCode};
+use super::{generated_code::{ConstHandle, ConstHole}, ir::{ConstValue, DataType}, stencils::{StencilOperation, StencilType}, GeneratedCode};
 use lazy_static::lazy_static;
 use libc::c_void;
 
@@ -18,6 +18,7 @@ lazy_static! {
 pub struct CopyPatchBackend {
     code: RefCell<Vec<u8>>,
     fixup_holes: RefCell<Vec<usize>>,
+    const_holes: RefCell<Vec<ConstHole>>,
 }
 
 // Example of how to emit control flow constructs
@@ -45,12 +46,14 @@ impl CopyPatchBackend {
         Self {
             code: RefCell::new(Vec::new()),
             fixup_holes: RefCell::new(Vec::new()),
+            const_holes: RefCell::new(Vec::new()),
         }
     }
 
     pub fn reset(&self) {
         self.code.borrow_mut().clear();
         self.fixup_holes.borrow_mut().clear();
+        self.const_holes.borrow_mut().clear();
     }   
 
     fn copy_and_patch(&self, stencil: &Stencil, holes_values: Vec<u64>) {
@@ -102,6 +105,21 @@ impl CopyPatchBackend {
         }
     }
 
+    /// Copies a stencil whose only hole is for a constant and remembers where the constant went,
+    /// so it can be patched again in the generated code. Returns the handle of the hole, if the
+    /// stencil has one the constant could be patched into.
+    fn copy_and_patch_const(&self, stencil: &Stencil, c: ConstValue) -> Option<ConstHandle> {
+        let start_ofs = self.code.borrow().len();
+        self.copy_and_patch(stencil, vec![c.bitcast_to_u64()]);
+        let reloc = *stencil.holes.first()?;
+        if reloc.offset + reloc.reloc_type.get_hole_len() > stencil.code.len() {
+            return None;
+        }
+        let mut const_holes = self.const_holes.borrow_mut();
+        const_holes.push(ConstHole { offset: start_ofs + reloc.offset, reloc_type: reloc.reloc_type, value: c });
+        Some(ConstHandle(const_holes.len() - 1))
+    }
+
     pub fn emit_take_stack(&self, n: usize) {
         self.emit_take_1_stack(n);
     }
@@ -138,18 +156,16 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
-    pub fn emit_take_1_const(&self, ir_const: ConstValue) {
+    pub fn emit_take_1_const(&self, ir_const: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::Take1Const, Some(ir_const.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![ir_const.bitcast_to_u64()];
-        self.copy_and_patch(stencil, holes_values);
+        self.copy_and_patch_const(stencil, ir_const)
     }
 
-    pub fn emit_take_2_const(&self, ir_const: ConstValue) {
+    pub fn emit_take_2_const(&self, ir_const: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::Take2Const, Some(ir_const.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![ir_const.bitcast_to_u64()];
-        self.copy_and_patch(stencil, holes_values);
+        self.copy_and_patch_const(stencil, ir_const)
     }
 
     pub fn emit_add(&self, data_type: DataType) {
@@ -159,11 +175,10 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
-    pub fn emit_add_const(&self, n: ConstValue) {
+    pub fn emit_add_const(&self, n: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::AddConst, Some(n.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![n.bitcast_to_u64()];
-        self.copy_and_patch(stencil, holes_values);
+        self.copy_and_patch_const(stencil, n)
     }
 
     pub fn emit_mul(&self, data_type: DataType) {
@@ -173,11 +188,10 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
-    pub fn emit_mul_const(&self, n: ConstValue) {
+    pub fn emit_mul_const(&self, n: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::MulConst, Some(n.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![n.bitcast_to_u64()];
-        self.copy_and_patch(stencil, holes_values);
+        self.copy_and_patch_const(stencil, n)
     }
 
     pub fn emit_sub(&self, data_type: DataType) {
@@ -187,11 +201,10 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
-    pub fn emit_sub_const(&self, n: ConstValue) {
+    pub fn emit_sub_const(&self, n: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::SubConst, Some(n.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![n.bitcast_to_u64()];
-        self.copy_and_patch(stencil, holes_values);
+        self.copy_and_patch_const(stencil, n)
     }
 
     pub fn emit_div(&self, data_type: DataType) {
@@ -201,11 +214,10 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
-    pub fn emit_div_const(&self, n: ConstValue) {
+    pub fn emit_div_const(&self, n: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::DivConst, Some(n.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![n.bitcast_to_u64()];
-        self.copy_and_patch(stencil, holes_values);
+        self.copy_and_patch_const(stencil, n)
     }
 
     pub fn emit_rem(&self, data_type: DataType) {
@@ -215,11 +227,10 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
-    pub fn emit_rem_const(&self, n: ConstValue) {
+    pub fn emit_rem_const(&self, n: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::RemConst, Some(n.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![n.bitcast_to_u64()];
-        self.copy_and_patch(stencil, holes_values);
+        self.copy_and_patch_const(stencil, n)
     }
 
     pub fn emit_eq(&self, data_type: DataType) {
@@ -229,11 +240,10 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
-    pub fn emit_eq_const(&self, n: ConstValue) {
+    pub fn emit_eq_const(&self, n: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::EqConst, Some(n.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![n.bitcast_to_u64()];
-        self.copy_and_patch(stencil, holes_values);
+        self.copy_and_patch_const(stencil, n)
     }
 
     pub fn emit_neq(&self, data_type: DataType) {
@@ -243,11 +253,10 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
-    pub fn emit_neq_const(&self, n: ConstValue) {
+    pub fn emit_neq_const(&self, n: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::NeConst, Some(n.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![n.bitcast_to_u64()];
-        self.copy_and_patch(stencil, holes_values);
+        self.copy_and_patch_const(stencil, n)
     }
 
     pub fn emit_lt(&self, data_type: DataType) {
@@ -257,11 +266,10 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
-    pub fn emit_lt_const(&self, n: ConstValue) {
+    pub fn emit_lt_const(&self, n: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::LtConst, Some(n.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![n.bitcast_to_u64()];
-        self.copy_and_patch(stencil, holes_values);
+        self.copy_and_patch_const(stencil, n)
     }
 
     pub fn emit_lte(&self, data_type: DataType) {
@@ -271,11 +279,10 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
-    pub fn emit_lte_const(&self, n: ConstValue) {
+    pub fn emit_lte_const(&self, n: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::LteConst, Some(n.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![n.bitcast_to_u64()];
-        self.copy_and_patch(stencil, holes_values);
+        self.copy_and_patch_const(stencil, n)
     }
 
     pub fn emit_gt(&self, data_type: DataType) {
@@ -285,11 +292,10 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
-    pub fn emit_gt_const(&self, n: ConstValue) {
+    pub fn emit_gt_const(&self, n: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::GtConst, Some(n.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![n.bitcast_to_u64()];
-        self.copy_and_patch(stencil, holes_values);
+        self.copy_and_patch_const(stencil, n)
     }
 
     pub fn emit_gte(&self, data_type: DataType) {
@@ -299,11 +305,10 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
-    pub fn emit_gte_const(&self, n: ConstValue) {
+    pub fn emit_gte_const(&self, n: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::GteConst, Some(n.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![n.bitcast_to_u64()];
-        self.copy_and_patch(stencil, holes_values);
+        self.copy_and_patch_const(stencil, n)
     }
 
     pub fn emit_and(&self, data_type: DataType) {
@@ -313,11 +318,10 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
-    pub fn emit_and_const(&self, n: ConstValue) {
+    pub fn emit_and_const(&self, n: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::AndConst, Some(n.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![n.bitcast_to_u64()];
-        self.copy_and_patch(stencil, holes_values);
+        self.copy_and_patch_const(stencil, n)
     }
 
     pub fn emit_or(&self, data_type: DataType) {
@@ -327,13 +331,38 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
-    pub fn emit_or_const(&self, n: ConstValue) {
+    pub fn emit_or_const(&self, n: ConstValue) -> Option<ConstHandle> {
         let s_type = StencilType::new(StencilOperation::OrConst, Some(n.get_type()));
         let stencil = STENCILS.get(&s_type).unwrap();
-        let holes_values = vec![n.bitcast_to_u64()];
+        self.copy_and_patch_const(stencil, n)
+    }
+
+    pub fn emit_shl(&self, data_type: DataType) {
+        let s_type = StencilType::new(StencilOperation::Shl, Some(data_type));
+        let stencil = STENCILS.get(&s_type).unwrap();
+        let holes_values = vec![];
         self.copy_and_patch(stencil, holes_values);
     }
 
+    pub fn emit_shl_const(&self, n: ConstValue) -> Option<ConstHandle> {
+        let s_type = StencilType::new(StencilOperation::ShlConst, Some(n.get_type()));
+        let stencil = STENCILS.get(&s_type).unwrap();
+        self.copy_and_patch_const(stencil, n)
+    }
+
+    pub fn emit_shr(&self, data_type: DataType) {
+        let s_type = StencilType::new(StencilOperation::Shr, Some(data_type));
+        let stencil = STENCILS.get(&s_type).unwrap();
+        let holes_values = vec![];
+        self.copy_and_patch(stencil, holes_values);
+    }
+
+    pub fn emit_shr_const(&self, n: ConstValue) -> Option<ConstHandle> {
+        let s_type = StencilType::new(StencilOperation::ShrConst, Some(n.get_type()));
+        let stencil = STENCILS.get(&s_type).unwrap();
+        self.copy_and_patch_const(stencil, n)
+    }
+
     pub fn emit_not(&self, data_type: DataType) {
         let s_type = StencilType::new(StencilOperation::Not, Some(data_type));
         let stencil = STENCILS.get(&s_type).unwrap();
@@ -341,6 +370,13 @@ impl CopyPatchBackend {
         self.copy_and_patch(stencil, holes_values);
     }
 
+    pub fn emit_extend(&self, data_type: DataType) {
+        let s_type = StencilType::new(StencilOperation::Extend, Some(data_type));
+        let stencil = STENCILS.get(&s_type).unwrap();
+        let holes_values = vec![];
+        self.copy_and_patch(stencil, holes_values);
+    }
+
     pub fn emit_duplex1(&self) {
         let s_type = StencilType::new(StencilOperation::Duplex1, None);
         let stencil = STENCILS.get(&s_type).unwrap();
@@ -487,7 +523,7 @@ impl CopyPatchBackend {
     
         let ghc_stencil = STENCILS.get(&StencilType::new(StencilOperation::GhcWrapper, None)).unwrap();
     
-        let gc = GeneratedCode::new(stack_size, ghc_stencil, &self.code.borrow());
+        let gc = GeneratedCode::new(stack_size, ghc_stencil, &self.code.borrow(), self.const_holes.borrow().clone());
     
         // Fix up the holes that need an absolute address
         // TODO: Find a nicer solution for this
@@ -499,6 +535,7 @@ impl CopyPatchBackend {
                 addr.write_unaligned(new_addr);           
             }
         }
+        gc.make_executable();
     
     #[cfg(feature = "print-asm")]
         {
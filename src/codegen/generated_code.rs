@@ -2,34 +2,68 @@
 //       We should also have a way to represent/address values so that we can insert
 //       put/take instructions automatically and so that we can also map the same logic to LLVM IR
 
-use std::os::raw::c_void;
+use std::{any::Any, os::raw::c_void};
 
 use crate::codegen::stencils::RelocType;
 
-use super::stencils::Stencil;
+use super::{get_data_type_size, ir::ConstValue, stencils::Stencil};
+
+/// A stencil hole that a constant was patched into
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ConstHole {
+    /// Offset of the hole from the start of the code
+    pub offset: usize,
+    pub reloc_type: RelocType,
+    /// The value that is currently in the hole
+    pub value: ConstValue,
+}
+
+/// Identifies one of the constant holes of the generated code, see `GeneratedCode::patch_const`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ConstHandle(pub(super) usize);
+
+/// Stack space for a call of generated code, see `GeneratedCode::call_on`
+pub struct CallStack {
+    ptr: *mut u8,
+    size: usize,
+}
+
+impl CallStack {
+    fn new(size: usize) -> Self {
+        let ptr = map(size) as *mut u8;
+        CallStack { ptr, size }
+    }
+}
+
+impl Drop for CallStack {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.size);
+        }
+    }
+}
 
 pub struct GeneratedCode {
     pub stack: *mut u8,
+    stack_size: usize,
     pub code: *const c_void,
     pub code_len: usize,
     pub ghcc_code: *const c_void,
+    ghcc_len: usize,
+    // State referenced by pointer constants in the code (e.g. hash sets for extern calls)
+    keep_alive: Vec<Box<dyn Any + Send>>,
+    const_holes: Vec<ConstHole>,
+    /// The holes of the constants of `CodeGen::new_patchable_i64`, in the order they were created
+    pub(super) patchable_consts: Vec<ConstHandle>,
 }
 
 impl GeneratedCode {
 
-    pub fn new(stack_size: usize, wrapper_stencil: &Stencil, code: &[u8]) -> Self {
-
-        // mmap a memory region with read and execute permissions
-        let mmap = unsafe {
-            libc::mmap(
-                std::ptr::null_mut(),
-                code.len(),
-                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
-                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
-                -1,
-                0,
-            )
-        };
+    /// The code is writable until `make_executable` is called, it is never writable and executable at the same time
+    pub fn new(stack_size: usize, wrapper_stencil: &Stencil, code: &[u8], const_holes: Vec<ConstHole>) -> Self {
+
+        // mmap a memory region with read and write permissions, it becomes executable once we are done writing to it
+        let mmap = map(code.len());
 
         let mut ghcc_code = wrapper_stencil.code.clone();
 
@@ -40,33 +74,16 @@ impl GeneratedCode {
             ghcc_code[reloc.offset..reloc.offset + 8].copy_from_slice(val);
         }
 
-        let ghcc_fun = unsafe {
-            libc::mmap(
-                std::ptr::null_mut(),
-                ghcc_code.len(),
-                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
-                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
-                -1,
-                0,
-            )
-        };
+        let ghcc_fun = map(ghcc_code.len());
 
         // Allocate stack space for our generated code
         // TODO: We could (and maybe should) also use the actual stack for this
-        let stack_space = unsafe {
-            libc::mmap(
-                std::ptr::null_mut(),
-                stack_size,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
-                -1,
-                0,
-            ) as *mut u8
-        };
+        let stack_space = map(stack_size) as *mut u8;
     
         unsafe {
             std::ptr::copy_nonoverlapping(ghcc_code.as_ptr(), ghcc_fun as *mut u8, ghcc_code.len());
         }
+        protect(ghcc_fun, ghcc_code.len(), libc::PROT_READ | libc::PROT_EXEC);
     
         // copy the code to the memory region
         unsafe {
@@ -75,20 +92,48 @@ impl GeneratedCode {
 
         Self {
             stack: stack_space,
+            stack_size,
             code: mmap,
             code_len: code.len(),
             ghcc_code: ghcc_fun,
+            ghcc_len: ghcc_code.len(),
+            keep_alive: Vec::new(),
+            const_holes,
+            patchable_consts: Vec::new(),
         }
     }
+
+    /// Makes the code executable (and read only) once all holes are filled
+    pub fn make_executable(&self) {
+        protect(self.code, self.code_len, libc::PROT_READ | libc::PROT_EXEC);
+    }
     
     pub fn call(&self, args: &[usize]) -> *mut u8 {
+        self.call_with_stack(self.stack, args)
+    }
+
+    /// A stack that is big enough for this code
+    pub fn new_stack(&self) -> CallStack {
+        CallStack::new(self.stack_size)
+    }
+
+    /// Like `call`, but uses `stack` instead of the code's own stack.
+    ///
+    /// # Safety
+    /// Several threads can run the same code at once with their own stacks, but only if the code
+    /// doesn't use any state of its own (like the set `generate_code` makes for `count distinct`).
+    pub unsafe fn call_on(&self, stack: &mut CallStack, args: &[usize]) -> *mut u8 {
+        self.call_with_stack(stack.ptr, args)
+    }
+
+    fn call_with_stack(&self, stack: *mut u8, args: &[usize]) -> *mut u8 {
             // cast the memory region to a function pointer
         let f: extern "C" fn(*mut u8) -> *mut u8 = unsafe { std::mem::transmute(self.ghcc_code) };
 
         // Copy args to the stack
         for (i, item) in args.iter().enumerate() {
             unsafe {
-                std::ptr::write_unaligned((self.stack as *mut usize).offset(i as isize), *item);
+                std::ptr::write_unaligned((stack as *mut usize).offset(i as isize), *item);
             }
         }
 
@@ -97,18 +142,83 @@ impl GeneratedCode {
         // a "root stencil" that unpacks all of our arguments from our custom stack into
         // the registers that the other stencils expect but that is only done once per 
         // call so it should be fine
-        f(self.stack)
+        f(stack)
+    }
+
+    /// Ties the lifetime of some state the generated code points to to the lifetime of the code
+    pub fn keep_alive(&mut self, state: Box<dyn Any + Send>) {
+        self.keep_alive.push(state);
+    }
+
+    /// The holes of the patchable constants (see `CodeGen::new_patchable_i64`): the first one
+    /// that was created is at index 0 and so on
+    pub fn patchable_consts(&self) -> &[ConstHandle] {
+        &self.patchable_consts
+    }
+
+    /// Replaces a constant in the code, so it can run with a different value without generating it again.
+    /// The new value must have the same type as the old one and fit into the hole, which only has
+    /// 32 bits for constants the stencil zero extends.
+    pub fn patch_const(&mut self, handle: ConstHandle, value: ConstValue) -> Result<(), String> {
+        let hole = self.const_holes.get_mut(handle.0).ok_or_else(|| "The constant doesn't belong to this code".to_string())?;
+        if value.get_type() != hole.value.get_type() {
+            return Err(format!("Cannot patch a constant of type {} with a value of type {}", hole.value.get_type(), value.get_type()));
+        }
+        let bits = value.bitcast_to_u64();
+        if hole.reloc_type == RelocType::Abs32 && get_data_type_size(&value.get_type()) > 4 && bits > u32::MAX as u64 {
+            return Err(format!("{:?} doesn't fit into the 32 bit hole of the constant", value));
+        }
+        let bytes = bits.to_ne_bytes();
+        let bytes = match hole.reloc_type {
+            RelocType::Abs64 => &bytes[..],
+            RelocType::Abs32 => &bytes[..4],
+            RelocType::Rel32 | RelocType::Abs64Fun => unreachable!("Constants are never patched into {:?} holes", hole.reloc_type),
+        };
+        protect(self.code, self.code_len, libc::PROT_READ | libc::PROT_WRITE);
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), (self.code as *mut u8).add(hole.offset), bytes.len());
+        }
+        protect(self.code, self.code_len, libc::PROT_READ | libc::PROT_EXEC);
+        hole.value = value;
+        Ok(())
     }
 
     // TODO: We have partial support for having 
 
 }
+
+/// Maps `len` bytes of zeroed memory with read and write permissions
+fn map(len: usize) -> *mut c_void {
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    assert_ne!(ptr, libc::MAP_FAILED, "mmap failed: {}", std::io::Error::last_os_error());
+    ptr
+}
+
+fn protect(addr: *const c_void, len: usize, prot: libc::c_int) {
+    let result = unsafe { libc::mprotect(addr as *mut c_void, len, prot) };
+    assert_eq!(result, 0, "mprotect failed: {}", std::io::Error::last_os_error());
+}
+
+// The code, its stack and the state it keeps alive aren't shared with anything else, so the code can
+// be generated on one thread and run on another. It is not `Sync` since every call uses the same stack.
+unsafe impl Send for GeneratedCode {}
+
 impl Drop for GeneratedCode {
     fn drop(&mut self) {
+        // Unmaps all pages of the mappings, not only the first one
         unsafe {
-            libc::munmap(self.code as *mut libc::c_void, 0x1000);
-            libc::munmap(self.ghcc_code as *mut libc::c_void, 0x1000);
-            libc::munmap(self.stack as *mut libc::c_void, 0x1000);
+            libc::munmap(self.code as *mut libc::c_void, self.code_len);
+            libc::munmap(self.ghcc_code as *mut libc::c_void, self.ghcc_len);
+            libc::munmap(self.stack as *mut libc::c_void, self.stack_size);
         }
     }
 }
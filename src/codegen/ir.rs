@@ -4,7 +4,7 @@
 // for identifying stencils. For now i will try without my own IR, also because it increases
 // codegen/compile times compared to just emitting binary directly.
 
-use std::fmt::{self, Display, Formatter};
+use std::{fmt::{self, Display, Formatter}, str::FromStr};
 
 use inkwell::{types::IntType, AddressSpace};
 
@@ -27,6 +27,9 @@ pub enum DataType {
     // Untyped Pointer. For the semantics of operations on values of this type it's enough.
     // Upper level code has to tell us how to interpret dereferenced values
     Ptr,
+    // Variable length string. Tables store them outside of the rows, in generated code
+    // a string is a pointer to its first byte (plus a length kept next to it)
+    Str,
 }
 
 impl<'a> TryFrom<IntType<'a>> for DataType {
@@ -58,7 +61,7 @@ impl DataType {
             DataType::F32 => context.f32_type().into(),
             DataType::F64 => context.f64_type().into(),
             DataType::Bool => context.bool_type().into(),
-            DataType::Ptr => context.ptr_type(AddressSpace::default()).into()
+            DataType::Ptr | DataType::Str => context.ptr_type(AddressSpace::default()).into()
         }
     }
 
@@ -121,7 +124,7 @@ impl DataType {
             DataType::I8 | DataType::U8 => DataType::U8,
             DataType::I16 | DataType::U16 => DataType::U16,
             DataType::I32 | DataType::U32 | DataType::F32 => DataType::U32,
-            DataType::I64 | DataType::U64 | DataType::F64 | DataType::Ptr => DataType::U64, // We assume we run on 64 bit systems
+            DataType::I64 | DataType::U64 | DataType::F64 | DataType::Ptr | DataType::Str => DataType::U64, // We assume we run on 64 bit systems
         }
     }
 }
@@ -141,6 +144,29 @@ impl Display for DataType {
             DataType::F64 => write!(f, "f64"),
             DataType::Bool => write!(f, "bool"),
             DataType::Ptr => write!(f, "ptr"),
+            DataType::Str => write!(f, "str"),
+        }
+    }
+}
+
+impl FromStr for DataType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "i8" => Ok(DataType::I8),
+            "i16" => Ok(DataType::I16),
+            "i32" => Ok(DataType::I32),
+            "i64" => Ok(DataType::I64),
+            "u8" => Ok(DataType::U8),
+            "u16" => Ok(DataType::U16),
+            "u32" => Ok(DataType::U32),
+            "u64" => Ok(DataType::U64),
+            "f32" => Ok(DataType::F32),
+            "f64" => Ok(DataType::F64),
+            "bool" => Ok(DataType::Bool),
+            "str" => Ok(DataType::Str),
+            _ => Err(format!("Unknown data type \"{}\"", s)),
         }
     }
 }
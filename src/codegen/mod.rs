@@ -12,7 +12,7 @@ use crate::codegen::{copy_patch::STENCILS, ir::DataType};
 
 use self::{copy_patch::CopyPatchBackend, ir::ConstValue};
 
-pub use generated_code::GeneratedCode;
+pub use generated_code::{ConstHandle, GeneratedCode};
 use libc::c_void;
 
 pub type CodegenCFunctionSignature = unsafe extern "C" fn(*mut u8, *mut u8, *mut u8) -> *mut u8; // (state, arg1, arg2) -> result Some can be unused depending on the usecase
@@ -112,6 +112,12 @@ impl<'cg> CGValueRef<'cg> {
     }
 }
 
+impl Clone for CGValueRef<'_> {
+    fn clone(&self) -> Self {
+        self.cg.clone_value(self)
+    }
+}
+
 impl Setable<'_, &Self> for CGValueRef<'_> {
     fn set(&self, other: &Self) {
         if self != other {
@@ -174,8 +180,36 @@ pub trait CGCmp<'o, Other> {
 }*/
 
 #[derive(Debug, PartialEq, PartialOrd, Eq)]
+#[repr(transparent)]
 pub struct I64Ref<'cg> (CGValueRef<'cg>);
 
+impl<'cg> I64Ref<'cg> {
+    /// Borrows a value as an integer. This allows any number of operations to read a value
+    /// (e.g. as their right hand side) without having to clone it first.
+    pub fn view<'a>(value: &'a CGValueRef<'cg>) -> &'a I64Ref<'cg> {
+        debug_assert_eq!(value.data_type, DataType::I64);
+        // I64Ref is a transparent wrapper around CGValueRef
+        unsafe { &*(value as *const CGValueRef<'cg> as *const I64Ref<'cg>) }
+    }
+
+    /// Compares the value with a constant as unsigned integers. The stencil is picked by the type
+    /// of the constant, so the comparison is unsigned even though the value is an i64.
+    pub fn cg_ulte(mut self, other: u64) -> BoolRef<'cg> {
+        let cg = self.0.cg;
+        let other = CGValueRef::new_const(ConstValue::U64(other), self.cg);
+        cg.lte(&mut self.0, &other);
+        BoolRef(self.0)
+    }
+
+    /// The remainder of dividing the value by a constant as unsigned integers (see `cg_ulte`)
+    pub fn urem(mut self, other: u64) -> Self {
+        let cg = self.0.cg;
+        let other = CGValueRef::new_const(ConstValue::U64(other), self.cg);
+        cg.rem(&mut self.0, &other);
+        self
+    }
+}
+
 impl<'cg> Deref for I64Ref<'cg> {
     type Target = CGValueRef<'cg>;
 
@@ -552,10 +586,62 @@ impl<'cg> std::ops::BitOrAssign<i64> for I64Ref<'cg> {
     }
 }
 
+impl<'cg> std::ops::Shl<&Self> for I64Ref<'cg> {
+    type Output = I64Ref<'cg>;
+
+    fn shl(mut self, rhs: &Self) -> Self::Output {
+        let cg = self.0.cg;
+        cg.shl(&mut self.0, &rhs.0);
+        self
+    }
+}
+
+impl<'cg> std::ops::Shl<i64> for I64Ref<'cg> {
+    type Output = I64Ref<'cg>;
+
+    fn shl(mut self, rhs: i64) -> Self::Output {
+        let cg = self.0.cg;
+        let rhs = CGValueRef::new_const(ConstValue::I64(rhs), self.cg);
+        cg.shl(&mut self.0, &rhs);
+        self
+    }
+}
+
+impl<'cg> std::ops::Shr<&Self> for I64Ref<'cg> {
+    type Output = I64Ref<'cg>;
+
+    fn shr(mut self, rhs: &Self) -> Self::Output {
+        let cg = self.0.cg;
+        cg.shr(&mut self.0, &rhs.0);
+        self
+    }
+}
+
+impl<'cg> std::ops::Shr<i64> for I64Ref<'cg> {
+    type Output = I64Ref<'cg>;
+
+    fn shr(mut self, rhs: i64) -> Self::Output {
+        let cg = self.0.cg;
+        let rhs = CGValueRef::new_const(ConstValue::I64(rhs), self.cg);
+        cg.shr(&mut self.0, &rhs);
+        self
+    }
+}
+
 
 #[derive(Debug, PartialEq, PartialOrd, Eq)]
+#[repr(transparent)]
 pub struct BoolRef<'cg> (CGValueRef<'cg>);
 
+impl<'cg> BoolRef<'cg> {
+    /// See `I64Ref::view`
+    pub fn view<'a>(value: &'a CGValueRef<'cg>) -> &'a BoolRef<'cg> {
+        debug_assert_eq!(value.data_type, DataType::Bool);
+        // BoolRef is a transparent wrapper around CGValueRef
+        unsafe { &*(value as *const CGValueRef<'cg> as *const BoolRef<'cg>) }
+    }
+}
+
 impl<'cg> Deref for BoolRef<'cg> {
     type Target = CGValueRef<'cg>;
 
@@ -644,6 +730,16 @@ impl<'cg> std::ops::BitOr<&Self> for BoolRef<'cg> {
     }
 }
 
+impl<'cg> std::ops::Not for BoolRef<'cg> {
+    type Output = BoolRef<'cg>;
+
+    fn not(mut self) -> Self::Output {
+        let cg = self.0.cg;
+        cg.not(&mut self.0);
+        self
+    }
+}
+
 // TODO: Add support for typed pointers
 pub struct UntypedPtrRef<'cg> (CGValueRef<'cg>);
 
@@ -685,6 +781,15 @@ impl<'cg> UntypedPtrRef<'cg> {
     fn new(i: usize, cg: &'cg CodeGen) -> Self {
         UntypedPtrRef(CGValueRef::new(i, cg, DataType::Ptr))
     }
+
+    /// Takes the pointer as an integer, e.g. the result of a C function that returns one
+    pub fn into_i64(mut self) -> I64Ref<'cg> {
+        let cg = self.0.cg;
+        let i = cg.materialize(&mut self.0);
+        cg.memory_management.borrow_mut().bitcast(i, DataType::I64);
+        self.0.data_type = DataType::I64;
+        I64Ref(self.0)
+    }
 }
 
 impl<'cg> PtrRefByteOffset<'cg, &I64Ref<'cg>> for UntypedPtrRef<'cg> {
@@ -751,17 +856,33 @@ impl<'cg> UntypedPtrRef<'cg> {
         new_var
     }
 
+    /// Loads a value that is stored with the width of `data_type`. Integers are extended to i64
+    /// so they can be mixed with all the other values, bools are loaded into a full slot.
+    pub fn load_widened(&self, data_type: DataType) -> CGValueRef<'cg> {
+        match data_type {
+            // u64 is just reinterpreted
+            DataType::I64 | DataType::U64 => TypedPtrRef::<I64Ref>::from(self.clone()).read().into(),
+            DataType::Ptr => self.load_from(data_type),
+            DataType::F32 | DataType::F64 => unreachable!("`Schema::new` rejects floating point columns"),
+            DataType::Str => panic!("Strings aren't stored inline and can't be loaded like this"),
+            _ => {
+                let mut new_var = self.0.cg.new_var(DataType::I64);
+                self.cg.deref_ptr_extend(self.inner.into_value_i(), new_var.inner.into_value_i(), data_type);
+                if data_type == DataType::Bool {
+                    self.cg.memory_management.borrow_mut().bitcast(new_var.inner.into_value_i(), DataType::Bool);
+                    new_var.data_type = DataType::Bool;
+                }
+                new_var
+            }
+        }
+    }
+
     #[allow(dead_code)]
     pub fn write_to(&self, value: &CGValueRef<'cg>) {
         self.cg.write_to_ptr(self.inner.into_value_i(), &value);
     }
 }
 
-#[allow(private_bounds)]
-pub trait TypedPtrRefOffset<'cg, PtrType: PtrTarget<'cg>, Other> {
-    fn typed_offset(&self, offset: Other) -> Self;
-}
-
 #[allow(private_bounds)]
 pub struct TypedPtrRef<'cg, PtrType: PtrTarget<'cg>> {
     ptr: UntypedPtrRef<'cg>,
@@ -839,20 +960,6 @@ impl<'cg, PtrType: PtrTarget<'cg>> TypedPtrRef<'cg, PtrType> {
     }
 }
 
-impl<'cg, PtrType: PtrTarget<'cg>> TypedPtrRefOffset<'cg, PtrType, i64> for TypedPtrRef<'cg, PtrType> {
-    fn typed_offset(&self, offset: i64) -> Self {
-        let ptr = self.ptr.clone().byte_offset(offset * get_data_type_size(&PtrType::get_data_type()) as i64);
-        TypedPtrRef { ptr, _phantom: std::marker::PhantomData }
-    }
-}
-
-impl<'cg, PtrType: PtrTarget<'cg>> TypedPtrRefOffset<'cg, PtrType, &I64Ref<'cg>> for TypedPtrRef<'cg, PtrType> {
-    fn typed_offset(&self, offset: &I64Ref<'cg>) -> Self {
-        let ptr = self.ptr.clone().byte_offset(&(offset.clone() * get_data_type_size(&PtrType::get_data_type()) as i64));
-        TypedPtrRef { ptr, _phantom: std::marker::PhantomData }
-    }
-}
-
 
 // How this works is that we have "values" which are roughly equivalent to 
 // variables in a high level language. Note that we don't do SSA here. I
@@ -876,13 +983,15 @@ impl<'cg, PtrType: PtrTarget<'cg>> TypedPtrRefOffset<'cg, PtrType, &I64Ref<'cg>>
 /// Convenience layer around copy and patch compilation backend
 /// so that you don't have to think about registers anymore
 
-fn get_data_type_size(data_type: &DataType) -> usize {
+pub(crate) fn get_data_type_size(data_type: &DataType) -> usize {
     match data_type {
         DataType::I64 | DataType::U64 | DataType::F64 => 8,
         DataType::I32 | DataType::U32 | DataType::F32 => 4,
         DataType::I16 | DataType::U16 => 2,
         DataType::I8 | DataType::U8 | DataType::Bool => 1,
         DataType::Ptr => mem::size_of::<usize>(),
+        // Only the bytes outside of the row, nothing is stored inside of it
+        DataType::Str => 0,
     }
 }
 
@@ -1168,10 +1277,12 @@ impl MemoryManagement {
         self.reg_state[reg] = None;
     }
 
-    fn init(&mut self, i: usize, c: ConstValue) {
+    /// Loads a constant into the value, returns the hole of the constant (see `GeneratedCode::patch_const`)
+    fn init(&mut self, i: usize, c: ConstValue) -> Option<ConstHandle> {
         self.free_reg(0);
-        self.cp_backend.emit_take_1_const(c);
+        let hole = self.cp_backend.emit_take_1_const(c);
         self.reg_state[0] = Some((i, true));
+        hole
     }
 
     fn bitcast(&mut self, i: usize, data_type: DataType) {
@@ -1195,6 +1306,8 @@ fn get_fn_ptr(f: CodegenCFunctionSignature) -> *const c_void {
 pub struct CodeGen {
     inner: Rc<CopyPatchBackend>,
     memory_management: RefCell<MemoryManagement>,
+    /// See `GeneratedCode::patchable_consts`
+    patchable_consts: RefCell<Vec<ConstHandle>>,
 }
 
 #[allow(dead_code)]
@@ -1206,7 +1319,7 @@ impl CodeGen {
         Self {
             inner: cp_backend,
             memory_management: RefCell::new(memory_management),
-
+            patchable_consts: RefCell::new(Vec::new()),
         }
     }
 
@@ -1244,6 +1357,17 @@ impl CodeGen {
         I64Ref(var)
     }
 
+    /// Create a new i64 variable whose initial value can be changed in the generated code later on
+    /// (see `GeneratedCode::patch_const`), e.g. to run the same code with different literals.
+    /// The handle is also in `GeneratedCode::patchable_consts`.
+    pub fn new_patchable_i64(&self, init: i64) -> (I64Ref, ConstHandle) {
+        let var = self.new_var(DataType::I64);
+        let hole = self.memory_management.borrow_mut().init(var.inner.into_value_i(), ConstValue::I64(init));
+        let hole = hole.expect("The stencil that loads a constant has a hole for it");
+        self.patchable_consts.borrow_mut().push(hole);
+        (I64Ref(var), hole)
+    }
+
     /// Create a new bool constant. Note that `set` cannot be called on constants!
     pub fn new_bool_const(&self, b: bool) -> BoolRef {
         BoolRef(self.new_const(ConstValue::Bool(b)))
@@ -1257,6 +1381,12 @@ impl CodeGen {
         BoolRef(var)
     }
 
+    /// Create a new pointer constant, e.g. to pass some state to a C function.
+    /// The pointee has to outlive the generated code (see `GeneratedCode::keep_alive`).
+    pub fn new_ptr_const<T>(&self, ptr: *const T) -> UntypedPtrRef {
+        UntypedPtrRef(CGValueRef { inner: CGValueRefInner::Const(ConstValue::U64(ptr as u64)), cg: self, data_type: DataType::Ptr })
+    }
+
 
     fn load_const(&self, v: usize, c: ConstValue) {
         let mut memory_management = self.memory_management.borrow_mut();
@@ -1318,12 +1448,13 @@ impl CodeGen {
     fn reset(&mut self) {
         self.memory_management.borrow_mut().reset();
         self.inner.reset();
+        self.patchable_consts.borrow_mut().clear();
     }
 
     //--------------------------------------------------------------------------------
     // Arithmetic operations
 
-    fn gen_arith<const COMMUTATIVE: bool, const RETURNS_BOOL: bool>(&self,  gen_op: fn(&CopyPatchBackend, DataType), gen_op_const: fn(&CopyPatchBackend, ConstValue), l: &mut CGValueRef, r: &CGValueRef) {
+    fn gen_arith<const COMMUTATIVE: bool, const RETURNS_BOOL: bool>(&self,  gen_op: fn(&CopyPatchBackend, DataType), gen_op_const: fn(&CopyPatchBackend, ConstValue) -> Option<ConstHandle>, l: &mut CGValueRef, r: &CGValueRef) {
         let mut memory_management = self.memory_management.borrow_mut();
         match (l.inner, r.inner) {
             (CGValueRefInner::Value(li), CGValueRefInner::Value(ri)) => {
@@ -1420,6 +1551,15 @@ impl CodeGen {
         self.gen_arith::<true, true>(CopyPatchBackend::emit_or,CopyPatchBackend::emit_or_const, l, r)
     }
 
+    fn shl(&self, l: &mut CGValueRef, r: &CGValueRef) {
+        self.gen_arith::<false, false>(CopyPatchBackend::emit_shl,CopyPatchBackend::emit_shl_const, l, r)
+    }
+
+    /// Arithmetic shift for signed types
+    fn shr(&self, l: &mut CGValueRef, r: &CGValueRef) {
+        self.gen_arith::<false, false>(CopyPatchBackend::emit_shr,CopyPatchBackend::emit_shr_const, l, r)
+    }
+
     fn not(&self, l: &mut CGValueRef) {
         let mut memory_management = self.memory_management.borrow_mut();
         match l.inner {
@@ -1536,6 +1676,16 @@ impl CodeGen {
         memory_management.reg_state[0] = Some((target_i, true));
     }
 
+    fn deref_ptr_extend(&self, ptr_i: usize, target_i: usize, data_type: DataType) {
+        let mut memory_management = self.memory_management.borrow_mut();
+        memory_management.put_in_reg(0, ptr_i);
+        // The pointer stays usable, so make sure it isn't lost when we overwrite the register
+        memory_management.free_reg(0);
+        self.inner.emit_load(data_type);
+        self.inner.emit_extend(data_type);
+        memory_management.reg_state[0] = Some((target_i, true));
+    }
+
     fn get_ptr(&self, value_i: usize) -> UntypedPtrRef {
         let mut memory_management = self.memory_management.borrow_mut();
         let stack_pos = match &memory_management.values[value_i] {
@@ -1588,25 +1738,60 @@ impl CodeGen {
         self.inner.emit_ret();
     }
 
-    pub fn call_c_function(&self, func: CodegenCFunctionSignature, args_ptr: UntypedPtrRef) -> UntypedPtrRef {
+    pub fn call_c_function(&self, func: CodegenCFunctionSignature, mut args_ptr: UntypedPtrRef) -> UntypedPtrRef {
+        let args_i = self.materialize(&mut args_ptr.0);
         // Put args ptr into first register
         let mut memory_management = self.memory_management.borrow_mut();
-        memory_management.put_in_reg(0, args_ptr.inner.into_value_i());
-        // Allocate a stack region large enough to hold the input arguments
+        memory_management.put_in_reg(0, args_i);
+        drop(memory_management);
+        self.emit_c_call(func)
+    }
+
+    /// Same as `call_c_function` but passes two arguments. The first one usually is a pointer to
+    /// some state the function needs, the second one the actual value.
+    pub fn call_c_function_2(&self, func: CodegenCFunctionSignature, mut arg1: UntypedPtrRef, mut arg2: UntypedPtrRef) -> UntypedPtrRef {
+        let arg1_i = self.materialize(&mut arg1.0);
+        let arg2_i = self.materialize(&mut arg2.0);
+        self.memory_management.borrow_mut().put_in_regs(arg1_i, arg2_i);
+        self.emit_c_call(func)
+    }
+
+    /// Expects the arguments to already be in the registers
+    fn emit_c_call(&self, func: CodegenCFunctionSignature) -> UntypedPtrRef {
+        let mut memory_management = self.memory_management.borrow_mut();
+        // The call clobbers both registers so we have to save dirty values before and not after it
+        memory_management.free_reg(0);
+        memory_management.free_reg(1);
         self.inner.emit_call_c_func(get_fn_ptr(func), 0);
-        // Put first register into a new value
-        memory_management.lose_reg(0);
-        memory_management.lose_reg(1);
+        memory_management.reg_state = [None, None];
         drop(memory_management);
+        // Put first register into a new value
         let new_var = self.new_var(DataType::Ptr);
         self.memory_management.borrow_mut().reg_state[0] = Some((new_var.inner.into_value_i(), true));
         new_var.into()
     }
 
+    /// Constants don't live on the stack. This makes sure a value has a stack slot
+    /// so it can be moved into a register.
+    fn materialize(&self, v: &mut CGValueRef) -> usize {
+        match v.inner {
+            CGValueRefInner::Value(i) => i,
+            CGValueRefInner::Const(c) => {
+                let mut memory_management = self.memory_management.borrow_mut();
+                let new_i = memory_management.allocate_stack(c.get_type());
+                memory_management.init(new_i, c);
+                v.inner = CGValueRefInner::Value(new_i);
+                new_i
+            }
+        }
+    }
+
     //--------------------------------------------------------------------------------
 
     pub fn generate_code(&self) -> GeneratedCode {
-        self.inner.generate_code(self.memory_management.borrow().stack_size)
+        let mut code = self.inner.generate_code(self.memory_management.borrow().stack_size);
+        code.patchable_consts = self.patchable_consts.borrow().clone();
+        code
     }
 }
 
@@ -53,6 +53,8 @@ pub enum StencilOperation {
     Shr,
     ShrConst,
     Not,
+    // Sign/zero-extends the value to i64 depending on the signedness of the type
+    Extend,
 
     // Comparison operations
     Eq,
@@ -119,6 +121,7 @@ impl Display for StencilOperation {
             StencilOperation::Shr => write!(f, "shr"),
             StencilOperation::ShrConst => write!(f, "shr-const"),
             StencilOperation::Not => write!(f, "not"),
+            StencilOperation::Extend => write!(f, "extend"),
             StencilOperation::Eq => write!(f, "eq"),
             StencilOperation::EqConst => write!(f, "eq-const"),
             StencilOperation::Ne => write!(f, "ne"),
@@ -774,6 +777,19 @@ impl<'ctx> StencilCodeGen<'ctx> {
         })
     }
 
+    fn compile_extend(&self, d_type: DataType) -> Stencil {
+        let s_type = StencilType::new(StencilOperation::Extend, Some(d_type.clone()));
+        self.compile_stencil(s_type, &[d_type.get_llvm_type(self.context).into()], |args, _| {
+            let x = args[0].into_int_value();
+            let res = if d_type.is_signed() {
+                self.builder.build_int_s_extend(x, self.context.i64_type(), "sext").unwrap()
+            } else {
+                self.builder.build_int_z_extend(x, self.context.i64_type(), "zext").unwrap()
+            };
+            vec![res.into()]
+        })
+    }
+
     fn compile_load_ofs(&self, d_type: DataType) -> Stencil {
         let s_type = StencilType::new(StencilOperation::LoadOfs, Some(d_type.clone()));
         let uint8ptr_type = self.context.ptr_type(AddressSpace::default());
@@ -1119,6 +1135,18 @@ fn compile_all_load_store() -> BTreeMap<StencilType, Stencil>{
     result
 }
 
+fn compile_all_extend() -> BTreeMap<StencilType, Stencil> {
+    let context = Context::create();
+    let mut result = BTreeMap::new();
+    let types = &[DataType::Bool, DataType::U8, DataType::U16, DataType::U32, DataType::I8, DataType::I16, DataType::I32];
+    for ty in types {
+        let codegen = StencilCodeGen::new(&context);
+        let stencil = codegen.compile_extend(ty.clone());
+        result.insert(stencil.s_type.clone(), stencil);
+    }
+    result
+}
+
 fn compile_stencil(stencil_lib: &mut BTreeMap<StencilType, Stencil>, comp_fn: fn(&StencilCodeGen) -> Stencil) {
     let context = Context::create();
     let codegen = StencilCodeGen::new(&context);
@@ -1150,6 +1178,10 @@ pub fn compile_all_stencils() -> BTreeMap<StencilType, Stencil> {
 
     stencil_library.append(&mut load_store_stencils);
 
+    let mut extend_stencils = compile_all_extend();
+
+    stencil_library.append(&mut extend_stencils);
+
     let mut int_arith_stencils = compile_all_int_op();
 
     stencil_library.append(&mut int_arith_stencils);
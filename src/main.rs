@@ -1,18 +1,31 @@
+mod adaptive;
+mod parallel;
 mod query;
 mod codegen;
 mod query_codegen;
+mod query_cache;
+mod schema;
+mod simplify;
+mod sql;
+mod table;
+mod typecheck;
+mod vectorized;
+#[cfg(test)]
+mod query_gen;
 
-use std::{error::Error, hint::black_box, ptr};
+use std::{collections::HashMap, error::Error, hint::black_box, ptr};
 
-use query_codegen::generate_code;
+use adaptive::run_adaptive;
+use parallel::run_parallel;
+use query_cache::QueryCache;
+use query_codegen::{CodeGenError, CodeGenOptions, OperandOrder, OutputBuffer, PreparedQuery};
 
 
 
-use clap::Parser;
-use csv::Reader;
+use clap::{Parser, ValueEnum};
 use rustyline::{error::ReadlineError, history::MemHistory, Config, Editor};
 
-use crate::query::{parse_query_from_str, run_query};
+use crate::{query::{observe_selectivity, parse_query, run_join_query, run_query, Atom, Syntax}, schema::Schema, table::{load_csv, Layout, Table}, vectorized::run_query_vectorized};
 
 // Empty consumer for benchmarking
 unsafe extern "C" fn noop_result_consumer(_: *mut u8, _: *mut u8, _: *mut u8) -> *mut u8 {
@@ -26,40 +39,118 @@ unsafe extern "C" fn stdout_result_consumer(_: *mut u8, args: *mut u8, _: *mut u
     return ptr::null_mut();
 }
 
+/// Results of compiled queries are passed on this many at a time (see `OutputBuffer`)
+const OUTPUT_BUFFER_SIZE: usize = 4096;
+
+fn print_results(results: &[i64]) {
+    for result in results {
+        println!("Result: {}", result);
+    }
+}
+
+/// Prints a result of the interpreter like `print_results` prints the ones of the generated code
+fn print_atom(result: Atom) {
+    match result {
+        Atom::Boolean(b) => println!("Result: {}", b as i64),
+        result => println!("Result: {}", result),
+    }
+}
+
 /// Search for a pattern in a file and display the lines that contain it.
 #[derive(Parser)]
 struct Cli {
     /// The path to the file to read
     #[arg(short, long)]
     csv: Option<std::path::PathBuf>,
+    /// A second csv file that queries can join the first one with (`join on (= l.id r.order_id)`)
+    #[arg(long)]
+    join_csv: Option<std::path::PathBuf>,
+    /// Schema file for the csv file of `--join-csv`
+    #[arg(long)]
+    join_schema: Option<std::path::PathBuf>,
+    /// The csv files have no header row, columns can only be referenced as $0, $1, ... (unless a schema is given)
+    #[arg(long)]
+    no_header: bool,
+    /// Schema file with the names and types of the csv columns (one `<name> <type>` per line).
+    /// Without it the types are inferred from the data
+    #[arg(short, long)]
+    schema: Option<std::path::PathBuf>,
+    /// Queries are written in SQL (`SELECT sum(a + 2) FROM t WHERE b > 3`) instead of the lisp syntax
+    #[arg(long)]
+    sql: bool,
+    /// Store the table column by column instead of row by row
+    #[arg(long)]
+    columnar: bool,
+    /// Evaluate all operands of boolean & and | instead of short circuiting (no branches), even divisions they guard
+    #[arg(long)]
+    eager: bool,
+    /// Reorder the operands of & and | in filters based on their cost and on how often they
+    /// were true for a sample of the rows
+    #[arg(long)]
+    reorder: bool,
     /// Whether to run in benchmark mode
     #[arg(short, long)]
     benchmark: bool,
+    /// The interpreter the compiled code is compared with in benchmark mode
+    #[arg(long, value_enum, default_value_t = Baseline::Row)]
+    baseline: Baseline,
     /// Number of elements to generate
     #[arg(short, long)]
-    number: Option<u64>
+    number: Option<u64>,
+    /// How many KiB of compiled code to keep for queries that are run again
+    #[arg(long, default_value_t = 16 * 1024)]
+    cache_size: usize,
+    /// Interpret queries while their code is generated in the background and switch to it once it is ready
+    #[arg(long)]
+    adaptive: bool,
+    /// Number of threads that run the generated code of a query
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+    /// Rows a thread (or the interpreter of `--adaptive`) takes at a time
+    #[arg(long, default_value_t = 10_000)]
+    morsel_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Baseline {
+    /// Interprets the query one row at a time (`query::run_query`)
+    Row,
+    /// Interprets the query a batch of rows at a time (`vectorized::run_query_vectorized`)
+    Vectorized,
 }
 
-fn eval(query: &query::Query, test_data: (usize, &[i64]), benchmark: bool) {
+/// `benchmark` is the interpreter to compare with, the query only runs once without it and passes its
+/// results to `result_consumer`. Queries that can't be compiled, like the ones whose results can be
+/// NULL, are interpreted instead.
+fn eval(query: &query::Query, table: &Table, options: &CodeGenOptions, benchmark: Option<Baseline>, cache: &mut QueryCache, result_consumer: &mut dyn FnMut(Atom)) {
     let codegen_start = std::time::Instant::now();
-    let result_consumer = if benchmark {
-        noop_result_consumer
-    } else {
-        stdout_result_consumer
-    };
-    let code = generate_code(&query, test_data.0, result_consumer);
+    let code = cache.get(query, table, options);
     let codegen_elapsed = codegen_start.elapsed();
     match code {
         Ok(code) => {
-            println!("Generated {} bytes of x86-64 binary in {:?}", code.code_len, codegen_elapsed);
-            if benchmark {
+            if code.hit {
+                println!("Reused {} bytes of x86-64 binary from the cache in {:?}", code.code.code_len(), codegen_elapsed);
+            } else {
+                println!("Generated {} bytes of x86-64 binary in {:?}", code.code.code_len(), codegen_elapsed);
+            }
+            println!("Query cache: {}", code.stats);
+            if let Some(baseline) = benchmark {
                 let start_time = std::time::Instant::now();
-                code.call(&[test_data.1.as_ptr() as usize, (test_data.1.len() / test_data.0) as usize]);
+                code.execute(table, &mut OutputBuffer::new(OUTPUT_BUFFER_SIZE, &mut |results| {black_box(results);})).unwrap();
                 let elapsed = start_time.elapsed();
                 
                 let start_interp = std::time::Instant::now();
 
-                run_query(&query, test_data.1, test_data.0, |result| {black_box(result);});
+                match baseline {
+                    Baseline::Row => if let Err(e) = run_query(query, table, |result| {black_box(result);}) {
+                        println!("Error: {}", e);
+                        return;
+                    },
+                    Baseline::Vectorized => if let Err(e) = run_query_vectorized(query, table, |result| {black_box(result);}) {
+                        println!("Error: {}", e);
+                        return;
+                    },
+                }
                 
                 let elapsed_interp = start_interp.elapsed();
 
@@ -68,7 +159,7 @@ fn eval(query: &query::Query, test_data: (usize, &[i64]), benchmark: bool) {
                 //let elapsed_hardcoded = start_hardcoded.elapsed();
                 //println!("Hardcoded: {:?}", elapsed_hardcoded);
                 
-                println!("Interpreted: {:?}", elapsed_interp);
+                println!("Interpreted ({:?}): {:?}", baseline, elapsed_interp);
                 println!("Compiled: {:?}", elapsed);
 
                 let factor = elapsed_interp.as_secs_f64() / elapsed.as_secs_f64();
@@ -76,50 +167,150 @@ fn eval(query: &query::Query, test_data: (usize, &[i64]), benchmark: bool) {
                 println!("Compiled is {:.2}x {}", factor, if factor > 1.0 { "faster" } else { "slower" });
 
             } else {
-                code.call(&[test_data.1.as_ptr() as usize, (test_data.1.len() / test_data.0) as usize]);
+                let mut consumer = |results: &[i64]| results.iter().for_each(|&result| result_consumer(Atom::Num(result)));
+                code.execute(table, &mut OutputBuffer::new(OUTPUT_BUFFER_SIZE, &mut consumer)).unwrap();
+            }
+        },
+        Err(CodeGenError::Unsupported(reason)) if benchmark.is_none() => {
+            println!("{}, interpreting the query instead", reason);
+            if let Err(e) = run_query(query, table, result_consumer) {
+                println!("Error: {}", e);
             }
         },
         Err(c) => {
-            match c {
-                query_codegen::CodeGenError::TypeError => {
-                    println!("Type error");
+            println!("{}", c);
+        }
+    }
+}
+
+/// Runs a query with a join of `table` and `right`. These queries aren't cached, so they are compiled
+/// every time. `benchmark` compares the compiled code with the interpreter.
+fn eval_join(query: &query::Query, table: &Table, right: &Table, options: &CodeGenOptions, benchmark: bool) {
+    let codegen_start = std::time::Instant::now();
+    let code = match PreparedQuery::new_join(query, table.schema(), table.layout(), right.schema(), right.layout(), options) {
+        Ok(code) => code,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    println!("Generated {} bytes of x86-64 binary in {:?}", code.code_len(), codegen_start.elapsed());
+    if benchmark {
+        let start_time = std::time::Instant::now();
+        code.execute_join(table, right, &[], &mut OutputBuffer::new(OUTPUT_BUFFER_SIZE, &mut |results| {black_box(results);})).unwrap();
+        let elapsed = start_time.elapsed();
+        let start_interp = std::time::Instant::now();
+        if let Err(e) = run_join_query(query, table, right, |result| {black_box(result);}) {
+            println!("Error: {}", e);
+            return;
+        }
+        let elapsed_interp = start_interp.elapsed();
+        println!("Interpreted: {:?}", elapsed_interp);
+        println!("Compiled: {:?}", elapsed);
+        let factor = elapsed_interp.as_secs_f64() / elapsed.as_secs_f64();
+        println!("Compiled is {:.2}x {}", factor, if factor > 1.0 { "faster" } else { "slower" });
+    } else {
+        code.execute_join(table, right, &[], &mut OutputBuffer::new(OUTPUT_BUFFER_SIZE, &mut print_results)).unwrap();
+    }
+}
+
+/// Resolves the columns of a query, which may join `table` with `right`
+fn bind(query: &mut query::Query, spans: &query::QuerySpans, table: &Table, right: Option<&Table>) -> Result<(), typecheck::TypeError> {
+    match right {
+        Some(right) if query.join.is_some() => query.bind_join(table.schema(), right.schema(), Some(spans)),
+        _ => query.bind_with_spans(table.schema(), Some(spans)),
+    }
+}
+
+/// Handles `\prepare <name> <query>`, which compiles a query with parameters once, and
+/// `\execute <name> <values>...`, which runs a prepared query with the given parameter values
+fn run_command(command: &str, table: &Table, right: Option<&Table>, syntax: Syntax, args: &Cli, prepared: &mut HashMap<String, PreparedQuery>) {
+    let (command, rest) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+    let rest = rest.trim_start();
+    let (name, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    match command {
+        "prepare" => {
+            let (mut query, spans) = match parse_query(rest, syntax) {
+                Ok(query) => query,
+                Err(e) => {
+                    println!("Parse-Error:\n{}", e.diagnostic(rest));
+                    return;
                 }
-                query_codegen::CodeGenError::Const(n) => {
-                    println!("Result(const): {}", n);
+            };
+            if let Err(e) = bind(&mut query, &spans, table, right) {
+                println!("Error:\n{}", e.diagnostic(rest));
+                return;
+            }
+            // The selectivity of a filter with parameters depends on their values, so it is not reordered
+            let options = CodeGenOptions { short_circuit: !args.eager, operand_order: OperandOrder::AsWritten };
+            let codegen_start = std::time::Instant::now();
+            let query = match right {
+                Some(right) if query.join.is_some() => PreparedQuery::new_join(&query, table.schema(), table.layout(), right.schema(), right.layout(), &options),
+                _ => PreparedQuery::new(&query, table.schema(), table.layout(), &options),
+            };
+            match query {
+                Ok(query) => {
+                    println!("Generated {} bytes of x86-64 binary in {:?}", query.code_len(), codegen_start.elapsed());
+                    println!("Parameters: {}", query.parameters().join(", "));
+                    prepared.insert(name.to_string(), query);
+                },
+                Err(e) => println!("{}", e),
+            }
+        },
+        "execute" => {
+            let Some(query) = prepared.get(name) else {
+                println!("There is no prepared query called \"{}\"", name);
+                return;
+            };
+            let values = match rest.split_whitespace().map(str::parse::<i64>).collect::<Result<Vec<_>, _>>() {
+                Ok(values) => values,
+                Err(e) => {
+                    println!("Parameter values must be integers: {}", e);
+                    return;
                 }
+            };
+            let mut benchmark_consumer = |results: &[i64]| {black_box(results);};
+            let consumer: &mut dyn FnMut(&[i64]) = if args.benchmark { &mut benchmark_consumer } else { &mut print_results };
+            let start_time = std::time::Instant::now();
+            let mut output = OutputBuffer::new(OUTPUT_BUFFER_SIZE, consumer);
+            let result = match right {
+                Some(right) if query.has_join() => query.execute_join(table, right, &values, &mut output),
+                _ => query.execute(table, &values, &mut output),
+            };
+            match result {
+                Ok(()) => println!("Executed in {:?}", start_time.elapsed()),
+                Err(e) => println!("{}", e),
             }
-        }
+        },
+        _ => println!("Unknown command \\{}, try \\prepare <name> <query> or \\execute <name> <values>...", command),
     }
 }
 
-
 fn main() -> Result<(), Box<dyn Error>> {
 
     let args = Cli::parse();
 
     // Fill a test vector with 100_000_000 elements
-    // If the user specified a csv (no header, comma separated) file we will read the data from there
-    // otherwise we will generate a sequential range of numbers 0..10_000_000
+    // If the user specified a csv (comma separated, with a header unless --no-header is given) file
+    // we will read the data from there otherwise we will generate a sequential range of numbers 0..10_000_000
 
-    let test_data: (usize, Vec<i64>) = if let Some(csv_path) = args.csv {
+    let load = |csv_path: &std::path::Path, schema_path: Option<&std::path::Path>| -> Result<Table, Box<dyn Error>> {
         println!("Reading data from csv file: {:?}", csv_path);
-        let mut rdr = Reader::from_path(csv_path)?;
-        let rows: Vec<Vec<i64>> = rdr.records()
-            .map(|record| {
-                let record = record.unwrap();
-                let mut vec = Vec::new();
-                for field in record.iter() {
-                    vec.push(field.parse::<i64>().unwrap());
-                }
-                vec
-            })
-            .collect();
-        if rows.len() == 0 {
+        let schema = schema_path.map(Schema::from_file).transpose()?;
+        let table = load_csv(csv_path, !args.no_header, schema)?;
+        let columns = table.schema().columns().iter().enumerate()
+            .map(|(i, c)| format!("{} {}", table.schema().display_name(i), c.data_type))
+            .collect::<Vec<_>>();
+        println!("Columns: {}", columns.join(", "));
+        Ok(table)
+    };
+    let table = if let Some(csv_path) = &args.csv {
+        let table = load(csv_path, args.schema.as_deref())?;
+        if table.rows() == 0 {
             println!("No data to process");
             return Ok(());
         }
-        let n = rows[0].len();
-        (n, rows.concat())
+        table
     } else {
         println!("No csv file specified, using default dummy data");
         let default_n = if args.benchmark {
@@ -132,33 +323,83 @@ fn main() -> Result<(), Box<dyn Error>> {
             return Ok(());
         }
         let n = args.number.map_or(default_n, |n| n as i64);
-        (1, (0..n).collect())
+        Table::from_i64(1, &(0..n).collect::<Vec<_>>())
+    };
+    let table = if args.columnar {
+        table.to_layout(Layout::Columnar)
+    } else {
+        table
+    };
+    // The table queries with a join join with
+    let right_table = match &args.join_csv {
+        Some(csv_path) if args.columnar => Some(load(csv_path, args.join_schema.as_deref())?.to_layout(Layout::Columnar)),
+        Some(csv_path) => Some(load(csv_path, args.join_schema.as_deref())?),
+        None => None,
     };
 
     codegen::init_stencils();
 
+    let syntax = if args.sql { Syntax::Sql } else { Syntax::Lisp };
+
     // REPL for evaluating expressions on the data
     let mut rl = Editor::<(), MemHistory>::with_history(Config::default(), MemHistory::new())?;
-
+    let mut prepared = HashMap::new();
+    let result_consumer = if args.benchmark { noop_result_consumer } else { stdout_result_consumer };
+    let mut cache = QueryCache::new(args.cache_size * 1024);
 
     loop {
         let readline = rl.readline(">> ");
         match readline {
             Ok(line) => {
                 rl.add_history_entry(line.as_str()).unwrap();
+                if let Some(command) = line.trim_start().strip_prefix('\\') {
+                    run_command(command, &table, right_table.as_ref(), syntax, &args, &mut prepared);
+                    continue;
+                }
                 let parse_start = std::time::Instant::now();
-                let query = parse_query_from_str(&line);
-                let query = match query {
-                    Ok(expr) => expr,
+                let query = parse_query(&line, syntax);
+                let (mut query, spans) = match query {
+                    Ok(query) => query,
                     Err(e) => {
-                        println!("Parse-Error: {}", e);
+                        println!("Parse-Error:\n{}", e.diagnostic(&line));
                         continue;
                     }
                 };
+                if let Err(e) = bind(&mut query, &spans, &table, right_table.as_ref()) {
+                    println!("Error:\n{}", e.diagnostic(&line));
+                    continue;
+                }
                 let parse_elapsed = parse_start.elapsed();
                 println!("Parsed in {:?}", parse_elapsed);
+                if !query.parameters().is_empty() {
+                    println!("The query has parameters, use \\prepare <name> <query> and \\execute <name> <values>... to run it");
+                    continue;
+                }
+
+                let operand_order = match &query.filter {
+                    // The filter of a join also refers to columns of the other table
+                    Some(filter) if args.reorder && query.join.is_none() => OperandOrder::Selectivity(observe_selectivity(filter, &table, 1000)),
+                    _ => OperandOrder::AsWritten,
+                };
+                let options = CodeGenOptions { short_circuit: !args.eager, operand_order };
 
-                eval(&query, (test_data.0, &test_data.1), args.benchmark)
+                if let (Some(_), Some(right)) = (&query.join, &right_table) {
+                    eval_join(&query, &table, right, &options, args.benchmark);
+                } else if args.adaptive {
+                    let start_time = std::time::Instant::now();
+                    match run_adaptive(&query, &table, &options, args.morsel_size, result_consumer) {
+                        Ok(stats) => println!("{}\nExecuted in {:?}", stats, start_time.elapsed()),
+                        Err(e) => println!("{}", e),
+                    }
+                } else if args.threads > 1 {
+                    let start_time = std::time::Instant::now();
+                    match run_parallel(&query, &table, &options, args.threads, args.morsel_size, result_consumer) {
+                        Ok(()) => println!("Generated and executed on {} threads in {:?}", args.threads, start_time.elapsed()),
+                        Err(e) => println!("{}", e),
+                    }
+                } else {
+                    eval(&query, &table, &options, args.benchmark.then_some(args.baseline), &mut cache, &mut print_atom)
+                }
             },
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
@@ -181,7 +422,11 @@ fn main() -> Result<(), Box<dyn Error>> {
 #[cfg(test)]
 mod test {
 
-    use crate::{query::{parse_query_from_str, run_query, Atom}, query_codegen::generate_code, test::results::Results};
+    use std::{cell::Cell, path::Path};
+
+    use proptest::prelude::*;
+
+    use crate::{eval, adaptive::{run_adaptive, run_resumable}, parallel::run_parallel, codegen::{ir::{ConstValue, DataType}, CodeGen, I64Ref}, query_cache::{CacheStats, QueryCache}, query::{run_rows, AggregateState, eval_expression, EvalError, observe_selectivity, parse_query, parse_query_from_str, parse_query_with_spans, like, run_join_query, run_query, Atom, Expr, Query, Span, Syntax}, query_codegen::{can_trap, estimated_cost, generate_code, CodeGenError, generate_code_with_options, generate_resumable_code, CodeGenOptions, OperandOrder, OutputBuffer, PreparedQuery}, query_gen, schema::{ColumnDef, Schema}, simplify::simplify, sql::to_sql, table::{load_csv, Layout, Table, Value}, test::results::Results, typecheck::type_expr, vectorized::{run_query_vectorized, BATCH_SIZE}};
 
     mod results {
         use std::{cell::RefCell, mem, ops::DerefMut, ptr};
@@ -232,14 +477,14 @@ mod test {
         let results = Results();
         let expr_str = "(+ (+ $0 $0) (* (+ $0 $0) (+ (* 9 4) $0)))";
         let query = parse_query_from_str(expr_str).unwrap();
-        let code = generate_code(&query, 1, results.consumer()).unwrap();
+        let code = generate_code(&query, &Schema::unnamed(1), Layout::RowMajor, results.consumer()).unwrap();
         let data = vec![0i64, 1, 5, 10, 100, 1000];
         code.call(&[data.as_ptr() as usize, data.len()]);
         let mut interp_result = vec![];
-        run_query(&query, &data, 1, |r| match r {
+        run_query(&query, &Table::from_i64(1, &data), |r| match r {
             Atom::Num(n) => interp_result.push(n),
             _ => unreachable!()
-        });
+        }).unwrap();
         let result = results.take();
         assert_eq!(result, interp_result);
     }
@@ -249,14 +494,14 @@ mod test {
         let results = Results();
         let expr_str = "(+ (+ $0 $0) (* (+ $0 $0) (+ (* 9 (+ 1 4)) $0)))";
         let query = parse_query_from_str(expr_str).unwrap();
-        let code = generate_code(&query, 1, results.consumer()).unwrap();
+        let code = generate_code(&query, &Schema::unnamed(1), Layout::RowMajor, results.consumer()).unwrap();
         let data = vec![0i64, 1, 5, 10, 100, 1000];
         code.call(&[data.as_ptr() as usize, data.len()]);
         let mut interp_result = vec![];
-        run_query(&query, &data, 1, |r| match r {
+        run_query(&query, &Table::from_i64(1, &data), |r| match r {
             Atom::Num(n) => interp_result.push(n),
             _ => unreachable!()
-        });
+        }).unwrap();
         let result = results.take();
         assert_eq!(result, interp_result);
     }
@@ -266,14 +511,14 @@ mod test {
         let results = Results();
         let expr_str = "(+ (+ $0 $0) (* (/ $0 2) (- (* 9 4) $0)))";
         let query = parse_query_from_str(expr_str).unwrap();
-        let code = generate_code(&query, 1, results.consumer()).unwrap();
+        let code = generate_code(&query, &Schema::unnamed(1), Layout::RowMajor, results.consumer()).unwrap();
         let data = vec![0, 1, 5, 10, 100, 1000];
         code.call(&[data.as_ptr() as usize, data.len()]);
         let mut interp_result = vec![];
-        run_query(&query, &data, 1, |r| match r {
+        run_query(&query, &Table::from_i64(1, &data), |r| match r {
             Atom::Num(n) => interp_result.push(n),
             _ => unreachable!()
-        });
+        }).unwrap();
         let result = results.take();
         assert_eq!(result, interp_result);
     }
@@ -285,32 +530,981 @@ mod test {
         let results = Results();
         let expr_str = "(- (/ $0 2) (* -2 $0))";
         let query = parse_query_from_str(expr_str).unwrap();
-        let code = generate_code(&query, 1, results.consumer()).unwrap();
+        let code = generate_code(&query, &Schema::unnamed(1), Layout::RowMajor, results.consumer()).unwrap();
         let data = vec![0, 1, 5, 10, 100, 1000];
         code.call(&[data.as_ptr() as usize, data.len()]);
         let mut interp_result = vec![];
-        run_query(&query, &data, 1, |r| match r {
+        run_query(&query, &Table::from_i64(1, &data), |r| match r {
             Atom::Num(n) => interp_result.push(n),
             _ => unreachable!()
-        });
+        }).unwrap();
         let result = results.take();
         assert_eq!(result, interp_result);
     }
 
+    /// Runs the query compiled and interpreted on the same data and compares the results.
+    /// The query written in SQL syntax has to give the same results.
+    fn compare_with_interpreter(query_str: &str, data: &[i64], columns: usize) {
+        compare_on_table(query_str, &Table::from_i64(columns, data));
+    }
+
+    /// Like `compare_with_interpreter` for a query that may refer to the columns of `table` by name
+    fn compare_on_table(query_str: &str, table: &Table) {
+        let mut query = parse_query_from_str(query_str).unwrap();
+        query.bind_with_spans(table.schema(), None).unwrap();
+        let results = compare_layouts(&query, table, query_str);
+        let sql = to_sql(&query);
+        let (mut sql_query, _) = parse_query(&sql, Syntax::Sql).unwrap();
+        sql_query.bind_with_spans(table.schema(), None).unwrap();
+        assert_eq!(compare_layouts(&sql_query, table, &sql), results, "{}", sql);
+    }
+
+    /// Queries on three columns that cover every aggregate and a query without one
+    const QUERIES: [&str; 15] = ["sum (+ $0 $1) where (> $2 0)", "prod (+ $0 7)", "avg $1", "max (* $0 $2)", "min $1 where (< $0 3)",
+        "count $0", "count(*) where (= $1 $2)", "count distinct (% $0 4)", "any (> $0 5)", "all (> $1 -6)",
+        "var_pop $2", "var_samp $2 where (> $0 0)", "stddev_pop (+ $0 $1)", "stddev_samp $0", "(- $0 $2) where (!= $1 0)"];
+
+    /// The results of the interpreter as generated code returns them
+    fn interpret(query: &Query, table: &Table) -> Vec<i64> {
+        let mut results = vec![];
+        run_query(query, table, |r| results.push(result_to_i64(r))).unwrap();
+        results
+    }
+
+    fn result_to_i64(result: Atom) -> i64 {
+        match result {
+            Atom::Num(n) => n,
+            // Only `any` and `all` have boolean results
+            Atom::Boolean(b) => b as i64,
+            Atom::Null | Atom::Str(_) => unreachable!("The table has no NULLs and results are never strings"),
+        }
+    }
+
+    /// Runs the query compiled for every layout of the table and compares with the interpreter.
+    /// Returns the results.
+    fn compare_layouts(query: &Query, table: &Table, query_str: &str) -> Vec<i64> {
+        let interp_result = interpret(query, table);
+        let eager = ![Some(&query.expr), query.filter.as_ref()].into_iter().flatten().any(can_trap);
+        for layout in [Layout::RowMajor, Layout::Columnar] {
+            let table = table.to_layout(layout);
+            let mut vectorized_result = vec![];
+            run_query_vectorized(query, &table, |r| vectorized_result.push(result_to_i64(r))).unwrap();
+            assert_eq!(vectorized_result, interp_result, "{} ({:?}, vectorized)", query_str, layout);
+            for short_circuit in [true, false].into_iter().filter(|&short_circuit| short_circuit || eager) {
+                let results = Results();
+                let options = CodeGenOptions { short_circuit, operand_order: OperandOrder::AsWritten };
+                let code = generate_code_with_options(query, table.schema(), layout, &options, results.consumer()).unwrap();
+                code.call(&table.call_args());
+                assert_eq!(results.take(), interp_result, "{} ({:?}, short circuit: {})", query_str, layout, short_circuit);
+            }
+            let prepared = PreparedQuery::new(query, table.schema(), layout, &CodeGenOptions::default()).unwrap();
+            // Buffers that fill up on every result, some of the time and never
+            for capacity in [1, 3, 1024] {
+                let mut results = vec![];
+                prepared.execute(&table, &[], &mut OutputBuffer::new(capacity, &mut |r| results.extend_from_slice(r))).unwrap();
+                assert_eq!(results, interp_result, "{} ({:?}, output buffer of {})", query_str, layout, capacity);
+            }
+        }
+        interp_result
+    }
+
+    #[test]
+    fn test_patch_const() {
+        let cg = CodeGen::new(&[DataType::I64]);
+        let x = I64Ref::from(cg.get_arg(0));
+        let (factor, factor_handle) = cg.new_patchable_i64(3);
+        let (offset, _) = cg.new_patchable_i64(100);
+        let result = x * &factor + &offset;
+        cg.gen_return(Some(result.into()));
+        let mut code = cg.generate_code();
+        assert_eq!(code.call(&[5]) as i64, 115);
+
+        code.patch_const(factor_handle, ConstValue::I64(-2)).unwrap();
+        assert_eq!(code.call(&[5]) as i64, 90);
+        // The handles are also there in the order the constants were created
+        assert_eq!(code.patchable_consts()[0], factor_handle);
+        let offset_handle = code.patchable_consts()[1];
+        code.patch_const(offset_handle, ConstValue::I64(-7)).unwrap();
+        assert_eq!(code.call(&[5]) as i64, -17);
+        assert!(code.patch_const(offset_handle, ConstValue::Bool(true)).is_err());
+        code.patch_const(offset_handle, ConstValue::I64(i64::MAX)).unwrap();
+        assert_eq!(code.call(&[6]) as i64, i64::MAX - 12);
+
+        // Handles of other code are rejected instead of patching a hole this code doesn't have
+        let cg = CodeGen::new(&[DataType::I64]);
+        cg.gen_return(Some(cg.get_arg(0)));
+        let mut other = cg.generate_code();
+        assert!(other.patch_const(offset_handle, ConstValue::I64(1)).is_err());
+    }
+
+    #[test]
+    fn test_query_cache() {
+        let data = (0..50).map(|v| (v * 7) % 11 - 5).collect::<Vec<i64>>();
+        let table = Table::from_i64(2, &data);
+        let options = CodeGenOptions::default();
+        // The statistics after the last lookup
+        let stats = Cell::new(CacheStats::default());
+        let run = |cache: &mut QueryCache, query_str: &str| {
+            let mut query = parse_query_from_str(query_str).unwrap();
+            query.bind_with_spans(table.schema(), None).unwrap();
+            let lookup = cache.get(&query, &table, &options).unwrap();
+            let mut results = vec![];
+            lookup.execute(&table, &mut OutputBuffer::new(4, &mut |r| results.extend_from_slice(r))).unwrap();
+            assert_eq!(results, interpret(&query, &table), "{}", query_str);
+            stats.set(lookup.stats);
+            lookup.hit
+        };
+
+        let mut cache = QueryCache::new(usize::MAX);
+        assert!(!run(&mut cache, "sum (+ $0 3) where (> $1 2)"));
+        // Only the literals are different
+        assert!(run(&mut cache, "sum (+ $0 -8) where (> $1 0)"));
+        // Constants are folded before looking the query up
+        assert!(run(&mut cache, "sum (+ $0 (* 2 3)) where (> $1 (- 4 2))"));
+        // Equal literals become the same parameter, so this is a different query
+        assert!(!run(&mut cache, "sum (+ $0 3) where (> $1 3)"));
+        assert!(!run(&mut cache, "count(*)"));
+        assert!(run(&mut cache, "sum (+ $0 3) where (> $1 2)"));
+        let CacheStats { hits, misses, evictions, entries, .. } = stats.get();
+        assert_eq!((hits, misses, evictions, entries), (3, 3, 0, 3));
+
+        // The limit is patched into the code, so it isn't part of the query that is looked up
+        assert!(!run(&mut cache, "$0 where (> $1 0) limit 3"));
+        assert!(run(&mut cache, "$0 where (> $1 2) limit 10"));
+        assert!(run(&mut cache, "$0 where (> $1 1) limit 1"));
+        assert!(!run(&mut cache, "$0 where (> $1 1) limit 0"));
+        assert!(!run(&mut cache, "$0 where (> $1 1) order by $0 limit 2"));
+
+        // Constant `in` lists and `between` bounds stay as they are, so the code is just as specialized
+        // as without the cache (the list is looked up in a table, the range check is one comparison)
+        let specialized = "sum $1 where (& (between $0 -2 3) (in $1 -5 -3 -1 0 2 4 5 6 7 9))";
+        assert!(!run(&mut cache, specialized));
+        let mut query = parse_query_from_str(specialized).unwrap();
+        query.bind_with_spans(table.schema(), None).unwrap();
+        let lookup = cache.get(&query, &table, &options).unwrap();
+        assert!(lookup.values.is_empty());
+        let prepared = PreparedQuery::new(&query, table.schema(), table.layout(), &options).unwrap();
+        assert_eq!(lookup.code.code_len(), prepared.code_len());
+        assert!(!run(&mut cache, "sum (+ $1 2) where (& (between $0 -2 3) (in $1 -5 -3 -1 0 2 4 5 6 7 9))"));
+        assert!(run(&mut cache, "sum (+ $1 7) where (& (between $0 -2 3) (in $1 -5 -3 -1 0 2 4 5 6 7 9))"));
+        assert!(!run(&mut cache, "sum (+ $1 7) where (& (between $0 -2 4) (in $1 -5 -3 -1 0 2 4 5 6 7 9))"));
+        assert!(!run(&mut cache, "sum (+ $1 7) where (& (between $0 -2 3) (in $1 -5 -3 -1 0 2 4 5 6 7 8))"));
+
+        // Only the query that was compiled last fits
+        let mut cache = QueryCache::new(1);
+        assert!(!run(&mut cache, "sum (+ $0 3) where (> $1 2)"));
+        assert!(!run(&mut cache, "count(*)"));
+        assert!(!run(&mut cache, "sum (+ $0 3) where (> $1 2)"));
+        assert!(run(&mut cache, "sum (+ $0 1) where (> $1 -1)"));
+        let CacheStats { hits, misses, evictions, entries, .. } = stats.get();
+        assert_eq!((hits, misses, evictions, entries), (1, 3, 2, 1));
+    }
+
+    #[test]
+    fn test_adaptive_execution() {
+        let data = (0..90).map(|v| (v * 7) % 13 - 6).collect::<Vec<i64>>();
+        let table = Table::from_i64(3, &data);
+        let results = Results();
+        let options = CodeGenOptions::default();
+        for query_str in QUERIES {
+            let query = parse_query_from_str(query_str).unwrap();
+            let expected = interpret(&query, &table);
+            for layout in [Layout::RowMajor, Layout::Columnar] {
+                let table = table.to_layout(layout);
+                let code = generate_resumable_code(&query, table.schema(), layout, &options, results.consumer()).unwrap();
+                // The interpreter does the first rows, the generated code the rest
+                for switch_at in [0, 1, 17, table.rows()] {
+                    let mut state = AggregateState::new(query.aggregate);
+                    let mut interpreted = vec![];
+                    run_rows(&query, &table, 0..switch_at, &mut state, &mut |r| interpreted.push(r.get_num())).unwrap();
+                    run_resumable(&code, &table, switch_at, state);
+                    interpreted.extend(results.take());
+                    assert_eq!(interpreted, expected, "{} ({:?}, switched at row {})", query_str, layout, switch_at);
+                }
+                run_adaptive(&query, &table, &options, 4, results.consumer()).unwrap();
+                assert_eq!(results.take(), expected, "{} ({:?}, adaptive)", query_str, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parallel_execution() {
+        let data = (0..300).map(|v| (v * 7) % 13 - 6).collect::<Vec<i64>>();
+        let table = Table::from_i64(3, &data);
+        let results = Results();
+        let options = CodeGenOptions::default();
+        for query_str in QUERIES {
+            let query = parse_query_from_str(query_str).unwrap();
+            let expected = interpret(&query, &table);
+            for layout in [Layout::RowMajor, Layout::Columnar] {
+                let table = table.to_layout(layout);
+                for (threads, morsel_size) in [(1, 1000), (2, 7), (4, 1), (8, 64)] {
+                    run_parallel(&query, &table, &options, threads, morsel_size, results.consumer()).unwrap();
+                    assert_eq!(results.take(), expected, "{} ({:?}, {} threads, morsels of {} rows)", query_str, layout, threads, morsel_size);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_vectorized_batches() {
+        // More than two batches, so some of them are full and the last one isn't
+        let data = (0..3 * (2 * BATCH_SIZE as i64 + 100)).map(|v| (v * 7) % 13 - 6).collect::<Vec<i64>>();
+        let table = Table::from_i64(3, &data);
+        for query_str in ["sum (+ $0 $1) where (> $2 0)", "avg $1", "count distinct (% $0 4)", "all (> $1 -6)", "var_samp $2 where (> $0 0)",
+            "(- $0 $2) where (| (= $1 0) (& (> $0 2) (!= $2 0) (!= (/ 12 $2) 3)))", "count(*) where (| (> $0 4) (< $1 -4) (= $2 0))"] {
+            let query = parse_query_from_str(query_str).unwrap();
+            let mut expected = vec![];
+            run_query(&query, &table, |r| expected.push(r)).unwrap();
+            for layout in [Layout::RowMajor, Layout::Columnar] {
+                let mut results = vec![];
+                run_query_vectorized(&query, &table.to_layout(layout), |r| results.push(r)).unwrap();
+                assert_eq!(results, expected, "{} ({:?})", query_str, layout);
+            }
+        }
+        let division_by_zero = parse_query_from_str("sum (/ $0 (- $1 $1))").unwrap();
+        assert_eq!(run_query_vectorized(&division_by_zero, &table, |_| ()), Err(EvalError::DivisionByZero.to_string()));
+    }
+
+    #[test]
+    fn test_order_by_limit() {
+        let table = Table::from_i64(2, &[3, 1, -2, 5, 7, 5, 0, -1, 4, 2]);
+        for (query_str, expected) in [
+            // Results with the same key stay in the order of their rows
+            ("$0 order by $1 desc limit 3", vec![-2, 7, 4]),
+            ("$0 where (> $0 0) order by $0", vec![3, 4, 7]),
+            ("$0 order by (> $1 1)", vec![3, 0, -2, 7, 4]),
+            ("(* $0 2) limit 2", vec![6, -4]),
+            ("$0 limit 0", vec![]),
+        ] {
+            let query = parse_query_from_str(query_str).unwrap();
+            let mut results = vec![];
+            run_query(&query, &table, |r| results.push(r.get_num())).unwrap();
+            assert_eq!(results, expected, "{}", query_str);
+        }
+        assert_eq!(parse_query("SELECT a FROM t ORDER BY b DESC LIMIT 3", Syntax::Sql).unwrap().0, parse_query_from_str("a order by b desc limit 3").unwrap());
+        assert!(parse_query_from_str("sum $0 limit 1").unwrap().check_types(table.schema(), None).is_err());
+
+        let data = (0..3 * (BATCH_SIZE as i64 + 10)).map(|v| (v * 7) % 13 - 6).collect::<Vec<i64>>();
+        compare_with_interpreter("$0 where (> $1 0) order by $2 desc limit 5", &data, 3);
+        compare_with_interpreter("(+ $0 $1) order by (* $2 $0)", &data, 3);
+        compare_with_interpreter("$1 where (< $0 0) limit 400", &data, 3);
+    }
+
+    #[test]
+    fn test_in_between() {
+        let table = Table::from_i64(1, &[3, -4, 10, 7, 20, 21, i64::MIN, i64::MAX]);
+        for (query_str, expected) in [
+            ("$0 where (in $0 3 5 7 11)", vec![3, 7]),
+            ("$0 where (between $0 -4 10)", vec![3, -4, 10, 7]),
+            ("$0 where (between $0 10 -4)", vec![]),
+            ("count(*) where (between 5 $0 21)", vec![3]),
+        ] {
+            let query = parse_query_from_str(query_str).unwrap();
+            let mut results = vec![];
+            run_query(&query, &table, |r| results.push(r.get_num())).unwrap();
+            assert_eq!(results, expected, "{}", query_str);
+        }
+        assert_eq!(
+            parse_query("SELECT a WHERE a IN (3, 5 + 2) AND a BETWEEN 1 AND 5 | 2", Syntax::Sql).unwrap().0,
+            parse_query_from_str("a where (& (in a 3 (+ 5 2)) (between a 1 (| 5 2)))").unwrap()
+        );
+        let type_error = |query_str: &str| parse_query_from_str(query_str).unwrap().check_types(table.schema(), None).unwrap_err().to_string();
+        assert!(type_error("$0 where (between $0 1)").contains("`between` takes exactly 3 operands, found 2"));
+        assert!(type_error("$0 where (in $0)").contains("`in` takes at least 2 operands, found 1"));
+
+        let data = (0..2 * (BATCH_SIZE as i64 + 10)).map(|v| (v * 7919) % 1000 - 500).collect::<Vec<i64>>();
+        let evens = (0..=20).map(|v| (v * 2).to_string()).collect::<Vec<_>>().join(" ");
+        // Too many values spread too far for a perfect hash table, so these are searched
+        let spread = (0..300u64).map(|v| {
+            let z = v.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            ((z ^ (z >> 29)).wrapping_mul(0xbf58_476d_1ce4_e5b9) as i64 / 2).to_string()
+        }).chain((-500..500).step_by(7).map(|v: i64| v.to_string())).collect::<Vec<_>>().join(" ");
+        for query_str in [
+            "$0 where (in $1 3 5 -7)".to_string(),
+            "$0 where (in $0 $1 (+ $1 1) 8)".to_string(),
+            format!("$1 where (in $0 {evens})"),
+            format!("count(*) where (in $0 {spread})"),
+            "$1 where (between $0 -100 100)".to_string(),
+            "$1 where (between $0 -9223372036854775807 9223372036854775807)".to_string(),
+            "count(*) where (between $0 $1 (+ $1 50))".to_string(),
+        ] {
+            compare_with_interpreter(&query_str, &data, 2);
+        }
+    }
+
+    #[test]
+    fn test_nulls() {
+        let schema = Schema::new(vec![ColumnDef::nullable("a", DataType::I64), ColumnDef::nullable("b", DataType::I64)]).unwrap();
+        let mut table = Table::new(schema.clone());
+        for row in [[Some(1), None], [None, None], [Some(3), Some(2)], [Some(4), Some(5)]] {
+            table.push_nullable_row(&row.map(|v| v.map(|v| Value::Const(ConstValue::I64(v)))));
+        }
+        let bind = |query_str: &str| {
+            let mut query = parse_query_from_str(query_str).unwrap();
+            query.bind_with_spans(&schema, None).map(|_| query)
+        };
+        const T: Atom = Atom::Boolean(true);
+        const F: Atom = Atom::Boolean(false);
+        const NULL: Atom = Atom::Null;
+        let n = Atom::Num;
+        let rows = (0..table.rows()).map(|row| vec![table.atom(row, 0), table.atom(row, 1)]).collect::<Vec<_>>();
+        for (expr_str, expected) in [
+            ("(+ $0 $1)", vec![NULL, NULL, n(5), n(9)]),
+            ("(/ $0 $1)", vec![NULL, NULL, n(1), n(0)]),
+            ("(& (> $0 2) (> $1 1))", vec![F, NULL, T, T]),
+            ("(| (> $0 2) (> $1 1))", vec![NULL, NULL, T, T]),
+            ("(in $0 1 $1)", vec![T, NULL, F, F]),
+            ("(in $0 3 $1)", vec![NULL, NULL, T, F]),
+            ("(between 2 $0 $1)", vec![NULL, NULL, F, F]),
+            ("(is-null $1)", vec![T, T, F, F]),
+            ("(coalesce $1 $0 0)", vec![n(1), n(0), n(2), n(5)]),
+        ] {
+            let expr = parse_query_from_str(expr_str).unwrap().expr;
+            assert_eq!(rows.iter().map(|row| eval_expression(&expr, row).unwrap()).collect::<Vec<_>>(), expected, "{}", expr_str);
+        }
+        for (query_str, expected) in [
+            ("a where (> b 1)", vec![n(3), n(4)]),
+            ("(+ a b)", vec![NULL, NULL, n(5), n(9)]),
+            ("sum b", vec![n(7)]),
+            ("count a", vec![n(3)]),
+            ("count(*) where (is-null a)", vec![n(1)]),
+        ] {
+            let mut results = vec![];
+            run_query(&bind(query_str).unwrap(), &table, |r| results.push(r)).unwrap();
+            assert_eq!(results, expected, "{}", query_str);
+        }
+        // The REPL interprets the queries whose results can be NULL and compiles the others
+        let mut cache = QueryCache::new(usize::MAX);
+        for (query_str, expected) in [("a", vec![n(1), NULL, n(3), n(4)]), ("sum a", vec![n(8)])] {
+            let mut results = vec![];
+            eval(&bind(query_str).unwrap(), &table, &CodeGenOptions::default(), None, &mut cache, &mut |r| results.push(r));
+            assert_eq!(results, expected, "{}", query_str);
+        }
+        assert_eq!(run_query_vectorized(&bind("sum a").unwrap(), &table, |_| ()), Err("The vectorized interpreter doesn't support NULLs".to_string()));
+        // NULL keys don't even match each other
+        let mut results = vec![];
+        let mut join = parse_query_from_str("l.$0 join on (= l.$1 r.$1)").unwrap();
+        join.bind_join(&schema, &schema, None).unwrap();
+        run_join_query(&join, &table, &table, |r| results.push(r.get_num())).unwrap();
+        assert_eq!(results, vec![3, 4]);
+
+        assert_eq!(
+            parse_query("SELECT coalesce(a, 0) WHERE b IS NULL AND a IS NOT NULL", Syntax::Sql).unwrap().0,
+            parse_query_from_str("(coalesce a 0) where (& (is-null b) (= (is-null a) #f))").unwrap()
+        );
+        let sql = to_sql(&parse_query_from_str("count(*) where (is-null (coalesce a b))").unwrap());
+        assert_eq!(parse_query(&sql, Syntax::Sql).unwrap().0, parse_query_from_str("count(*) where (is-null (coalesce a b))").unwrap(), "{}", sql);
+        assert!(bind("a where (is-null a b)").unwrap_err().message.contains("`is-null` takes exactly 1 operand, found 2"));
+        let order_by_null = bind("a order by b").unwrap_err();
+        assert!(order_by_null.message.contains("can't be ordered by an expression that can be NULL"), "{}", order_by_null.message);
+        // The product is NULL for NULLs, not 0
+        let times_zero = parse_query_from_str("(* $0 0)").unwrap().expr;
+        assert_eq!(simplify(&times_zero, &[DataType::I64], &[true]), Ok(times_zero.clone()));
+        assert_eq!(simplify(&times_zero, &[DataType::I64], &[false]), Ok(Expr::Constant(Atom::Num(0))));
+
+        let dir = std::env::temp_dir();
+        std::fs::write(dir.join("nulls.csv"), "a,b\n1,\n,2\n3,4\n").unwrap();
+        let inferred = load_csv(&dir.join("nulls.csv"), true, None).unwrap();
+        assert_eq!(inferred.schema().nullable_columns(), vec![true, true]);
+        assert_eq!((inferred.is_null(0, 1), inferred.is_null(1, 0), inferred.is_null(2, 1)), (true, true, false));
+        std::fs::write(dir.join("nulls.schema"), "a i64 null\nb i64\n").unwrap();
+        let error = load_csv(&dir.join("nulls.csv"), true, Some(Schema::from_file(&dir.join("nulls.schema")).unwrap())).map(|_| ()).unwrap_err();
+        assert!(error.to_string().contains("the value is missing but the column can't be NULL"), "{}", error);
+
+        // Enough rows for several words of the bitmaps
+        let schema = Schema::new(vec![ColumnDef::nullable("a", DataType::I64), ColumnDef::nullable("b", DataType::I32), ColumnDef::new("c", DataType::I64)]).unwrap();
+        let mut table = Table::new(schema.clone());
+        for i in 0..150i64 {
+            table.push_nullable_row(&[
+                (i % 3 != 0).then_some(Value::Const(ConstValue::I64(i % 17 - 8))),
+                (i % 5 != 0).then_some(Value::Const(ConstValue::I32((i % 7) as i32))),
+                Some(Value::Const(ConstValue::I64(i))),
+            ]);
+        }
+        let bind = |query_str: &str| {
+            let mut query = parse_query_from_str(query_str).unwrap();
+            query.bind_with_spans(&schema, None).map(|_| query)
+        };
+        assert!(matches!(generate_code(&bind("(+ a c)").unwrap(), &schema, Layout::RowMajor, Results().consumer()), Err(CodeGenError::Unsupported(_))));
+        for query_str in [
+            "sum a",
+            "count (+ a b)",
+            "max (/ c b)",
+            "c where (> a b)",
+            "c where (& (> a 0) (< b 3))",
+            "c where (| (> a 0) (< b 3) (is-null a))",
+            "c where (in a 1 2 b)",
+            "count(*) where (between c a (+ b 100))",
+            "(coalesce a b -1) where (| (< a 0) (is-null b))",
+            "any (= a b)",
+        ] {
+            let query = bind(query_str).unwrap();
+            let mut expected = vec![];
+            run_query(&query, &table, |r| match r {
+                Atom::Num(n) => expected.push(n),
+                Atom::Boolean(b) => expected.push(b as i64),
+                Atom::Null | Atom::Str(_) => unreachable!("Queries that can have NULL results aren't compiled"),
+            }).unwrap();
+            for layout in [Layout::RowMajor, Layout::Columnar] {
+                let table = table.to_layout(layout);
+                for short_circuit in [true, false] {
+                    let results = Results();
+                    let options = CodeGenOptions { short_circuit, operand_order: OperandOrder::AsWritten };
+                    let code = generate_code_with_options(&query, &schema, layout, &options, results.consumer()).unwrap();
+                    code.call(&table.call_args());
+                    assert_eq!(results.take(), expected, "{} ({:?}, short circuit: {})", query_str, layout, short_circuit);
+                }
+            }
+            let results = Results();
+            run_parallel(&query, &table, &CodeGenOptions::default(), 3, 40, results.consumer()).unwrap();
+            assert_eq!(results.take(), expected, "{} (parallel)", query_str);
+        }
+    }
+
+    #[test]
+    fn test_strings() {
+        assert!(like("api-gateway", "api%"));
+        assert!(like("api", "a_i"));
+        assert!(like("auth", "%th%"));
+        assert!(!like("auth", "_th"));
+        assert!(like("ünïcode", "_n_code"));
+        assert!(like("", "%"));
+
+        // (service, status, latency)
+        let schema = Schema::new(vec![ColumnDef::nullable("service", DataType::Str), ColumnDef::new("status", DataType::I32), ColumnDef::new("latency", DataType::I64)]).unwrap();
+        let mut table = Table::new(schema.clone());
+        let services = ["api", "auth", "api-gateway", "billing", "", "it's"];
+        for i in 0..60i64 {
+            table.push_nullable_row(&[
+                (i % 7 != 6).then_some(Value::Str(services[i as usize % services.len()])),
+                Some(Value::Const(ConstValue::I32(if i % 4 == 0 { 500 } else { 200 }))),
+                Some(Value::Const(ConstValue::I64(i * 3 % 50))),
+            ]);
+        }
+        assert_eq!((table.string(2, 0), table.atom(5, 0), table.atom(6, 0)), ("api-gateway", Atom::Str("it's".into()), Atom::Null));
+        let bind = |query_str: &str| {
+            let mut query = parse_query_from_str(query_str).unwrap();
+            query.bind_with_spans(&schema, None).map(|_| query)
+        };
+        let literal = parse_query_from_str("'it''s'").unwrap().expr;
+        assert_eq!(literal, Expr::Constant(Atom::Str("it's".into())));
+        assert_eq!(Atom::Str("it's".into()).to_string(), "'it''s'");
+        assert!(parse_query_from_str("count(*) where (= service 'api)").is_err());
+        for (query_str, sql) in [
+            ("count(*) where (= service 'api')", "SELECT count(*) WHERE service = 'api'"),
+            ("sum latency where (like service 'a%')", "SELECT sum(latency) WHERE service LIKE 'a%'"),
+            ("count(*) where (= (like service '%i%') #f)", "SELECT count(*) WHERE service NOT LIKE '%i%'"),
+            ("max latency where (starts-with service 'api')", "SELECT max(latency) WHERE starts_with(service, 'api')"),
+            ("sum (length service)", "SELECT sum(length(service))"),
+            ("count(*) where (< service 'b')", "SELECT count(*) WHERE service < 'b'"),
+            ("latency where (& (>= service 'auth') (= status 500))", "SELECT latency WHERE service >= 'auth' AND status = 500"),
+            ("count(*) where (in service 'auth' 'it''s')", "SELECT count(*) WHERE service IN ('auth', 'it''s')"),
+            ("count(*) where (is-null service)", "SELECT count(*) WHERE service IS NULL"),
+        ] {
+            let query = bind(query_str).unwrap();
+            assert_eq!(parse_query(sql, Syntax::Sql).unwrap().0, parse_query_from_str(query_str).unwrap(), "{}", sql);
+            assert_eq!(parse_query(&to_sql(&query), Syntax::Sql).unwrap().0, query, "{}", to_sql(&query));
+
+            let mut expected = vec![];
+            run_query(&query, &table, |r| expected.push(r.get_num())).unwrap();
+            for layout in [Layout::RowMajor, Layout::Columnar] {
+                let table = table.to_layout(layout);
+                let results = Results();
+                let code = generate_code(&query, &schema, layout, results.consumer()).unwrap();
+                code.call(&table.call_args());
+                assert_eq!(results.take(), expected, "{} ({:?})", query_str, layout);
+            }
+            let results = Results();
+            run_parallel(&query, &table, &CodeGenOptions::default(), 3, 16, results.consumer()).unwrap();
+            assert_eq!(results.take(), expected, "{} (parallel)", query_str);
+        }
+        let mut results = vec![];
+        run_query(&bind("count(*) where (like service 'a%')").unwrap(), &table, |r| results.push(r.get_num())).unwrap();
+        assert_eq!(results, vec![25]);
+        for (query_str, error) in [
+            ("count(*) where (< service 1)", "the first one is str but this one is i64"),
+            ("sum (+ service 1)", "`+` expects i64 operands, found str"),
+            ("count(*) where (like service)", "`like` takes exactly 2 operands, found 1"),
+            ("count(*) where (starts-with latency 'a')", "`starts-with` expects str operands, found i64"),
+        ] {
+            let message = bind(query_str).unwrap_err().message;
+            assert!(message.contains(error), "{}: {}", query_str, message);
+        }
+        assert!(bind("service").unwrap_err().message.contains("found str"));
+        assert!(bind("latency order by service").is_err());
+
+        let dir = std::env::temp_dir();
+        std::fs::write(dir.join("logs.csv"), "service,latency\napi,12\n,7\nauth,30\n").unwrap();
+        let logs = load_csv(&dir.join("logs.csv"), true, None).unwrap();
+        assert_eq!(logs.schema().columns()[0].data_type, DataType::Str);
+        assert_eq!((logs.string(2, 0), logs.is_null(1, 0)), ("auth", true));
+    }
+
+    #[test]
+    fn test_join() {
+        // (id, price) and (order id, quantity)
+        let orders = Table::from_i64(2, &[1, 10, 2, 20, 3, 30, 2, 25]);
+        let items = Table::from_i64(2, &[2, 1, 1, 5, 2, 3, 4, 7]);
+        let bind = |query_str: &str| {
+            let mut query = parse_query_from_str(query_str).unwrap();
+            query.bind_join(orders.schema(), items.schema(), None).map(|_| query)
+        };
+        for (query_str, expected) in [
+            // Rows of `r` with the same key come in the order of the rows of `r`
+            ("(* l.$1 r.$1) join on (= l.$0 r.$0)", vec![50, 20, 60, 25, 75]),
+            ("sum r.$1 join on (= r.$0 l.$0) where (> l.$1 15)", vec![8]),
+            ("count(*) join on (= (% l.$0 2) (% r.$0 2))", vec![8]),
+            ("l.$0 join on (= l.$0 r.$0) order by $3 desc limit 2", vec![1, 2]),
+            ("(+ l.$1 r.$1) join on (= l.$0 r.$0) limit 2", vec![15, 21]),
+        ] {
+            let query = bind(query_str).unwrap();
+            let mut expected_results = vec![];
+            run_join_query(&query, &orders, &items, |r| expected_results.push(r.get_num())).unwrap();
+            assert_eq!(expected_results, expected, "{}", query_str);
+            let (sql_query, _) = parse_query(&to_sql(&query), Syntax::Sql).unwrap();
+            assert_eq!(sql_query, query, "{}", to_sql(&query));
+            for (left_layout, right_layout) in [(Layout::RowMajor, Layout::RowMajor), (Layout::RowMajor, Layout::Columnar), (Layout::Columnar, Layout::RowMajor), (Layout::Columnar, Layout::Columnar)] {
+                let (left, right) = (orders.to_layout(left_layout), items.to_layout(right_layout));
+                let code = PreparedQuery::new_join(&query, left.schema(), left_layout, right.schema(), right_layout, &CodeGenOptions::default()).unwrap();
+                let mut results = vec![];
+                code.execute_join(&left, &right, &[], &mut OutputBuffer::new(2, &mut |r| results.extend_from_slice(r))).unwrap();
+                assert_eq!(results, expected, "{} ({:?}, {:?})", query_str, left_layout, right_layout);
+            }
+        }
+        assert_eq!(parse_query("SELECT sum(r.$1) FROM orders JOIN items ON l.$0 = r.$0 WHERE l.$1 > 15", Syntax::Sql).unwrap().0,
+            parse_query_from_str("sum r.$1 join on (= l.$0 r.$0) where (> l.$1 15)").unwrap());
+        assert!(bind("l.$0 join on (= l.$0 l.$1)").is_err());
+        assert!(bind("r.$2 join on (= l.$0 r.$0)").is_err());
+        assert!(parse_query_from_str("l.$0 join on (> l.$0 r.$0)").is_err());
+        assert!(parse_query_from_str("sum r.$1 join on (= l.$0 r.$0)").unwrap().bind_with_spans(orders.schema(), None).is_err());
+
+        let query = bind("sum r.$1 join on (= l.$0 r.$0) where (> l.$1 ?1)").unwrap();
+        let code = PreparedQuery::new_join(&query, orders.schema(), orders.layout(), items.schema(), items.layout(), &CodeGenOptions::default()).unwrap();
+        let mut results = vec![];
+        code.execute_join(&orders, &items, &[15], &mut OutputBuffer::new(2, &mut |r| results.extend_from_slice(r))).unwrap();
+        assert_eq!(results, vec![8]);
+        assert!(code.execute(&orders, &[15], &mut OutputBuffer::new(2, &mut |_| {})).is_err());
+        assert!(code.execute_join(&orders, &orders, &[15], &mut OutputBuffer::new(2, &mut |_| {})).is_err());
+    }
+
+    #[test]
+    fn test_column_pruning() {
+        let query = parse_query_from_str("sum (+ $3 $1) where (> $1 $0)").unwrap();
+        assert_eq!(query.referenced_columns().into_iter().collect::<Vec<_>>(), vec![0, 1, 3]);
+
+        let data = (0..40).map(|v| (v * 7) % 11 - 5).collect::<Vec<i64>>();
+        compare_with_interpreter("sum (+ $3 $1) where (> $1 $0)", &data, 4);
+        compare_with_interpreter("$2 where (< $0 0)", &data, 4);
+        compare_with_interpreter("count(*) where (= $1 $1)", &data, 4);
+        compare_with_interpreter("count(*)", &data, 4);
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        let data = vec![0i64, 3, 4, 0, 50, 5, 100, 1];
+        // The division is only safe because it is skipped for the rows where $0 is 0
+        let guarded = "count(*) where (& (!= $0 0) (> (/ 100 $0) 5))";
+        compare_with_interpreter(guarded, &data, 2);
+        compare_with_interpreter("count(*) where (| (= $0 0) (> (/ 100 $0) 5))", &data, 2);
+        compare_with_interpreter("all (| (> $1 2) (= $0 0) (< $0 0))", &data, 2);
+        // Compiled eagerly the division runs for every row, which is only fine without zero divisors
+        let nonzero = Table::from_i64(2, &[3, 4, -50, 5, 100, 1, 7, 0]);
+        let query = parse_query_from_str(guarded).unwrap();
+        let results = Results();
+        let options = CodeGenOptions { short_circuit: false, operand_order: OperandOrder::AsWritten };
+        let code = generate_code_with_options(&query, nonzero.schema(), Layout::RowMajor, &options, results.consumer()).unwrap();
+        code.call(&nonzero.call_args());
+        assert_eq!(results.take(), vec![2]);
+        // Without the guard the interpreter fails where the generated code would trap
+        let unguarded = parse_query_from_str("count(*) where (> (/ 100 $0) 5)").unwrap();
+        assert_eq!(run_query(&unguarded, &Table::from_i64(2, &data), |_| {}), Err(EvalError::DivisionByZero));
+        let min_rem = parse_query_from_str("(% $0 $1)").unwrap().expr;
+        assert_eq!(eval_expression(&min_rem, &[Atom::Num(i64::MIN), Atom::Num(-1)]), Err(EvalError::Overflow));
+        assert_eq!(eval_expression(&min_rem, &[Atom::Num(i64::MIN), Atom::Num(-2)]), Ok(Atom::Num(0)));
+
+        let table = Table::from_i64(2, &data);
+        let query = parse_query_from_str("count(*) where (& (> (% $0 7) (/ $1 3)) (< $1 3) (< $0 60))").unwrap();
+        let filter = query.filter.as_ref().unwrap();
+        let selectivity = observe_selectivity(filter, &table, 1000);
+        assert_eq!(selectivity, vec![0.5, 0.5, 0.75]);
+        assert!(estimated_cost(&parse_query_from_str("(% $0 7)").unwrap().expr) > estimated_cost(&parse_query_from_str("(> $1 3)").unwrap().expr));
+        for operand_order in [OperandOrder::Cost, OperandOrder::Selectivity(selectivity)] {
+            let results = Results();
+            let options = CodeGenOptions { short_circuit: true, operand_order };
+            let code = generate_code_with_options(&query, table.schema(), Layout::RowMajor, &options, results.consumer()).unwrap();
+            code.call(&table.call_args());
+            assert_eq!(results.take(), vec![1]);
+        }
+        // The division is more likely to decide the result, but it can't be moved in front of its guard
+        let table = Table::from_i64(2, &[0, 1, 50, 2, 100, 3, 200, 4, 10, 5]);
+        let query = parse_query_from_str(guarded).unwrap();
+        let selectivity = observe_selectivity(query.filter.as_ref().unwrap(), &table, 1000);
+        assert_eq!(selectivity, vec![0.8, 0.2]);
+        for operand_order in [OperandOrder::Cost, OperandOrder::Selectivity(selectivity)] {
+            let results = Results();
+            let options = CodeGenOptions { short_circuit: true, operand_order };
+            let code = generate_code_with_options(&query, table.schema(), Layout::RowMajor, &options, results.consumer()).unwrap();
+            code.call(&table.call_args());
+            assert_eq!(results.take(), vec![1]);
+        }
+    }
+
+    #[test]
+    fn test_simplify() {
+        let column_types = [DataType::I64, DataType::I64, DataType::Bool];
+        let simplified = |expr: &str| simplify(&parse_query_from_str(expr).unwrap().expr, &column_types, &[]).unwrap();
+        let expr = |expr: &str| parse_query_from_str(expr).unwrap().expr;
+        assert_eq!(simplified("(< 1 2)"), expr("#t"));
+        assert_eq!(simplified("(% 7 4)"), expr("3"));
+        assert_eq!(simplified("(+ 1 $0 (+ 2 $1))"), expr("(+ $0 $1 3)"));
+        assert_eq!(simplified("(* $0 (+ $1 2) 3)"), expr("(* (+ $1 2) $0 3)"));
+        assert_eq!(simplified("(* $0 1)"), expr("$0"));
+        assert_eq!(simplified("(+ (* $0 0) $1)"), expr("$1"));
+        assert_eq!(simplified("(- 0 (- 0 $0))"), expr("$0"));
+        assert_eq!(simplified("(- (- $0 1) $1 2)"), expr("(- $0 $1 3)"));
+        assert_eq!(simplified("(& #t $2)"), expr("$2"));
+        assert_eq!(simplified("(| $2 (= $0 $0) #t)"), expr("#t"));
+        assert_eq!(simplified("(/ (/ 100 5) $0 1)"), expr("(/ 20 $0)"));
+        assert_eq!(simplified("(% $0 1)"), expr("0"));
+        // Divisions that trap for every row are errors before any code is generated
+        let error = |expr: &str| simplify(&parse_query_from_str(expr).unwrap().expr, &column_types, &[]).unwrap_err();
+        assert_eq!(error("(/ 1 0)"), EvalError::DivisionByZero);
+        assert_eq!(error("(+ $1 (% $0 3 0))"), EvalError::DivisionByZero);
+        assert_eq!(error("(/ (- -9223372036854775807 1) -1 $0)"), EvalError::Overflow);
+        assert_eq!(simplified("(/ $0 -1)"), expr("(/ $0 -1)"));
+        let query = parse_query_from_str("count(*) where (& (!= $0 0) (> (/ 100 0) 5))").unwrap();
+        assert!(matches!(generate_code(&query, &Schema::unnamed(2), Layout::RowMajor, Results().consumer()), Err(CodeGenError::Eval(EvalError::DivisionByZero))));
+
+        let data = vec![3i64, -4, 10, 7, -8, 2];
+        compare_with_interpreter("sum (- 100 $0 (* 2 3))", &data, 2);
+        compare_with_interpreter("(+ 1 2)", &data, 2);
+        compare_with_interpreter("(% 1000 (+ $1 (* 0 $0) 20)) where (& #t (> 5 $0))", &data, 2);
+        compare_with_interpreter("count(*) where (| #f (<= 3 $0))", &data, 2);
+    }
+
+    #[test]
+    fn test_common_subexpressions() {
+        let data = vec![3i64, -4, 10, 7, -8, 2, 0, 5];
+        compare_with_interpreter("(+ (* $0 $1) (* $0 $1))", &data, 2);
+        compare_with_interpreter("sum (- (* (+ $0 4) $1) (+ $0 4)) where (> (+ $0 4) 2)", &data, 2);
+        compare_with_interpreter("count(*) where (| (< (* $0 $1) 0) (> (* $0 $1) 10) (= (- $1 (* $0 $1)) 3))", &data, 2);
+        // The repeated product is only computed once
+        let code_len = |query: &str| {
+            let query = parse_query_from_str(query).unwrap();
+            generate_code(&query, &Schema::unnamed(2), Layout::RowMajor, Results().consumer()).unwrap().code_len
+        };
+        assert!(code_len("(+ (* $0 $1) (* $0 $1))") < code_len("(+ (* $0 $1) (* $1 $0))"));
+    }
+
+    #[test]
+    fn test_codegen_count() {
+        let data = vec![1i64, 10, 2, 20, 3, 30, 2, 40, 1, 50];
+        compare_with_interpreter("count(*)", &data, 2);
+        compare_with_interpreter("COUNT (*) where (> $1 15)", &data, 2);
+        compare_with_interpreter("count $0 where (< $1 35)", &data, 2);
+        compare_with_interpreter("count distinct $0", &data, 2);
+        compare_with_interpreter("count distinct (% $1 20) where (> $0 1)", &data, 2);
+        // Every run starts with an empty set
+        let table = Table::from_i64(2, &data);
+        let results = Results();
+        let code = generate_code(&parse_query_from_str("count distinct $0").unwrap(), table.schema(), Layout::RowMajor, results.consumer()).unwrap();
+        code.call(&table.call_args());
+        code.call(&table.call_args());
+        assert_eq!(results.take(), vec![3, 3]);
+    }
+
+    #[test]
+    fn test_codegen_any_all() {
+        let data = vec![1i64, 10, 2, 20, 3, 30, 2, 40, 1, 50];
+        compare_with_interpreter("any (> $1 45)", &data, 2);
+        compare_with_interpreter("bool_or (> $1 45) where (> $0 1)", &data, 2);
+        compare_with_interpreter("all (> $1 5)", &data, 2);
+        compare_with_interpreter("bool_and (> $1 15)", &data, 2);
+    }
+
+    #[test]
+    fn test_codegen_variance() {
+        let data = vec![0i64, 1, 5, 10, 100, 1000, -7, 42];
+        compare_with_interpreter("var_pop $0", &data, 1);
+        compare_with_interpreter("var_samp (* $0 2)", &data, 1);
+        compare_with_interpreter("stddev_pop $0", &data, 1);
+        compare_with_interpreter("stddev $0 where (< $0 50)", &data, 1);
+        // Empty input must not divide by zero
+        compare_with_interpreter("variance $0 where (> $0 5000)", &data, 1);
+    }
+
+    #[test]
+    fn test_codegen_basic_aggregates() {
+        let data = vec![3i64, -4, 1, 7, 9, -2, 5, 6];
+        for agg in ["sum", "prod", "avg", "max", "min"] {
+            compare_with_interpreter(&format!("{agg} $0"), &data, 2);
+            compare_with_interpreter(&format!("{agg} (* $0 $1) where (> $1 0)"), &data, 2);
+            // No row passes the filter
+            compare_with_interpreter(&format!("{agg} $1 where (> $0 100)"), &data, 2);
+        }
+    }
+
+    #[test]
+    fn test_named_columns() {
+        let schema = Schema::new(vec![
+            ColumnDef::new("price", DataType::I64),
+            ColumnDef::new("order id", DataType::I64),
+            ColumnDef::new("qty", DataType::I64),
+        ]).unwrap();
+        let mut query = parse_query_from_str("sum (* price qty) where (> \"order id\" 1)").unwrap();
+        query.bind_with_spans(&schema, None).unwrap();
+        assert_eq!(query, parse_query_from_str("sum (* $0 $2) where (> $1 1)").unwrap());
+
+        let mut unknown = parse_query_from_str("sum prize").unwrap();
+        assert!(unknown.bind_with_spans(&schema, None).unwrap_err().message.starts_with("Unknown column \"prize\""));
+        let mut out_of_range = parse_query_from_str("sum $3").unwrap();
+        assert!(out_of_range.bind_with_spans(&schema, None).is_err());
+
+        let table = load_csv(Path::new("test.csv"), true, None).unwrap();
+        assert_eq!(table.schema().index_of("rand"), Ok(3));
+
+        // Floating point columns are rejected instead of being loaded as something else
+        assert!(Schema::new(vec![ColumnDef::new("x", DataType::F64)]).unwrap_err().contains("floating point columns aren't supported"));
+        let dir = std::env::temp_dir();
+        std::fs::write(dir.join("floats.csv"), "a,b\n1,1.5\n2,-3\n").unwrap();
+        let error = load_csv(&dir.join("floats.csv"), true, None).map(|_| ()).unwrap_err().to_string();
+        assert!(error.starts_with("Column \"b\" has type f64"), "{}", error);
+        std::fs::write(dir.join("floats.schema"), "a i64\nb f32\n").unwrap();
+        assert!(Schema::from_file(&dir.join("floats.schema")).is_err());
+        std::fs::write(dir.join("floats.schema"), "a i64\nb str\n").unwrap();
+        let table = load_csv(&dir.join("floats.csv"), true, Some(Schema::from_file(&dir.join("floats.schema")).unwrap())).unwrap();
+        assert_eq!(table.atom(0, 1), Atom::Str("1.5".into()));
+    }
+
+    #[test]
+    fn test_typed_columns() {
+        let schema = Schema::new(vec![
+            ColumnDef::new("small", DataType::I8),
+            ColumnDef::new("flag", DataType::Bool),
+            ColumnDef::new("medium", DataType::I32),
+            ColumnDef::new("unsigned", DataType::U16),
+            ColumnDef::new("big", DataType::I64),
+        ]).unwrap();
+        assert_eq!((schema.column_offset(2), schema.column_offset(4), schema.row_size()), (4, 16, 24));
+        let mut table = Table::new(schema);
+        for i in 0..20i64 {
+            table.push_row(&[
+                ConstValue::I8(i as i8 - 10),
+                ConstValue::Bool(i % 3 == 0),
+                ConstValue::I32(-70_000 * i as i32),
+                ConstValue::U16(60_000 + i as u16),
+                ConstValue::I64(i64::MAX - i),
+            ]);
+        }
+        for query_str in [
+            "sum (+ small medium unsigned)",
+            "(* small unsigned) where flag",
+            "count(*) where (& flag (< medium -100000))",
+            "max big where (= flag #f)",
+            "any flag where (> small 5)",
+        ] {
+            let mut query = parse_query_from_str(query_str).unwrap();
+            query.bind_with_spans(table.schema(), None).unwrap();
+            compare_layouts(&query, &table, query_str);
+        }
+
+        let mut bool_as_int = parse_query_from_str("sum flag").unwrap();
+        assert!(bool_as_int.bind_with_spans(table.schema(), None).is_err());
+    }
+
+    #[test]
+    fn test_type_errors() {
+        let schema = Schema::new(vec![
+            ColumnDef::new("price", DataType::I64),
+            ColumnDef::new("flag", DataType::Bool),
+        ]).unwrap();
+        let error = |query_str: &str| {
+            let (mut query, spans) = parse_query_with_spans(query_str).unwrap();
+            let error = query.bind_with_spans(&schema, Some(&spans)).unwrap_err();
+            (error.span.map(|s| query_str[s.start..s.end].to_string()), error.message)
+        };
+        assert_eq!(error("sum (+ price flag)"), (Some("flag".to_string()), "`+` expects i64 operands, found bool".to_string()));
+        assert_eq!(error("count(*) where (& flag (> price 1) price)").0.as_deref(), Some("price"));
+        assert_eq!(error("count(*) where (= flag (+ price 1))").0.as_deref(), Some("(+ price 1)"));
+        assert_eq!(error("sum (/ 10 (<))").0.as_deref(), Some("(<)"));
+        assert_eq!(error("sum (< 1 2 3)").0.as_deref(), Some("(< 1 2 3)"));
+        assert_eq!(error("sum price where (+  price 1)").0.as_deref(), Some("(+  price 1)"));
+        assert_eq!(error("all price").0.as_deref(), Some("price"));
+        assert_eq!(error("sum (* 2 \"prize\")").0.as_deref(), Some("\"prize\""));
+        assert_eq!(error("sum $2").0.as_deref(), Some("$2"));
+
+        let (mut query, spans) = parse_query_with_spans("sum (+ price flag)").unwrap();
+        let diagnostic = query.bind_with_spans(&schema, Some(&spans)).unwrap_err().diagnostic("sum (+ price flag)");
+        assert_eq!(diagnostic, "sum (+ price flag)\n             ^^^^ `+` expects i64 operands, found bool");
+
+        // Every subexpression has its own span
+        let (query, spans) = parse_query_with_spans("any (= (> $0 1) $1)").unwrap();
+        let typed = |expr: &Expr, spans| type_expr(expr, Some(spans), &schema).map(|typed| (typed.data_type, typed.span)).unwrap();
+        assert_eq!(typed(&query.expr, &spans.expr), (DataType::Bool, Some(Span { start: 4, end: 19 })));
+        let Expr::Application(_, args) = &query.expr else { unreachable!() };
+        assert_eq!(typed(&args[0], &spans.expr.args[0]), (DataType::Bool, Some(Span { start: 7, end: 15 })));
+        let Expr::Application(_, inner_args) = &args[0] else { unreachable!() };
+        assert_eq!(typed(&inner_args[0], &spans.expr.args[0].args[0]), (DataType::I64, Some(Span { start: 10, end: 12 })));
+        assert_eq!(typed(&args[1], &spans.expr.args[1]).1, Some(Span { start: 16, end: 18 }));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |query_str: &str| {
+            let error = parse_query_from_str(query_str).unwrap_err();
+            (error.position, error.message)
+        };
+        assert_eq!(error("sum (+ 1 2"), (10, "expected `)`, found end of input".to_string()));
+        assert_eq!(error("sum $0 foo"), (7, "expected `join`, `where`, `order by`, `limit` or the end of the query, found `foo`".to_string()));
+        assert_eq!(error("(foo 1)"), (1, "expected an operator, found `foo`".to_string()));
+        assert_eq!(error("(+ 1 -9223372036854775809)"), (5, "integer literal doesn't fit into i64".to_string()));
+        assert_eq!(error("(+ $x 1)"), (4, "expected a column number after `$`, found `x`".to_string()));
+        assert_eq!(error("sum $0 where"), (12, "expected a filter after `where`, found end of input".to_string()));
+        assert_eq!(error("  ").1, "expected an expression, found end of input");
+        assert_eq!(parse_query_from_str("-9223372036854775808").unwrap().expr, Expr::Constant(Atom::Num(i64::MIN)));
+
+        let diagnostic = parse_query_from_str("sum (+ 1 2").unwrap_err().diagnostic("sum (+ 1 2");
+        assert_eq!(diagnostic, "sum (+ 1 2\n          ^ expected `)`, found end of input");
+    }
+
+    #[test]
+    fn test_sql() {
+        let sql = |query_str: &str| parse_query(query_str, Syntax::Sql).unwrap().0;
+        let lisp = |query_str: &str| parse_query_from_str(query_str).unwrap();
+        assert_eq!(sql("SELECT sum(a + 2) FROM t WHERE b > 3"), lisp("sum (+ a 2) where (> b 3)"));
+        assert_eq!(sql("select a + b * c - d;"), lisp("(- (+ a (* b c)) d)"));
+        assert_eq!(sql("SELECT a - b - 3 % c"), lisp("(- a b (% 3 c))"));
+        assert_eq!(sql("SELECT (a - b) - -3"), lisp("(- (- a b) -3)"));
+        assert_eq!(sql("SELECT -a * 2"), lisp("(* (- 0 a) 2)"));
+        assert_eq!(sql("SELECT a | b & 3"), lisp("(| a (& b 3))"));
+        assert_eq!(sql("SELECT count(*) WHERE a & 3 = 1 AND NOT_A <> 2 OR $1 >= \"order id\""), lisp("count(*) where (| (& (= (& a 3) 1) (!= NOT_A 2)) (>= $1 \"order id\"))"));
+        assert_eq!(sql("SELECT COUNT(DISTINCT a % 10) FROM t"), lisp("count distinct (% a 10)"));
+        assert_eq!(sql("SELECT bool_and(a > 1 or false) FROM orders"), lisp("all (| (> a 1) #f)"));
+        assert_eq!(sql("SELECT count ( * )"), lisp("count(*)"));
+
+        let error = |query_str: &str| {
+            let error = parse_query(query_str, Syntax::Sql).unwrap_err();
+            (error.position, error.message)
+        };
+        assert_eq!(error("sum(a)"), (0, "expected `SELECT`, found `sum`".to_string()));
+        assert_eq!(error("SELECT sum(a"), (12, "expected `)`, found end of input".to_string()));
+        assert_eq!(error("SELECT a b"), (9, "expected `FROM`, `JOIN`, `WHERE`, `ORDER BY`, `LIMIT` or the end of the query, found `b`".to_string()));
+        assert_eq!(error("SELECT a < b < c").0, 13);
+        assert_eq!(error("SELECT sum(DISTINCT a)"), (11, "only count supports DISTINCT".to_string()));
+        assert_eq!(error("SELECT a WHERE"), (14, "expected an expression, found end of input".to_string()));
+
+        // Type errors point into the SQL query
+        let schema = Schema::new(vec![ColumnDef::new("a", DataType::I64), ColumnDef::new("flag", DataType::Bool)]).unwrap();
+        let query_str = "SELECT count(*) FROM t WHERE a * 2 + 1";
+        let (mut query, spans) = parse_query(query_str, Syntax::Sql).unwrap();
+        let span = query.bind_with_spans(&schema, Some(&spans)).unwrap_err().span.unwrap();
+        assert_eq!(&query_str[span.start..span.end], "a * 2 + 1");
+        let query_str = "SELECT sum(-flag)";
+        let (mut query, spans) = parse_query(query_str, Syntax::Sql).unwrap();
+        let span = query.bind_with_spans(&schema, Some(&spans)).unwrap_err().span.unwrap();
+        assert_eq!(&query_str[span.start..span.end], "flag");
+    }
+
+    #[test]
+    fn test_prepared_query() {
+        let query_str = "sum (* $1 :scale) where (& (> $0 ?2) (< $0 :max) (!= $1 ?1))";
+        let query = parse_query_from_str(query_str).unwrap();
+        // Positional parameters first, then named ones in the order they appear
+        assert_eq!(query.parameters(), vec!["1", "2", "scale", "max"]);
+        let (sql_query, _) = parse_query(&to_sql(&query), Syntax::Sql).unwrap();
+        assert_eq!(sql_query, query);
+        assert!(query.with_parameters(&[1, 2, 3]).is_err());
+
+        let data = (0..60).map(|v| (v * 7) % 13 - 6).collect::<Vec<i64>>();
+        let table = Table::from_i64(2, &data);
+        for layout in [Layout::RowMajor, Layout::Columnar] {
+            let table = table.to_layout(layout);
+            let options = CodeGenOptions { short_circuit: true, operand_order: OperandOrder::AsWritten };
+            let prepared = PreparedQuery::new(&query, table.schema(), layout, &options).unwrap();
+            assert!(prepared.execute(&table, &[1, 2], &mut OutputBuffer::new(1, &mut |_| {})).is_err());
+            // The code can only read tables like the one it was prepared for
+            let other_layout = table.to_layout(if layout == Layout::RowMajor { Layout::Columnar } else { Layout::RowMajor });
+            assert!(prepared.execute(&other_layout, &[0, -3, 2, 4], &mut OutputBuffer::new(1, &mut |_| {})).is_err());
+            assert!(prepared.execute(&Table::from_i64(3, &data[..30]), &[0, -3, 2, 4], &mut OutputBuffer::new(1, &mut |_| {})).is_err());
+            // The same code runs with different values
+            for values in [[0, -3, 2, 4], [5, 0, -1, 100], [-2, 6, 3, -6]] {
+                let mut results = vec![];
+                prepared.execute(&table, &values, &mut OutputBuffer::new(16, &mut |r| results.extend_from_slice(r))).unwrap();
+                assert_eq!(results, interpret(&query.with_parameters(&values).unwrap(), &table), "{:?} ({:?})", values, layout);
+            }
+        }
+    }
+
+    /// The data of the fuzz tests in a table with the columns of `query_gen::schema`
+    fn fuzz_table(columns: usize, data: &[i64]) -> Table {
+        let mut table = Table::new(query_gen::schema(columns));
+        for row in data.chunks_exact(columns) {
+            table.push_row(&row.iter().map(|v| ConstValue::I64(*v)).collect::<Vec<_>>());
+        }
+        table
+    }
+
+    proptest! {
+        /// Malformed queries never crash the parser or the type checker
+        #[test]
+        fn fuzz_parser(query in prop_oneof![
+            "[ ()$0-9a-z#<>=!&|+*/%\"-]{0,40}",
+            (query_gen::query(3), any::<bool>(), any::<prop::sample::Index>(), "[ ()$0-9a-z#<>=!&|+*/%\"-]{0,3}")
+                .prop_map(|(query, sql, index, garbage)| {
+                    let query = if sql { to_sql(&parse_query_from_str(&query).unwrap()) } else { query };
+                    let at = index.index(query.len() + 1);
+                    format!("{}{}{}", &query[..at], garbage, &query[at..])
+                }),
+        ]) {
+            for syntax in [Syntax::Lisp, Syntax::Sql] {
+                match parse_query(&query, syntax) {
+                    Ok((mut parsed, spans)) => {
+                        prop_assert!(spans.expr.span.end <= query.len());
+                        let _ = parsed.bind_with_spans(&query_gen::schema(3), Some(&spans)).map_err(|e| e.diagnostic(&query));
+                    },
+                    Err(e) => {
+                        prop_assert!(e.position <= query.len());
+                        e.diagnostic(&query);
+                    },
+                }
+            }
+        }
+
+        #[test]
+        fn fuzz_codegen_against_interpreter((query, columns, data) in query_gen::query_with_data()) {
+            compare_on_table(&query, &fuzz_table(columns, &data));
+        }
+
+        #[test]
+        fn fuzz_simplify((query, columns, data) in query_gen::query_with_data()) {
+            let mut query = parse_query_from_str(&query).unwrap();
+            query.bind_with_spans(&query_gen::schema(columns), None).unwrap();
+            let column_types = vec![DataType::I64; columns];
+            for row in data.chunks_exact(columns) {
+                let row = row.iter().map(|v| Atom::Num(*v)).collect::<Vec<_>>();
+                for expr in [Some(&query.expr), query.filter.as_ref()].into_iter().flatten() {
+                    let simplified = simplify(expr, &column_types, &[]).unwrap();
+                    prop_assert_eq!(eval_expression(&simplified, &row), eval_expression(expr, &row), "{:?}", simplified);
+                }
+            }
+        }
+    }
+
     const VERY_COMPLEX_EXPR_1: &str = include_str!("complex_expr.txt");
 
     #[test]
     fn test_codegen_very_complex_1() {
         let results = Results();
         let query = parse_query_from_str(VERY_COMPLEX_EXPR_1).unwrap();
-        let code = generate_code(&query, 1, results.consumer()).unwrap();
+        let code = generate_code(&query, &Schema::unnamed(1), Layout::RowMajor, results.consumer()).unwrap();
         let data = vec![0, 1, 5];
         code.call(&[data.as_ptr() as usize, data.len()]);
         let mut interp_result = vec![];
-        run_query(&query, &data, 1, |r| match r {
+        run_query(&query, &Table::from_i64(1, &data), |r| match r {
             Atom::Num(n) => interp_result.push(n),
             _ => unreachable!()
-        });
+        }).unwrap();
         let result = results.take();
         assert_eq!(result, interp_result);
     }
@@ -0,0 +1,76 @@
+// Morsel-driven parallel execution: the rows of the table are split into morsels that the threads of
+// a pool take one at a time, so threads that get faster morsels (e.g. ones where the filter drops most
+// rows) simply do more of them. Every thread aggregates into a state of its own, the states are merged
+// once all morsels are done. Results of queries without an aggregate are kept per morsel and passed on
+// in the order of the morsels, so the results are the same as for single-threaded execution.
+
+use std::{sync::{atomic::{AtomicUsize, Ordering}, Mutex}, thread};
+
+use crate::{codegen::{CodegenCFunctionSignature, GeneratedCode}, query::{AggregateFunc, AggregateState, Atom, Query}, query_codegen::{consume_result, generate_partial_code, CodeGenError, CodeGenOptions}, table::Table};
+
+/// Code generated by `generate_partial_code` doesn't have any state of its own, so it can be called
+/// from several threads at once as long as each of them uses its own stack
+struct SharedCode<'a>(&'a GeneratedCode);
+
+unsafe impl Sync for SharedCode<'_> {}
+
+/// Runs a bound query without parameters on `table` with `threads` threads, each taking `morsel_size`
+/// rows at a time. The results go to `result_consumer` on the calling thread.
+pub fn run_parallel(query: &Query, table: &Table, options: &CodeGenOptions, threads: usize, morsel_size: usize, result_consumer: CodegenCFunctionSignature) -> Result<(), CodeGenError> {
+    if let Some(name) = query.parameters().into_iter().next() {
+        return Err(CodeGenError::UnboundParameter(name));
+    }
+    let code = generate_partial_code(query, table.schema(), table.layout(), options)?;
+    let code = SharedCode(&code);
+
+    let morsel_size = morsel_size.max(1);
+    let morsels = table.rows().div_ceil(morsel_size);
+    let next_morsel = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+
+    let states = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.clamp(1, morsels.max(1))).map(|_| scope.spawn(|| {
+            let code = &code;
+            let mut stack = code.0.new_stack();
+            let mut state = AggregateState::new(query.aggregate);
+            loop {
+                let morsel = next_morsel.fetch_add(1, Ordering::Relaxed);
+                if morsel >= morsels {
+                    break state;
+                }
+                let start = morsel * morsel_size;
+                let mut args = table.call_args_for(start..(start + morsel_size).min(table.rows()));
+                if query.aggregate.is_none() {
+                    let mut morsel_results = Vec::<i64>::new();
+                    args.push(&mut morsel_results as *mut _ as usize);
+                    unsafe { code.0.call_on(&mut stack, &args) };
+                    results.lock().unwrap().push((morsel, morsel_results));
+                } else {
+                    args.push(match state.aggregate {
+                        Some(AggregateFunc::CountDistinct) => &mut state.distinct as *mut _ as usize,
+                        _ => state.values.as_mut_ptr() as usize,
+                    });
+                    unsafe { code.0.call_on(&mut stack, &args) };
+                }
+            }
+        })).collect();
+        workers.into_iter().map(|worker| worker.join().expect("Worker thread panicked")).collect::<Vec<_>>()
+    });
+
+    let mut consume = |result: Atom| consume_result(result_consumer, result);
+    let mut results = results.into_inner().unwrap();
+    results.sort_unstable_by_key(|(morsel, _)| *morsel);
+    for (_, morsel_results) in results {
+        for result in morsel_results {
+            consume(Atom::Num(result));
+        }
+    }
+
+    let mut states = states.into_iter();
+    let mut state = states.next().unwrap();
+    for other in states {
+        state.merge(other);
+    }
+    state.finish(&mut consume);
+    Ok(())
+}
@@ -4,26 +4,27 @@
 //! parser and tiny [lisp](https://en.wikipedia.org/wiki/Lisp_(programming_language)) interpreter.
 //! Lisp is a simple type of language made up of Atoms and Lists, forming easily parsable trees.
 
-use std::fmt::{self, Display, Formatter};
+use std::{cmp::Ordering, collections::{BTreeSet, BinaryHeap, HashMap, HashSet}, fmt::{self, Display, Formatter}, mem, ops::{ControlFlow, Range}, sync::Arc};
 
 use nom::{
   branch::alt,
   bytes::complete::{tag, tag_no_case},
-  character::complete::{char, digit1, multispace0, multispace1},
-  combinator::{cut, map, map_res, opt},
-  error::{context, VerboseError},
-  multi::many0,
-  sequence::{delimited, preceded, terminated, tuple},
+  bytes::complete::take_while,
+  character::complete::{alpha1, alphanumeric1, char, digit1, multispace0, multispace1},
+  combinator::{cut, map, map_res, opt, peek, recognize, value, verify},
+  error::{context, VerboseError, VerboseErrorKind},
+  multi::{many0, many0_count},
+  sequence::{delimited, pair, preceded, terminated, tuple},
   IResult, Parser,
 };
 
-use crate::{codegen::ir::DataType, query_codegen::get_type};
+use crate::{codegen::ir::DataType, schema::Schema, sql::{keyword, parse_sql_query}, table::Table, typecheck::{can_be_null, type_expr, TypeError}};
 
 /// We start by defining the types that define the shape of data that we want.
 /// In this case, we want something tree-like
 
 /// Starting from the most basic, we define some built-in functions that our lisp has
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum BuiltIn {
   Plus,
   Minus,
@@ -38,16 +39,61 @@ pub enum BuiltIn {
   GreaterThanOrEqual,
   And,
   Or,
+  /// Whether the first operand is equal to any of the others
+  In,
+  /// Whether the first operand is within the range given by the second and the third one (inclusive)
+  Between,
+  /// Whether the operand is NULL
+  IsNull,
+  /// The first operand that isn't NULL
+  Coalesce,
+  /// Whether the first string starts with the second one
+  StartsWith,
+  /// Whether the first string matches the pattern given by the second one (see `like`)
+  Like,
+  /// The length of a string in bytes
+  Length,
   /*Not,*/
 }
 
+impl BuiltIn {
+  /// How the operator is written in a query
+  pub fn symbol(&self) -> &'static str {
+    match self {
+      BuiltIn::Plus => "+",
+      BuiltIn::Minus => "-",
+      BuiltIn::Times => "*",
+      BuiltIn::Divide => "/",
+      BuiltIn::Rem => "%",
+      BuiltIn::Equal => "=",
+      BuiltIn::NotEqual => "!=",
+      BuiltIn::LessThan => "<",
+      BuiltIn::GreaterThan => ">",
+      BuiltIn::LessThanOrEqual => "<=",
+      BuiltIn::GreaterThanOrEqual => ">=",
+      BuiltIn::And => "&",
+      BuiltIn::Or => "|",
+      BuiltIn::In => "in",
+      BuiltIn::Between => "between",
+      BuiltIn::IsNull => "is-null",
+      BuiltIn::Coalesce => "coalesce",
+      BuiltIn::StartsWith => "starts-with",
+      BuiltIn::Like => "like",
+      BuiltIn::Length => "length",
+    }
+  }
+}
+
 /// We now wrap this type and a few other primitives into our Atom type.
 /// Remember from before that Atoms form one half of our language.
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Atom {
   Num(i64),
   Boolean(bool),
+  Str(Arc<str>),
+  /// A missing value. Queries can't contain it as a constant, it only comes from nullable columns.
+  Null,
 }
 
 impl Atom {
@@ -71,6 +117,8 @@ impl Display for Atom {
     match self {
       Atom::Num(n) => write!(f, "{}", n),
       Atom::Boolean(b) => write!(f, "{}", b),
+      Atom::Str(s) => write!(f, "'{}'", s.replace('\'', "''")),
+      Atom::Null => write!(f, "NULL"),
     }
   }
 }
@@ -85,10 +133,18 @@ impl Display for Atom {
 /// structure that we can deal with programmatically. Thus any valid expression
 /// is also a valid data structure in Lisp itself.
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Expr {
   Constant(Atom),
   Variable(usize),
+  /// A column referenced by name. Replaced by a `Variable` in `Query::bind_with_spans`
+  Column(String),
+  /// A parameter whose value is only given when the query runs, `?1` (as "1") or `:name` (as "name").
+  /// Parameters are always integers.
+  Parameter(String),
+  /// A column of one of the tables of a join, `l.price` or `r.$2`: a `Variable` (the position in the table
+  /// of that side) or a `Column`. Replaced by a `Variable` in `Query::bind_join`
+  Joined(Side, Box<Expr>),
   /// (func-name arg1 arg2)
   Application(BuiltIn, Vec<Expr>),
   /*/// (if predicate do-this)
@@ -99,22 +155,420 @@ pub enum Expr {
   Quote(Vec<Expr>),*/
 }
 
+/// The tables of a join: `l` is the table the query runs on, `r` the table it is joined with
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Side {
+  Left,
+  Right,
+}
+
+impl Side {
+  /// How columns of this side are qualified
+  pub fn prefix(&self) -> &'static str {
+    match self {
+      Side::Left => "l.",
+      Side::Right => "r.",
+    }
+  }
+
+  /// How a column of this side is written, e.g. `r.$2` or `l.price`
+  pub fn qualify(&self, column: &Expr) -> String {
+    match column {
+      Expr::Variable(i) => format!("{}${}", self.prefix(), i),
+      Expr::Column(name) => format!("{}{}", self.prefix(), name),
+      _ => unreachable!("Only columns can be qualified"),
+    }
+  }
+}
 
+/// A range of bytes in the query string
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Span {
+  pub start: usize,
+  pub end: usize,
+}
+
+impl Span {
+  /// The message below `src`, with carets marking the span:
+  /// ```text
+  /// sum (+ $0 #t)
+  ///           ^^ `+` expects i64 operands, found bool
+  /// ```
+  pub fn diagnostic(&self, src: &str, message: &str) -> String {
+    if self.end > src.len() {
+      return message.to_string();
+    }
+    // Count characters instead of bytes so that quoted column names with non-ASCII
+    // characters don't shift the carets
+    let indent = src[..self.start].chars().count();
+    let width = src[self.start..self.end].chars().count().max(1);
+    format!("{}\n{}{} {}", src, " ".repeat(indent), "^".repeat(width), message)
+  }
+}
+
+/// Where an expression and (recursively) its operands are in the query string.
+/// Has the same shape as the `Expr` it was parsed together with.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ExprSpans {
+  pub span: Span,
+  pub args: Vec<ExprSpans>,
+}
+
+impl ExprSpans {
+  /// The parser only sees the remaining input, so it measures spans from the end of the input.
+  /// This turns them into offsets from the start of an input that is `len` bytes long.
+  pub fn into_offsets(self, len: usize) -> ExprSpans {
+    ExprSpans {
+      span: Span { start: len - self.span.start, end: len - self.span.end },
+      args: self.args.into_iter().map(|arg| arg.into_offsets(len)).collect(),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct QuerySpans {
+  pub expr: ExprSpans,
+  /// The join condition, `(= <left key> <right key>)`
+  pub join: Option<ExprSpans>,
+  pub filter: Option<ExprSpans>,
+  pub order_by: Option<ExprSpans>,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
 pub enum AggregateFunc {
   Sum,
   Prod,
   Avg,
   Max,
-  Min
-  // TODO: Add more aggregate functions here
+  Min,
+  /// COUNT expr, which unlike COUNT(*) skips NULLs
+  Count,
+  /// COUNT(*)
+  CountStar,
+  CountDistinct,
+  /// ANY/BOOL_OR on a boolean expression
+  Any,
+  /// ALL/BOOL_AND on a boolean expression
+  All,
+  VarPop,
+  VarSamp,
+  StddevPop,
+  StddevSamp,
+}
+
+impl AggregateFunc {
+  /// The type the aggregated expression must have. `None` means any type is accepted.
+  pub fn input_type(&self) -> Option<DataType> {
+    match self {
+      AggregateFunc::Count | AggregateFunc::CountStar => None,
+      AggregateFunc::Any | AggregateFunc::All => Some(DataType::Bool),
+      _ => Some(DataType::I64),
+    }
+  }
+
+  /// The name of the aggregate as a function, e.g. `sum` in `SELECT sum(a)` (see `sql::to_sql`)
+  #[cfg(test)]
+  pub fn name(&self) -> &'static str {
+    match self {
+      AggregateFunc::Sum => "sum",
+      AggregateFunc::Prod => "prod",
+      AggregateFunc::Avg => "avg",
+      AggregateFunc::Max => "max",
+      AggregateFunc::Min => "min",
+      AggregateFunc::Count | AggregateFunc::CountStar | AggregateFunc::CountDistinct => "count",
+      AggregateFunc::Any => "bool_or",
+      AggregateFunc::All => "bool_and",
+      AggregateFunc::VarPop => "var_pop",
+      AggregateFunc::VarSamp => "var_samp",
+      AggregateFunc::StddevPop => "stddev_pop",
+      AggregateFunc::StddevSamp => "stddev_samp",
+    }
+  }
+
+  /// Looks up an aggregate by its function name (case insensitive). `count` is `Count`,
+  /// `COUNT(*)` and `COUNT(DISTINCT ...)` have to be told apart by the caller.
+  pub fn from_name(name: &str) -> Option<AggregateFunc> {
+    Some(match name.to_ascii_lowercase().as_str() {
+      "sum" => AggregateFunc::Sum,
+      "prod" => AggregateFunc::Prod,
+      "avg" => AggregateFunc::Avg,
+      "max" => AggregateFunc::Max,
+      "min" => AggregateFunc::Min,
+      "count" => AggregateFunc::Count,
+      "any" | "bool_or" => AggregateFunc::Any,
+      "all" | "bool_and" => AggregateFunc::All,
+      "var_pop" => AggregateFunc::VarPop,
+      "var_samp" | "variance" => AggregateFunc::VarSamp,
+      "stddev_pop" => AggregateFunc::StddevPop,
+      "stddev_samp" | "stddev" => AggregateFunc::StddevSamp,
+      _ => return None,
+    })
+  }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct Query {
   pub aggregate: Option<AggregateFunc>,
   pub filter: Option<Expr>, // Must be a boolean expression
-  pub expr: Expr // Must be an integer expression
+  pub expr: Expr, // Must be an integer expression (or whatever AggregateFunc::input_type says)
+  pub join: Option<Join>,
+  /// Only for queries without an aggregate, like `limit`
+  pub order_by: Option<OrderBy>,
+  /// Only the first `limit` results (after sorting them)
+  pub limit: Option<usize>,
+}
+
+/// `order by <expr> [asc|desc]`: results are sorted by the value `expr` has for their row.
+/// Results with the same value stay in the order of their rows.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct OrderBy {
+  pub expr: Expr, // Must be an integer or a boolean expression
+  pub descending: bool,
+}
+
+/// `join on (= <left key> <right key>)`: every row of the table the query runs on is combined with
+/// each row of the right table that has the same key. In the combined row the columns of the right
+/// table come after the ones of the left table.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct Join {
+  /// Only refers to columns of the left table. Must be an integer expression, like the right key.
+  pub left_key: Expr,
+  /// Only refers to columns of the right table
+  pub right_key: Expr,
+}
+
+impl Expr {
+  /// Replaces all named columns by their index in the schema. With a `right` schema the index is the
+  /// one in the combined row of a join, unqualified names may be columns of either table.
+  fn resolve_columns(&mut self, schema: &Schema, right: Option<&Schema>, spans: Option<&ExprSpans>) -> Result<(), TypeError> {
+    let span = spans.map(|s| s.span);
+    match self {
+      Expr::Constant(_) | Expr::Variable(_) | Expr::Parameter(_) => Ok(()),
+      Expr::Column(name) => {
+        let index = match right {
+          None => schema.index_of(name),
+          Some(right) => match (schema.index_of(name), right.index_of(name)) {
+            (Ok(_), Ok(_)) => Err(format!("Column name \"{}\" is ambiguous, use l.{} or r.{}", name, name, name)),
+            (Ok(i), Err(_)) => Ok(i),
+            (Err(_), Ok(i)) => Ok(schema.column_count() + i),
+            (Err(e), Err(_)) => Err(e),
+          },
+        }.map_err(|e| TypeError::new(e, span))?;
+        *self = Expr::Variable(index);
+        Ok(())
+      },
+      Expr::Joined(side, column) => {
+        let Some(right) = right else {
+          return Err(TypeError::new(format!("`{}` can only be used in queries with a join", side.prefix()), span));
+        };
+        let (table, offset) = match side {
+          Side::Left => (schema, 0),
+          Side::Right => (right, schema.column_count()),
+        };
+        let index = match column.as_ref() {
+          Expr::Variable(i) if *i < table.column_count() => Ok(*i),
+          Expr::Variable(i) => Err(format!("Column {}${} doesn't exist, the table only has {} columns", side.prefix(), i, table.column_count())),
+          Expr::Column(name) => table.index_of(name),
+          _ => unreachable!("Only columns can be qualified"),
+        }.map_err(|e| TypeError::new(e, span))?;
+        *self = Expr::Variable(offset + index);
+        Ok(())
+      },
+      Expr::Application(_, args) => args.iter_mut().enumerate().try_for_each(|(i, arg)| {
+        arg.resolve_columns(schema, right, spans.and_then(|s| s.args.get(i)))
+      }),
+    }
+  }
+
+  /// All columns the expression refers to, in ascending order
+  pub fn referenced_columns(&self) -> BTreeSet<usize> {
+    let mut columns = BTreeSet::new();
+    self.collect_variables(&mut columns);
+    columns
+  }
+
+  fn collect_variables(&self, variables: &mut BTreeSet<usize>) {
+    match self {
+      Expr::Constant(_) | Expr::Column(_) | Expr::Joined(..) | Expr::Parameter(_) => {},
+      Expr::Variable(i) => {
+        variables.insert(*i);
+      },
+      Expr::Application(_, args) => args.iter().for_each(|arg| arg.collect_variables(variables)),
+    }
+  }
+
+  fn collect_parameters<'a>(&'a self, parameters: &mut Vec<&'a str>) {
+    match self {
+      Expr::Parameter(name) if !parameters.contains(&name.as_str()) => parameters.push(name),
+      Expr::Application(_, args) => args.iter().for_each(|arg| arg.collect_parameters(parameters)),
+      _ => {},
+    }
+  }
+
+  /// A copy of the expression with every parameter replaced by `replacement(name)`
+  pub fn replace_parameters(&self, replacement: &impl Fn(&str) -> Expr) -> Expr {
+    match self {
+      Expr::Parameter(name) => replacement(name),
+      Expr::Application(fun, args) => Expr::Application(*fun, args.iter().map(|arg| arg.replace_parameters(replacement)).collect()),
+      _ => self.clone(),
+    }
+  }
+}
+
+impl Query {
+  /// Resolves column names against the schema and checks that the query is well typed. With `spans`
+  /// errors point to where they are in the query string.
+  pub fn bind_with_spans(&mut self, schema: &Schema, spans: Option<&QuerySpans>) -> Result<(), TypeError> {
+    self.bind_tables(schema, None, spans)
+  }
+
+  /// Like `bind_with_spans` for a query that joins the table with `schema` (`l`) with the one with
+  /// `right` (`r`). Columns are numbered like in the combined row, see `Schema::joined`.
+  pub fn bind_join(&mut self, schema: &Schema, right: &Schema, spans: Option<&QuerySpans>) -> Result<(), TypeError> {
+    self.bind_tables(schema, Some(right), spans)
+  }
+
+  fn bind_tables(&mut self, schema: &Schema, right: Option<&Schema>, spans: Option<&QuerySpans>) -> Result<(), TypeError> {
+    let join_spans = spans.and_then(|s| s.join.as_ref());
+    match (&mut self.join, right) {
+      (Some(_), None) => return Err(TypeError::new("The query joins a second table, but there is none", join_spans.map(|s| s.span))),
+      (Some(join), Some(right)) => {
+        join.left_key.resolve_columns(schema, Some(right), join_spans.and_then(|s| s.args.first()))?;
+        join.right_key.resolve_columns(schema, Some(right), join_spans.and_then(|s| s.args.get(1)))?;
+        let left_columns = schema.column_count();
+        let on_left = |key: &Expr| key.referenced_columns().iter().all(|&c| c < left_columns);
+        let on_right = |key: &Expr| key.referenced_columns().iter().all(|&c| c >= left_columns);
+        if !on_left(&join.left_key) || !on_right(&join.right_key) {
+          // `(= r.a l.b)` is the same join as `(= l.b r.a)`
+          if !on_right(&join.left_key) || !on_left(&join.right_key) {
+            return Err(TypeError::new("A join must compare an expression of columns of `l` with one of columns of `r`", join_spans.map(|s| s.span)));
+          }
+          mem::swap(&mut join.left_key, &mut join.right_key);
+        }
+      },
+      (None, _) => {},
+    }
+    self.expr.resolve_columns(schema, right, spans.map(|s| &s.expr))?;
+    if let Some(filter) = &mut self.filter {
+      filter.resolve_columns(schema, right, spans.and_then(|s| s.filter.as_ref()))?;
+    }
+    if let Some(order_by) = &mut self.order_by {
+      order_by.expr.resolve_columns(schema, right, spans.and_then(|s| s.order_by.as_ref()))?;
+    }
+    match right {
+      Some(right) => self.check_types(&schema.joined(right), spans),
+      None => self.check_types(schema, spans),
+    }
+  }
+
+  /// All columns the expression, the join keys, the filter or the order refer to, in ascending order
+  pub fn referenced_columns(&self) -> BTreeSet<usize> {
+    let mut columns = self.expr.referenced_columns();
+    if let Some(join) = &self.join {
+      join.left_key.collect_variables(&mut columns);
+      join.right_key.collect_variables(&mut columns);
+    }
+    if let Some(filter) = &self.filter {
+      filter.collect_variables(&mut columns);
+    }
+    if let Some(order_by) = &self.order_by {
+      order_by.expr.collect_variables(&mut columns);
+    }
+    columns
+  }
+
+  /// The names of the parameters in the order their values are given: `?1` up to the highest
+  /// numbered parameter (as "1", "2", ...), then the named parameters in the order they appear
+  pub fn parameters(&self) -> Vec<String> {
+    let mut names = Vec::new();
+    self.expr.collect_parameters(&mut names);
+    if let Some(join) = &self.join {
+      join.left_key.collect_parameters(&mut names);
+      join.right_key.collect_parameters(&mut names);
+    }
+    if let Some(filter) = &self.filter {
+      filter.collect_parameters(&mut names);
+    }
+    if let Some(order_by) = &self.order_by {
+      order_by.expr.collect_parameters(&mut names);
+    }
+    let positional = names.iter().filter_map(|name| name.parse::<usize>().ok()).max().unwrap_or(0);
+    (1..=positional).map(|n| n.to_string())
+      .chain(names.into_iter().filter(|name| name.parse::<usize>().is_err()).map(String::from))
+      .collect()
+  }
+
+  /// The query with every parameter replaced by its value (in the order of `parameters`). The tests
+  /// run it with the interpreter to check the results of prepared queries.
+  #[cfg(test)]
+  pub fn with_parameters(&self, values: &[i64]) -> Result<Query, String> {
+    let parameters = self.parameters();
+    if values.len() != parameters.len() {
+      return Err(format!("The query has {} parameters but {} values were given", parameters.len(), values.len()));
+    }
+    let values = parameters.iter().map(String::as_str).zip(values.iter().copied()).collect::<HashMap<_, _>>();
+    let replacement = |name: &str| Expr::Constant(Atom::Num(values[name]));
+    Ok(Query {
+      aggregate: self.aggregate,
+      filter: self.filter.as_ref().map(|filter| filter.replace_parameters(&replacement)),
+      expr: self.expr.replace_parameters(&replacement),
+      join: self.join.as_ref().map(|join| Join { left_key: join.left_key.replace_parameters(&replacement), right_key: join.right_key.replace_parameters(&replacement) }),
+      order_by: self.order_by.as_ref().map(|order_by| OrderBy { expr: order_by.expr.replace_parameters(&replacement), descending: order_by.descending }),
+      limit: self.limit,
+    })
+  }
+
+  /// The type of every column depends on the schema so we can only do this after parsing.
+  /// For queries with a join `schema` is the schema of the combined row.
+  pub fn check_types(&self, schema: &Schema, spans: Option<&QuerySpans>) -> Result<(), TypeError> {
+    if let Some(join) = &self.join {
+      // The keys may have been swapped while binding, so errors point to the whole condition
+      let span = spans.and_then(|s| s.join.as_ref()).map(|s| s.span);
+      for key in [&join.left_key, &join.right_key] {
+        let key = type_expr(key, None, schema).map_err(|e| TypeError::new(e.message, span))?;
+        if key.data_type != DataType::I64 {
+          return Err(TypeError::new(format!("Join keys must be integer expressions, found {}", key.data_type), span));
+        }
+      }
+    }
+    let expr = type_expr(&self.expr, spans.map(|s| &s.expr), schema)?;
+    let expected_type = match self.aggregate {
+      Some(aggregate) => aggregate.input_type(),
+      None => Some(DataType::I64),
+    };
+    match expected_type {
+      Some(DataType::I64) if expr.data_type != DataType::I64 => {
+        return Err(TypeError::new(format!("Expression must be an integer expression, found {}", expr.data_type), expr.span));
+      },
+      Some(DataType::Bool) if expr.data_type != DataType::Bool => {
+        return Err(TypeError::new(format!("Expression must be a boolean expression, found {}", expr.data_type), expr.span));
+      },
+      _ => {}
+    }
+    if let Some(filter) = &self.filter {
+      let filter = type_expr(filter, spans.and_then(|s| s.filter.as_ref()), schema)?;
+      if filter.data_type != DataType::Bool {
+        return Err(TypeError::new(format!("Filter must be a boolean expression, found {}", filter.data_type), filter.span));
+      }
+    }
+    if let Some(order_by) = &self.order_by {
+      let nullable = can_be_null(&order_by.expr, &schema.nullable_columns());
+      let order_by = type_expr(&order_by.expr, spans.and_then(|s| s.order_by.as_ref()), schema)?;
+      if self.aggregate.is_some() {
+        return Err(TypeError::new("Queries with an aggregate have a single result, they can't be ordered", order_by.span));
+      }
+      if order_by.data_type != DataType::I64 && order_by.data_type != DataType::Bool {
+        return Err(TypeError::new(format!("Results can only be ordered by integer or boolean expressions, found {}", order_by.data_type), order_by.span));
+      }
+      if nullable {
+        return Err(TypeError::new("Results can't be ordered by an expression that can be NULL, use `coalesce` to replace NULLs", order_by.span));
+      }
+    }
+    if self.aggregate.is_some() && self.limit.is_some() {
+      return Err(TypeError::new("Queries with an aggregate have a single result, they can't be limited", None));
+    }
+    Ok(())
+  }
 }
 
 /// Continuing the trend of starting from the simplest piece and building up,
@@ -128,14 +582,22 @@ fn parse_builtin_op<'a>(i: &'a str) -> IResult<&'a str, BuiltIn, VerboseError<&'
     tag("%"),
     tag("="),
     tag("!="),
-    tag("<"),
-    tag(">"),
+    // Has to come before "<" and ">" since alt takes the first match
     tag("<="),
     tag(">="),
+    tag("<"),
+    tag(">"),
     tag("&"),
     tag("|"),
+    tag("in"),
+    tag("between"),
+    tag("is-null"),
+    tag("coalesce"),
+    tag("starts-with"),
+    tag("like"),
+    tag("length"),
     //tag("not"),
-  )), multispace1)(i)?;
+  )), alt((multispace1, peek(tag(")")))))(i)?;
 
   // because we are matching single character tokens, we can do the matching logic
   // on the returned value
@@ -155,6 +617,13 @@ fn parse_builtin_op<'a>(i: &'a str) -> IResult<&'a str, BuiltIn, VerboseError<&'
       ">=" => BuiltIn::GreaterThanOrEqual,
       "&" => BuiltIn::And,
       "|" => BuiltIn::Or,
+      "in" => BuiltIn::In,
+      "between" => BuiltIn::Between,
+      "is-null" => BuiltIn::IsNull,
+      "coalesce" => BuiltIn::Coalesce,
+      "starts-with" => BuiltIn::StartsWith,
+      "like" => BuiltIn::Like,
+      "length" => BuiltIn::Length,
       //"not" => BuiltIn::Not,
       _ => unreachable!(),
     },
@@ -193,18 +662,39 @@ fn parse_bool<'a>(i: &'a str) -> IResult<&'a str, Atom, VerboseError<&'a str>> {
   )(i)
 }*/
 
+/// Fails without back-tracking, with `message` as the error at `i`
+pub fn failure<'a, O>(i: &'a str, message: &'static str) -> IResult<&'a str, O, VerboseError<&'a str>> {
+  Err(nom::Err::Failure(VerboseError { errors: vec![(i, VerboseErrorKind::Context(message))] }))
+}
+
 /// Next up is number parsing. We're keeping it simple here by accepting any number (> 1)
-/// of digits but ending the program if it doesn't fit into an i32.
-fn parse_num(i: &str) -> IResult<&str, Atom, VerboseError<&str>> {
-  alt((
-    map_res(digit1, |digit_str: &str| {
-      digit_str.parse::<i64>().map(Atom::Num)
-    }),
-    map(preceded(tag("-"), digit1), |digit_str: &str| {
-      Atom::Num(-digit_str.parse::<i64>().unwrap())
-    }),
-  ))
-  .parse(i)
+/// of digits, optionally negative. Numbers that don't fit into an i64 are an error.
+pub fn parse_num(i: &str) -> IResult<&str, Atom, VerboseError<&str>> {
+  let (rest, digit_str) = recognize(pair(opt(tag("-")), digit1))(i)?;
+  match digit_str.parse::<i64>() {
+    Ok(n) => Ok((rest, Atom::Num(n))),
+    Err(_) => failure(i, "integer literal doesn't fit into i64"),
+  }
+}
+
+/// Strings are written in single quotes, a quote inside of the string is written twice: `'it''s'`
+pub fn parse_string(i: &str) -> IResult<&str, Atom, VerboseError<&str>> {
+  let (mut rest, _) = char('\'')(i)?;
+  let mut string = String::new();
+  loop {
+    let Some(end) = rest.find('\'') else {
+      return failure(i, "string literal is missing its closing `'`");
+    };
+    string.push_str(&rest[..end]);
+    rest = &rest[end + 1..];
+    match rest.strip_prefix('\'') {
+      Some(after_quote) => {
+        string.push('\'');
+        rest = after_quote;
+      },
+      None => return Ok((rest, Atom::Str(string.into()))),
+    }
+  }
 }
 
 /// Now we take all these simple parsers and connect them.
@@ -213,6 +703,7 @@ fn parse_atom<'a>(i: &'a str) -> IResult<&'a str, Atom, VerboseError<&'a str>> {
   alt((
     parse_num,
     parse_bool,
+    parse_string,
     //map(parse_builtin, Atom::BuiltIn),
     //parse_keyword,
   ))(i)
@@ -224,10 +715,46 @@ fn parse_constant<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str
 }
 
 /// We add variables in the format $n where n is a number
-fn parse_variable<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
-  map_res(preceded(tag("$"), digit1), |digit_str: &str| {
-    digit_str.parse::<usize>().map(Expr::Variable)
-  })(i)
+pub fn parse_variable<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
+  let (rest, digit_str) = preceded(tag("$"), context("expected a column number after `$`", cut(digit1)))(i)?;
+  match digit_str.parse::<usize>() {
+    Ok(n) => Ok((rest, Expr::Variable(n))),
+    Err(_) => failure(i, "column number is too large"),
+  }
+}
+
+/// Columns can also be referenced by name, either as a plain identifier (`price`) or
+/// in double quotes if the name contains other characters (`"order id"`)
+fn parse_column<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
+  let identifier = verify(
+    identifier,
+    // Otherwise `sum where (...)` would aggregate a column called "where"
+    |name: &str| !name.eq_ignore_ascii_case("where"),
+  );
+  let quoted = delimited(char('"'), take_while(|c| c != '"'), char('"'));
+  map(alt((identifier, quoted)), |name: &str| Expr::Column(name.to_string()))(i)
+}
+
+/// A column of one side of a join, `l.` or `r.` followed by the position or the name of the column.
+/// Both syntaxes share it, only names are parsed with `parse_column` of the syntax.
+pub fn parse_joined_column<'a>(i: &'a str, parse_column: impl Parser<&'a str, Expr, VerboseError<&'a str>>) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
+  let side = alt((value(Side::Left, tag("l.")), value(Side::Right, tag("r."))));
+  let column = context("expected a column after `l.` or `r.`", cut(alt((parse_variable, parse_column))));
+  map(pair(side, column), |(side, column)| Expr::Joined(side, Box::new(column)))(i)
+}
+
+/// Letters, digits and underscores, not starting with a digit
+pub fn identifier<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
+  recognize(pair(alt((alpha1, tag("_"))), many0_count(alt((alphanumeric1, tag("_"))))))(i)
+}
+
+/// Parameters are numbered from 1 (`?1`, `?2`, ...) or named (`:name`)
+pub fn parse_parameter<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
+  let number = verify(digit1, |n: &str| n.parse::<u16>().is_ok_and(|n| n > 0));
+  map(alt((
+    preceded(tag("?"), context("expected a parameter number between 1 and 65535 after `?`", cut(number))),
+    preceded(tag(":"), context("expected a parameter name after `:`", cut(identifier))),
+  )), |name: &str| Expr::Parameter(name.to_string()))(i)
 }
 
 /// Before continuing, we need a helper function to parse lists.
@@ -244,7 +771,7 @@ where
   delimited(
     char('('),
     preceded(multispace0, inner),
-    context("closing paren", cut(preceded(multispace0, char(')')))),
+    context("expected `)`", cut(preceded(multispace0, char(')')))),
   )
 }
 
@@ -257,9 +784,11 @@ where
 ///
 /// `tuple` is used to sequence parsers together, so we can translate this directly
 /// and then map over it to transform the output into an `Expr::Application`
-fn parse_application<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
-  let application_inner = map(tuple((parse_builtin, many0(parse_expr))), |(head, tail)| {
-    Expr::Application(head, tail)
+fn parse_application<'a>(i: &'a str) -> IResult<&'a str, (Expr, Vec<ExprSpans>), VerboseError<&'a str>> {
+  // Once we have seen a `(` it has to be an application
+  let application_inner = map(tuple((context("expected an operator", cut(parse_builtin)), many0(parse_expr))), |(head, tail)| {
+    let (args, spans) = tail.into_iter().unzip();
+    (Expr::Application(head, args), spans)
   });
   // finally, we wrap it in an s-expression
   s_exp(application_inner)(i)
@@ -314,12 +843,21 @@ fn parse_application<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a
 }*/
 
 /// We tie them all together again, making a top-level expression parser!
-
-fn parse_expr<'a>(i: &'a str) -> IResult<&'a str, Expr, VerboseError<&'a str>> {
-  preceded(
-    multispace0,
-    alt((parse_constant, parse_variable, parse_application/*, parse_if, parse_quote*/)),
-  )(i)
+/// Together with the expression we return where it is in the input (see `ExprSpans::into_offsets`)
+fn parse_expr<'a>(i: &'a str) -> IResult<&'a str, (Expr, ExprSpans), VerboseError<&'a str>> {
+  fn leaf(expr: Expr) -> (Expr, Vec<ExprSpans>) {
+    (expr, Vec::new())
+  }
+  let (i, _) = multispace0(i)?;
+  let (rest, (expr, args)) = context("expected an expression", alt((
+    map(parse_constant, leaf),
+    map(parse_variable, leaf),
+    map(|i| parse_joined_column(i, parse_column), leaf),
+    map(parse_column, leaf),
+    map(parse_parameter, leaf),
+    parse_application/*, parse_if, parse_quote*/
+  )))(i)?;
+  Ok((rest, (expr, ExprSpans { span: Span { start: i.len(), end: rest.len() }, args })))
 }
 
 
@@ -352,11 +890,19 @@ fn get_num_from_expr(e: Expr, vars: &[&i64]) -> Option<i64> {
 /// This function tries to reduce the AST.
 /// This has to return an Expression rather than an Atom because quoted s_expressions
 /// can't be reduced
-pub fn eval_expression(e: &Expr, vars: &[i64]) -> Option<Atom> {
+///
+/// Arithmetic wraps around like in the generated code. A division or remainder by zero and
+/// `i64::MIN / -1` (or `% -1`) are errors instead, for these the generated code traps (the
+/// `idiv` raises SIGFPE), so it can only be compared with the interpreter on queries that don't fail.
+pub fn eval_expression(e: &Expr, vars: &[Atom]) -> Result<Atom, EvalError> {
   match e {
     // Constants and quoted s-expressions are our base-case
-    Expr::Constant(c) /*| Expr::Quote(_)*/ => Some(c.clone()),
-    Expr::Variable(i) => Some(Atom::Num(vars[*i])),
+    Expr::Constant(c) /*| Expr::Quote(_)*/ => Ok(c.clone()),
+    Expr::Variable(i) => Ok(vars[*i].clone()),
+    // Has to be resolved to a variable first
+    Expr::Column(_) | Expr::Joined(..) => Err(EvalError::Invalid),
+    // Has to be replaced by a value first (see `Query::with_parameters`)
+    Expr::Parameter(_) => Err(EvalError::Invalid),
     // we then recursively `eval_expression` in the context of our special forms
     // and built-in operators
     /*Expr::If(pred, true_branch) => {
@@ -375,57 +921,119 @@ pub fn eval_expression(e: &Expr, vars: &[i64]) -> Option<Atom> {
         eval_expression(*false_branch)
       }
     }*/
+    Expr::Application(op @ (BuiltIn::And | BuiltIn::Or), tail) => {
+      let decisive = *op == BuiltIn::Or;
+      let mut bits = None;
+      let mut unknown = false;
+      for expr in tail {
+        match eval_expression(expr, vars)? {
+          // Booleans short circuit just like the generated code does. A NULL doesn't decide the
+          // result (three-valued logic), `(& #f NULL)` is still false.
+          Atom::Boolean(b) if b == decisive => return Ok(Atom::Boolean(decisive)),
+          Atom::Boolean(_) => {},
+          Atom::Null => unknown = true,
+          Atom::Str(_) => return Err(EvalError::Invalid),
+          // Bitwise operations on integers
+          Atom::Num(n) => bits = Some(match (bits, op) {
+            (None, _) => n,
+            (Some(bits), BuiltIn::And) => bits & n,
+            (Some(bits), _) => bits | n,
+          }),
+        }
+      }
+      Ok(match bits {
+        _ if unknown => Atom::Null,
+        Some(bits) => Atom::Num(bits),
+        None => Atom::Boolean(!decisive),
+      })
+    },
+    // Only evaluates operands until one isn't NULL
+    Expr::Application(BuiltIn::Coalesce, tail) => {
+      for expr in tail {
+        match eval_expression(expr, vars)? {
+          Atom::Null => {},
+          value => return Ok(value),
+        }
+      }
+      Ok(Atom::Null)
+    },
     Expr::Application(op, tail) => {
       let reduced_tail = tail
         .into_iter()
         .map(|expr| eval_expression(expr, vars))
-        .collect::<Option<Vec<Atom>>>()?;
+        .collect::<Result<Vec<Atom>, _>>()?;
+      // Operations on NULL are NULL, unless the other operands decide the result anyway
+      if reduced_tail.contains(&Atom::Null) {
+        return Ok(match (op, &reduced_tail[..]) {
+          (BuiltIn::IsNull, _) => Atom::Boolean(true),
+          (BuiltIn::In, [x, list @ ..]) if *x != Atom::Null && list.contains(x) => Atom::Boolean(true),
+          (BuiltIn::Between, [Atom::Num(x), low, high]) => {
+            let below = matches!(low, Atom::Num(low) if x < low);
+            let above = matches!(high, Atom::Num(high) if x > high);
+            if below || above { Atom::Boolean(false) } else { Atom::Null }
+          },
+          _ => Atom::Null,
+        });
+      }
       match op {
+        BuiltIn::IsNull => Ok(Atom::Boolean(false)),
         BuiltIn::Plus | BuiltIn::Times | BuiltIn::Divide | BuiltIn::Rem | BuiltIn::Minus 
-        | BuiltIn::LessThan | BuiltIn::GreaterThan | BuiltIn::LessThanOrEqual 
-        | BuiltIn::GreaterThanOrEqual => {
+        | BuiltIn::Between => {
           // Check that all the tail expressions are numbers
-          let nums = reduced_tail.iter().map(|a| if let Atom::Num(n) = a { Some(*n) } else { return None }).collect::<Option<Vec<i64>>>()?;
+          let nums = reduced_tail.iter().map(|a| if let Atom::Num(n) = a { Ok(*n) } else { Err(EvalError::Invalid) }).collect::<Result<Vec<i64>, _>>()?;
           match op {
-            BuiltIn::Plus => Some(Atom::Num(nums.iter().sum())),
-            BuiltIn::Times => Some(Atom::Num(nums.iter().product())),
-            BuiltIn::Minus => Some(Atom::Num(nums.iter().skip(1).fold(nums[0], |a, f| a - f))),
-            BuiltIn::Divide => Some(Atom::Num(nums.iter().skip(1).fold(nums[0], |a, f| a / f))),
-            BuiltIn::Rem => Some(Atom::Num(nums.iter().skip(1).fold(nums[0], |a, f| a % f))),
-            BuiltIn::LessThan => Some(Atom::Boolean(nums.iter().skip(1).all(|&x| nums[0] < x))),
-            BuiltIn::GreaterThan => Some(Atom::Boolean(nums.iter().skip(1).all(|&x| nums[0] > x))),
-            BuiltIn::LessThanOrEqual => Some(Atom::Boolean(nums.iter().skip(1).all(|&x| nums[0] <= x))),
-            BuiltIn::GreaterThanOrEqual => Some(Atom::Boolean(nums.iter().skip(1).all(|&x| nums[0] >= x))),
+            // Arithmetic wraps around just like it does in the generated code
+            BuiltIn::Plus => Ok(Atom::Num(nums.iter().fold(0, |a, f| a.wrapping_add(*f)))),
+            BuiltIn::Times => Ok(Atom::Num(nums.iter().fold(1, |a, f| a.wrapping_mul(*f)))),
+            BuiltIn::Minus => Ok(Atom::Num(nums.iter().skip(1).fold(nums[0], |a, f| a.wrapping_sub(*f)))),
+            BuiltIn::Divide => nums.iter().skip(1).try_fold(nums[0], |a, f| divide(a, *f, i64::checked_div)).map(Atom::Num),
+            BuiltIn::Rem => nums.iter().skip(1).try_fold(nums[0], |a, f| divide(a, *f, i64::checked_rem)).map(Atom::Num),
+            BuiltIn::Between => match nums[..] {
+              [x, low, high] => Ok(Atom::Boolean(low <= x && x <= high)),
+              _ => Err(EvalError::Invalid),
+            },
             _ => unreachable!(),
           }
         },
-        BuiltIn::Equal => Some(Atom::Boolean(
+        // Integers are compared by value, strings byte by byte
+        BuiltIn::LessThan | BuiltIn::GreaterThan | BuiltIn::LessThanOrEqual | BuiltIn::GreaterThanOrEqual => {
+          let holds = |ordering: Ordering| match op {
+            BuiltIn::LessThan => ordering.is_lt(),
+            BuiltIn::GreaterThan => ordering.is_gt(),
+            BuiltIn::LessThanOrEqual => ordering.is_le(),
+            _ => ordering.is_ge(),
+          };
+          let ordering = |x: &Atom| match (&reduced_tail[0], x) {
+            (Atom::Num(a), Atom::Num(b)) => Ok(a.cmp(b)),
+            (Atom::Str(a), Atom::Str(b)) => Ok(a.cmp(b)),
+            _ => Err(EvalError::Invalid),
+          };
+          reduced_tail[1..].iter().try_fold(true, |all, x| Ok(all && holds(ordering(x)?))).map(Atom::Boolean)
+        },
+        BuiltIn::In => Ok(Atom::Boolean(reduced_tail[1..].contains(&reduced_tail[0]))),
+        BuiltIn::StartsWith | BuiltIn::Like => match &reduced_tail[..] {
+          [Atom::Str(text), Atom::Str(pattern)] if *op == BuiltIn::Like => Ok(Atom::Boolean(like(text, pattern))),
+          [Atom::Str(text), Atom::Str(prefix)] => Ok(Atom::Boolean(text.starts_with(&**prefix))),
+          _ => Err(EvalError::Invalid),
+        },
+        BuiltIn::Length => match &reduced_tail[..] {
+          [Atom::Str(s)] => Ok(Atom::Num(s.len() as i64)),
+          _ => Err(EvalError::Invalid),
+        },
+        BuiltIn::Equal => Ok(Atom::Boolean(
           reduced_tail
             .iter()
             .zip(reduced_tail.iter().skip(1))
             .all(|(a, b)| a == b),
         )),
-        BuiltIn::NotEqual => Some(Atom::Boolean(
+        BuiltIn::NotEqual => Ok(Atom::Boolean(
           reduced_tail
             .iter()
             .zip(reduced_tail.iter().skip(1))
             .any(|(a, b)| a != b),
         )),
-        // Bitwise operations on integers and normal and/or with boolean result on booleans
-        BuiltIn::And => {
-          if let Atom::Boolean(_) = &reduced_tail[0] {
-            Some(Atom::Boolean(reduced_tail.iter().all(|a| a.get_bool())))
-          } else {
-            Some(Atom::Num(reduced_tail.iter().fold(-1, |a, b| a & b.get_num())))
-          }
-        },
-        BuiltIn::Or => {
-          if let Atom::Boolean(_) = &reduced_tail[0] {
-            Some(Atom::Boolean(reduced_tail.iter().any(|a| a.get_bool())))
-          } else {
-            Some(Atom::Num(reduced_tail.iter().fold(0, |a, b| a | b.get_num())))
-          }
-        },
+        // Handled above
+        BuiltIn::And | BuiltIn::Or | BuiltIn::Coalesce => unreachable!(),
         /*BuiltIn::Not => {
           if reduced_tail.len() != 1 {
             return None;
@@ -438,78 +1046,617 @@ pub fn eval_expression(e: &Expr, vars: &[i64]) -> Option<Atom> {
   }
 }
 
-pub fn run_query(query: &Query, data: &[i64], columns: usize, mut result_consumer: impl FnMut(Atom)) {
-  let expr = &query.expr;
-  let filter = &query.filter;
-  let aggregate = query.aggregate;
+/// `op` is `checked_div` or `checked_rem`, which also fail for `i64::MIN` and -1
+fn divide(a: i64, b: i64, op: fn(i64, i64) -> Option<i64>) -> Result<i64, EvalError> {
+  match b {
+    0 => Err(EvalError::DivisionByZero),
+    _ => op(a, b).ok_or(EvalError::Overflow),
+  }
+}
+
+/// Whether `text` matches the SQL LIKE `pattern`: `%` matches any number of characters, `_` exactly
+/// one and everything else itself (case sensitive, there is no escape character)
+pub fn like(text: &str, pattern: &str) -> bool {
+  // Byte positions in the text and the pattern
+  let (mut t, mut p) = (0, 0);
+  // Behind the last `%` and how far into the text it matches so far. Only the last one has to be
+  // retried with longer matches, everything before it already matched as early as possible.
+  let mut backtrack = None;
+  loop {
+    let expected = pattern[p..].chars().next();
+    if expected == Some('%') {
+      p += 1;
+      backtrack = Some((p, t));
+      continue;
+    }
+    match (text[t..].chars().next(), expected) {
+      (Some(c), Some(e)) if e == '_' || e == c => {
+        t += c.len_utf8();
+        p += e.len_utf8();
+      },
+      (None, None) => return true,
+      _ => match backtrack {
+        // Let the `%` match one more character
+        Some((after_percent, matched)) if matched < text.len() => {
+          t = matched + text[matched..].chars().next().unwrap().len_utf8();
+          p = after_percent;
+          backtrack = Some((p, t));
+        },
+        _ => return false,
+      },
+    }
+  }
+}
+
+/// Integer square root (rounded down). Negative inputs produce 0.
+pub fn isqrt(v: i64) -> i64 {
+  if v <= 0 {
+    return 0;
+  }
+  let v = v as i128;
+  let mut r = (v as f64).sqrt() as i128;
+  // The float result can be off by one in either direction for large values
+  while r * r > v {
+    r -= 1;
+  }
+  while (r + 1) * (r + 1) <= v {
+    r += 1;
+  }
+  r as i64
+}
+
+/// Computes variance and standard deviation from the running count, sum and sum of squares.
+/// This uses integer arithmetic with the same wrapping behaviour as the generated code so that
+/// both produce identical results. Divisors are clamped to at least 1 so empty inputs produce 0.
+pub fn finish_variance(aggregate: AggregateFunc, count: i64, sum: i64, sum_squares: i64) -> i64 {
+  let n = count.max(1);
+  let m2 = sum_squares.wrapping_sub(sum.wrapping_mul(sum) / n);
+  match aggregate {
+    AggregateFunc::VarPop => m2 / n,
+    AggregateFunc::VarSamp => m2 / (count - 1).max(1),
+    AggregateFunc::StddevPop => isqrt(m2 / n),
+    AggregateFunc::StddevSamp => isqrt(m2 / (count - 1).max(1)),
+    _ => unreachable!("{:?} is not a variance aggregate", aggregate),
+  }
+}
 
-  let mut aggregate_value = 0;
+fn read_row(table: &Table, row_i: usize, row: &mut [Atom]) {
+  for (column, value) in row.iter_mut().enumerate() {
+    // Floats can't be referenced by a bound query so their value doesn't matter
+    *value = table.atom(row_i, column);
+  }
+}
 
-  for row in data.chunks_exact(columns) {
-    if let Some(filter) = filter {
-      if let Atom::Boolean(false) = eval_expression(filter, row).unwrap() {
-        continue;
+/// For every operand of the filter's top level `&`/`|` the fraction of the first `max_rows` rows
+/// for which it is true. Empty if the filter isn't such an operation.
+pub fn observe_selectivity(filter: &Expr, table: &Table, max_rows: usize) -> Vec<f64> {
+  let Expr::Application(BuiltIn::And | BuiltIn::Or, args) = filter else {
+    return Vec::new();
+  };
+  let rows = table.rows().min(max_rows);
+  let mut true_count = vec![0usize; args.len()];
+  let mut row = vec![Atom::Num(0); table.schema().column_count()];
+  for row_i in 0..rows {
+    read_row(table, row_i, &mut row);
+    for (count, arg) in true_count.iter_mut().zip(args) {
+      match eval_expression(arg, &row) {
+        Ok(Atom::Boolean(true)) => *count += 1,
+        // Each operand is evaluated for every row, even where another one guards its division
+        Ok(Atom::Boolean(false) | Atom::Null) | Err(EvalError::DivisionByZero | EvalError::Overflow) => {},
+        // Bitwise operation on integers
+        _ => return Vec::new(),
       }
     }
-    let value = if let Atom::Num(n) = eval_expression(expr, row).unwrap() {
-      n
-    } else {
-      panic!("Main expression must produce an integer");
+  }
+  true_count.into_iter().map(|count| count as f64 / rows.max(1) as f64).collect()
+}
+
+/// The state of an aggregation part way through the rows. `values` are laid out like the aggregate
+/// variables of the generated code, so that it can continue a scan the interpreter started
+/// (see `query_codegen::generate_resumable_code`):
+/// `avg` has the sum and the count, the variance aggregates the count, sum and sum of squares,
+/// `any`/`all` their flag as 0 or 1 and the others a single value. `count distinct` only has `distinct`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AggregateState {
+  pub aggregate: Option<AggregateFunc>,
+  pub values: Vec<i64>,
+  pub distinct: HashSet<i64>,
+}
+
+impl AggregateState {
+  /// The state before any row was seen
+  pub fn new(aggregate: Option<AggregateFunc>) -> Self {
+    let values = match aggregate {
+      Some(AggregateFunc::Avg) => vec![0, 0],
+      Some(AggregateFunc::Prod) => vec![1],
+      Some(AggregateFunc::Max) => vec![i64::MIN],
+      Some(AggregateFunc::Min) => vec![i64::MAX],
+      Some(AggregateFunc::All) => vec![1],
+      Some(AggregateFunc::CountDistinct) => Vec::new(),
+      Some(AggregateFunc::VarPop) | Some(AggregateFunc::VarSamp)
+      | Some(AggregateFunc::StddevPop) | Some(AggregateFunc::StddevSamp) => vec![0, 0, 0],
+      Some(_) => vec![0],
+      None => Vec::new(),
     };
-    match aggregate {
-      Some(AggregateFunc::Sum) => aggregate_value += value,
-      None => result_consumer(Atom::Num(value)),
-      _ => panic!("Unsupported aggregate function"),
+    AggregateState { aggregate, values, distinct: HashSet::new() }
+  }
+
+  /// Adds the value of the query's expression for a row that passed the filter. Without an
+  /// aggregate the value is the result for the row and goes to `result_consumer` right away.
+  /// Aggregates skip NULLs (the value of `count(*)` never is one).
+  pub fn add(&mut self, value: Atom, result_consumer: &mut impl FnMut(Atom)) {
+    if value == Atom::Null && self.aggregate.is_some() {
+      return;
+    }
+    let values = &mut self.values;
+    match self.aggregate {
+      Some(AggregateFunc::Sum) => values[0] = values[0].wrapping_add(value.get_num()),
+      Some(AggregateFunc::Prod) => values[0] = values[0].wrapping_mul(value.get_num()),
+      Some(AggregateFunc::Avg) => {
+        values[0] = values[0].wrapping_add(value.get_num());
+        values[1] += 1;
+      },
+      Some(AggregateFunc::Max) => values[0] = values[0].max(value.get_num()),
+      Some(AggregateFunc::Min) => values[0] = values[0].min(value.get_num()),
+      Some(AggregateFunc::Count) | Some(AggregateFunc::CountStar) => values[0] += 1,
+      Some(AggregateFunc::CountDistinct) => {
+        self.distinct.insert(value.get_num());
+      },
+      Some(AggregateFunc::Any) => values[0] |= value.get_bool() as i64,
+      Some(AggregateFunc::All) => values[0] &= value.get_bool() as i64,
+      Some(AggregateFunc::VarPop) | Some(AggregateFunc::VarSamp)
+      | Some(AggregateFunc::StddevPop) | Some(AggregateFunc::StddevSamp) => {
+        let value = value.get_num();
+        values[0] += 1;
+        values[1] = values[1].wrapping_add(value);
+        values[2] = values[2].wrapping_add(value.wrapping_mul(value));
+      },
+      None => {
+        if let Atom::Num(_) | Atom::Null = value {
+          result_consumer(value);
+        } else {
+          panic!("Main expression must produce an integer");
+        }
+      },
+    }
+  }
+
+  /// Combines the state of other rows into this one, as if their values had been added to it
+  pub fn merge(&mut self, other: AggregateState) {
+    debug_assert_eq!(self.aggregate, other.aggregate);
+    let (values, other_values) = (&mut self.values, &other.values);
+    match self.aggregate {
+      Some(AggregateFunc::Prod) => values[0] = values[0].wrapping_mul(other_values[0]),
+      Some(AggregateFunc::Max) => values[0] = values[0].max(other_values[0]),
+      Some(AggregateFunc::Min) => values[0] = values[0].min(other_values[0]),
+      Some(AggregateFunc::Any) => values[0] |= other_values[0],
+      Some(AggregateFunc::All) => values[0] &= other_values[0],
+      Some(AggregateFunc::CountDistinct) => self.distinct.extend(other.distinct),
+      // Sums and counts, including the ones of avg and the variances
+      Some(_) => {
+        for (value, other_value) in values.iter_mut().zip(other_values) {
+          *value = value.wrapping_add(*other_value);
+        }
+      },
+      None => {},
     }
   }
-  if let Some(_) = aggregate {
-    result_consumer(Atom::Num(aggregate_value));
+
+  /// Passes the aggregate value to `result_consumer` once all rows were added
+  pub fn finish(self, result_consumer: &mut impl FnMut(Atom)) {
+    let values = &self.values;
+    match self.aggregate {
+      // Like the generated code we return 0 instead of dividing by zero for empty inputs
+      Some(AggregateFunc::Avg) => result_consumer(Atom::Num(values[0] / values[1].max(1))),
+      Some(AggregateFunc::CountDistinct) => result_consumer(Atom::Num(self.distinct.len() as i64)),
+      Some(AggregateFunc::Any) | Some(AggregateFunc::All) => result_consumer(Atom::Boolean(values[0] != 0)),
+      Some(f @ AggregateFunc::VarPop) | Some(f @ AggregateFunc::VarSamp)
+      | Some(f @ AggregateFunc::StddevPop) | Some(f @ AggregateFunc::StddevSamp) => {
+        result_consumer(Atom::Num(finish_variance(f, values[0], values[1], values[2])));
+      },
+      Some(_) => result_consumer(Atom::Num(values[0])),
+      None => {},
+    }
   }
 }
 
-fn err_converter(e: nom::Err<VerboseError<&str>>) -> String {
-  match e {
-    nom::Err::Error(e) | nom::Err::Failure(e) => nom::error::convert_error("", e),
-    nom::Err::Incomplete(_) => "Incomplete input".to_string(),
+/// A result in a `TopK`, ordered by the key (inverted for descending orders) and then by the number
+/// of results added before it. These are unique, so the result itself is never compared.
+#[derive(Debug, Clone)]
+struct Ranked<T> {
+  key: i64,
+  seq: usize,
+  value: T,
+}
+
+impl<T> PartialEq for Ranked<T> {
+  fn eq(&self, other: &Self) -> bool {
+    (self.key, self.seq) == (other.key, other.seq)
+  }
+}
+
+impl<T> Eq for Ranked<T> {}
+
+impl<T> PartialOrd for Ranked<T> {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl<T> Ord for Ranked<T> {
+  fn cmp(&self, other: &Self) -> Ordering {
+    (self.key, self.seq).cmp(&(other.key, other.seq))
+  }
+}
+
+/// The results with the lowest (or highest if `descending`) sort keys, at most `limit` of them.
+/// Results with the same key are kept in the order they were added.
+#[derive(Debug, Clone)]
+pub struct TopK<T = i64> {
+  /// The worst result is on top, so it is the one that goes once there are more than `limit`
+  heap: BinaryHeap<Ranked<T>>,
+  limit: Option<usize>,
+  descending: bool,
+  added: usize,
+}
+
+impl<T> TopK<T> {
+  pub fn new(limit: Option<usize>, descending: bool) -> Self {
+    TopK { heap: BinaryHeap::new(), limit, descending, added: 0 }
+  }
+
+  pub fn add(&mut self, key: i64, value: T) {
+    // `!` reverses the order of all i64 without overflowing
+    let key = if self.descending { !key } else { key };
+    self.heap.push(Ranked { key, seq: self.added, value });
+    self.added += 1;
+    if self.limit.is_some_and(|limit| self.heap.len() > limit) {
+      self.heap.pop();
+    }
+  }
+
+  /// The results in order. Afterwards no results are left, so more can be added for another run.
+  pub fn take_sorted(&mut self) -> Vec<T> {
+    self.added = 0;
+    mem::take(&mut self.heap).into_sorted_vec().into_iter().map(|ranked| ranked.value).collect()
   }
 }
 
+pub fn run_query(query: &Query, table: &Table, result_consumer: impl FnMut(Atom)) -> Result<(), EvalError> {
+  run_on_tables(query, table, None, result_consumer)
+}
+
+/// Runs a query with a join of `table` (`l`) and `right` (`r`)
+pub fn run_join_query(query: &Query, table: &Table, right: &Table, result_consumer: impl FnMut(Atom)) -> Result<(), EvalError> {
+  run_on_tables(query, table, Some(right), result_consumer)
+}
+
+fn run_on_tables(query: &Query, table: &Table, right: Option<&Table>, mut result_consumer: impl FnMut(Atom)) -> Result<(), EvalError> {
+  if let Some(order_by) = &query.order_by {
+    let mut top_k = TopK::new(query.limit, order_by.descending);
+    scan_rows(query, table, right, 0..table.rows(), |row| {
+      let key = match eval_expression(&order_by.expr, row)? {
+        Atom::Num(n) => n,
+        Atom::Boolean(b) => b as i64,
+        Atom::Null => unreachable!("`Query::check_types` rejects order keys that can be NULL"),
+        Atom::Str(_) => unreachable!("`Query::check_types` rejects string order keys"),
+      };
+      top_k.add(key, eval_expression(&query.expr, row)?);
+      Ok(ControlFlow::Continue(()))
+    })?;
+    top_k.take_sorted().into_iter().for_each(result_consumer);
+  } else if let Some(limit) = query.limit {
+    let mut results = 0;
+    if limit > 0 {
+      scan_rows(query, table, right, 0..table.rows(), |row| {
+        result_consumer(eval_expression(&query.expr, row)?);
+        results += 1;
+        Ok(if results == limit { ControlFlow::Break(()) } else { ControlFlow::Continue(()) })
+      })?;
+    }
+  } else {
+    let mut state = AggregateState::new(query.aggregate);
+    scan_rows(query, table, right, 0..table.rows(), |row| {
+      state.add(eval_expression(&query.expr, row)?, &mut result_consumer);
+      Ok(ControlFlow::Continue(()))
+    })?;
+    state.finish(&mut result_consumer);
+  }
+  Ok(())
+}
+
+/// Interprets the query for some of the rows of the table, the aggregate value of the rows
+/// that pass the filter is added to `state`. Ignores the order and the limit of the query, which
+/// can't have a join.
+pub fn run_rows(query: &Query, table: &Table, rows: Range<usize>, state: &mut AggregateState, result_consumer: &mut impl FnMut(Atom)) -> Result<(), EvalError> {
+  scan_rows(query, table, None, rows, |row| {
+    state.add(eval_expression(&query.expr, row)?, result_consumer);
+    Ok(ControlFlow::Continue(()))
+  })
+}
+
+/// Calls `f` with the values of the rows that pass the filter (for which it is true, not false or
+/// NULL) until it breaks. With a join these are the rows of `table` combined with each row of `right`
+/// that has the same key, in the order of the rows of `table` and then in the order of the rows of
+/// `right`. Rows with a NULL key don't match any row. Stops at the first error.
+fn scan_rows(query: &Query, table: &Table, right: Option<&Table>, rows: Range<usize>, mut f: impl FnMut(&[Atom]) -> Result<ControlFlow<()>, EvalError>) -> Result<(), EvalError> {
+  let left_columns = table.schema().column_count();
+  let mut row = vec![Atom::Num(0); left_columns + right.map_or(0, |right| right.schema().column_count())];
+  // The hash join: the rows of `right` by their key
+  let mut right_rows = HashMap::<i64, Vec<usize>>::new();
+  if let Some(join) = &query.join {
+    let right = right.expect("Queries with a join need the table they join with");
+    for right_i in 0..right.rows() {
+      read_row(right, right_i, &mut row[left_columns..]);
+      if let Atom::Num(key) = eval_expression(&join.right_key, &row)? {
+        right_rows.entry(key).or_default().push(right_i);
+      }
+    }
+  }
+  let mut visit = |row: &[Atom]| {
+    if let Some(filter) = &query.filter {
+      if eval_expression(filter, row)? != Atom::Boolean(true) {
+        return Ok(ControlFlow::Continue(()));
+      }
+    }
+    f(row)
+  };
+  for row_i in rows {
+    read_row(table, row_i, &mut row[..left_columns]);
+    let Some(join) = &query.join else {
+      if visit(&row)?.is_break() {
+        return Ok(());
+      }
+      continue;
+    };
+    let Atom::Num(key) = eval_expression(&join.left_key, &row)? else {
+      continue;
+    };
+    for &right_i in right_rows.get(&key).map_or(&[][..], Vec::as_slice) {
+      read_row(right.unwrap(), right_i, &mut row[left_columns..]);
+      if visit(&row)?.is_break() {
+        return Ok(());
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Why an expression can't be evaluated (see `eval_expression`)
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EvalError {
+  /// The expression isn't well typed (see `Query::check_types`) or isn't bound yet
+  Invalid,
+  DivisionByZero,
+  /// `i64::MIN / -1` or `i64::MIN % -1`, whose quotient doesn't fit into an i64
+  Overflow,
+}
+
+impl Display for EvalError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      EvalError::Invalid => write!(f, "The expression can't be evaluated"),
+      EvalError::DivisionByZero => write!(f, "Division by zero"),
+      EvalError::Overflow => write!(f, "The quotient of {} and -1 doesn't fit into an i64", i64::MIN),
+    }
+  }
+}
+
+/// A syntax error at byte `position` of the query string
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ParseError {
+  pub message: String,
+  pub position: usize,
+}
+
+impl ParseError {
+  /// The error message below the query, with a caret pointing to where the error is
+  pub fn diagnostic(&self, src: &str) -> String {
+    Span { start: self.position, end: self.position }.diagnostic(src, &self.message)
+  }
+}
+
+impl Display for ParseError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "{} at position {}", self.message, self.position)
+  }
+}
+
+/// Describes the input starting at `i` for error messages: the next token, a single parenthesis
+/// or the end of the input
+fn describe_input(i: &str) -> String {
+  let i = i.trim_start();
+  match i.chars().next() {
+    None => "end of input".to_string(),
+    Some(c @ ('(' | ')')) => format!("`{}`", c),
+    Some(_) => format!("`{}`", i.split(|c: char| c.is_whitespace() || c == '(' || c == ')').next().unwrap()),
+  }
+}
+
+/// Turns nom's error stack into a `ParseError`. The innermost error (the first one) tells us
+/// where parsing failed, the innermost context why.
+pub fn err_converter(src: &str, e: nom::Err<VerboseError<&str>>) -> ParseError {
+  let errors = match e {
+    nom::Err::Error(e) | nom::Err::Failure(e) => e.errors,
+    nom::Err::Incomplete(_) => Vec::new(),
+  };
+  let Some((input, kind)) = errors.first() else {
+    return ParseError { message: "incomplete input".to_string(), position: src.len() };
+  };
+  let position = src.len() - input.len();
+  let expected = errors.iter().find_map(|(_, kind)| match kind {
+    VerboseErrorKind::Context(context) => Some(context.to_string()),
+    _ => None,
+  }).or_else(|| match kind {
+    VerboseErrorKind::Char(c) => Some(format!("expected `{}`", c)),
+    _ => None,
+  });
+  let message = match expected {
+    Some(expected) if expected.starts_with("expected") => format!("{}, found {}", expected, describe_input(input)),
+    // Failures like `failure(i, "column number is too large")` already say what is wrong
+    Some(message) => message,
+    None => format!("unexpected {}", describe_input(input)),
+  };
+  // Skip the whitespace in front of the token the message talks about
+  let position = position + (input.len() - input.trim_start().len());
+  ParseError { message, position }
+}
+
 #[allow(dead_code)]
-pub fn parse_expr_from_str(src: &str) -> Result<Expr, String> {
+pub fn parse_expr_from_str(src: &str) -> Result<Expr, ParseError> {
   parse_expr(src)
-    .map_err( err_converter)
-    .map(|(_, exp)| exp)
+    .map_err(|e| err_converter(src, e))
+    .map(|(_, (exp, _))| exp)
 }
 /// And we add one more top-level function to tie everything together, letting
 /// us call eval on a string directly
 #[allow(dead_code)]
 pub fn eval_from_str(src: &str, vars: &[i64]) -> Result<Atom, String> {
   parse_expr(src)
-    .map_err(err_converter)
-    .and_then(|(_, exp)| eval_expression(&exp, vars).ok_or("Eval Error".to_string()))
+    .map_err(|e| err_converter(src, e).to_string())
+    .and_then(|(_, (exp, _))| {
+      let vars = vars.iter().map(|v| Atom::Num(*v)).collect::<Vec<_>>();
+      eval_expression(&exp, &vars).map_err(|e| e.to_string())
+    })
 }
 
 fn parse_aggregate_func<'a>(i: &'a str) -> IResult<&'a str, AggregateFunc, VerboseError<&'a str>> {
+  // Longer names have to come before their prefixes (e.g. "stddev_pop" before "stddev")
   alt((
     map(tag_no_case("sum"), |_| AggregateFunc::Sum),
     map(tag_no_case("prod"), |_| AggregateFunc::Prod),
     map(tag_no_case("avg"), |_| AggregateFunc::Avg),
     map(tag_no_case("max"), |_| AggregateFunc::Max),
     map(tag_no_case("min"), |_| AggregateFunc::Min),
+    map(tuple((tag_no_case("count"), multispace1, tag_no_case("distinct"))), |_| AggregateFunc::CountDistinct),
+    map(tag_no_case("count"), |_| AggregateFunc::Count),
+    map(alt((tag_no_case("any"), tag_no_case("bool_or"))), |_| AggregateFunc::Any),
+    map(alt((tag_no_case("all"), tag_no_case("bool_and"))), |_| AggregateFunc::All),
+    map(tag_no_case("var_pop"), |_| AggregateFunc::VarPop),
+    map(alt((tag_no_case("var_samp"), tag_no_case("variance"))), |_| AggregateFunc::VarSamp),
+    map(tag_no_case("stddev_pop"), |_| AggregateFunc::StddevPop),
+    map(alt((tag_no_case("stddev_samp"), tag_no_case("stddev"))), |_| AggregateFunc::StddevSamp),
   ))(i)
 }
 
-pub fn parse_query_from_str(src: &str) -> Result<Query, String> {
-  let (src, aggregate) = opt(terminated(parse_aggregate_func, multispace1))(src).map_err(err_converter)?;
-  let (src, expr) = parse_expr(src).unwrap();
-  if get_type(&expr) != DataType::I64 {
-    return Err("Expression must be an integer expression".to_string());
+/// COUNT(*) (or COUNT *) doesn't take an expression so we parse it separately
+fn parse_count_star<'a>(i: &'a str) -> IResult<&'a str, AggregateFunc, VerboseError<&'a str>> {
+  map(
+    tuple((tag_no_case("count"), alt((
+      delimited(tuple((multispace0, char('('), multispace0)), char('*'), tuple((multispace0, char(')')))),
+      preceded(multispace1, char('*')),
+    )))),
+    |_| AggregateFunc::CountStar,
+  )(i)
+}
+
+/// Parses a query without its spans, which the tests don't need
+#[cfg(test)]
+pub fn parse_query_from_str(src: &str) -> Result<Query, ParseError> {
+  parse_query_with_spans(src).map(|(query, _)| query)
+}
+
+/// Parses a query and remembers where each of its expressions is in `src`.
+/// The whole input has to be a query, only whitespace may follow it.
+pub fn parse_query_with_spans(original: &str) -> Result<(Query, QuerySpans), ParseError> {
+  let len = original.len();
+  let to_parse_error = |e| err_converter(original, e);
+  let (src, count_star) = opt(parse_count_star)(original).map_err(to_parse_error)?;
+  let (src, aggregate, expr) = if let Some(count_star) = count_star {
+    // There is no expression to aggregate so we just count a constant for every row
+    let spans = ExprSpans { span: Span { start: len, end: src.len() }, args: Vec::new() };
+    (src, Some(count_star), (Expr::Constant(Atom::Num(1)), spans))
+  } else {
+    let (src, aggregate) = opt(terminated(parse_aggregate_func, multispace1))(src).map_err(to_parse_error)?;
+    let (src, expr) = parse_expr(src).map_err(to_parse_error)?;
+    (src, aggregate, expr)
+  };
+  let join_clause = preceded(
+    tuple((multispace1, keyword("join"), multispace1, context("expected `on` after `join`", cut(keyword("on"))))),
+    context("expected a join condition after `on`", cut(preceded(multispace1, parse_expr))),
+  );
+  let (src, join) = opt(join_clause)(src).map_err(to_parse_error)?;
+  let where_clause = preceded(tuple((multispace1, tag_no_case("where"))), context("expected a filter after `where`", cut(preceded(multispace1, parse_expr))));
+  let (src, filter) = opt(where_clause)(src).map_err(to_parse_error)?;
+  let (src, order_by_limit) = parse_order_by_limit(src, parse_expr).map_err(to_parse_error)?;
+  finish_query(original, src, "`join`, `where`, `order by`, `limit` or the end of the query", (aggregate, expr), join, filter, order_by_limit)
+}
+
+/// The clauses that can come after the filter, as they were parsed
+#[derive(Debug, Default)]
+pub struct OrderByLimit {
+  /// The expression and whether the order is descending
+  pub order_by: Option<((Expr, ExprSpans), bool)>,
+  pub limit: Option<usize>,
+}
+
+/// `[order by <expr> [asc|desc]] [limit <n>]` in any case. Both syntaxes share them, only
+/// the expression is parsed with `parse_expr` of the syntax.
+pub fn parse_order_by_limit<'a>(i: &'a str, parse_expr: impl FnMut(&'a str) -> IResult<&'a str, (Expr, ExprSpans), VerboseError<&'a str>>) -> IResult<&'a str, OrderByLimit, VerboseError<&'a str>> {
+  let direction = alt((value(false, keyword("asc")), value(true, keyword("desc"))));
+  let order_by = preceded(
+    tuple((multispace0, keyword("order"), multispace1, context("expected `by` after `order`", cut(keyword("by"))))),
+    cut(pair(
+      context("expected an expression to order by", preceded(multispace0, parse_expr)),
+      map(opt(preceded(multispace1, direction)), Option::unwrap_or_default),
+    )),
+  );
+  let (i, order_by) = opt(order_by)(i)?;
+  let limit = preceded(
+    pair(multispace0, keyword("limit")),
+    context("expected the number of results after `limit`", cut(preceded(multispace0, map_res(digit1, str::parse::<usize>)))),
+  );
+  let (i, limit) = opt(limit)(i)?;
+  Ok((i, OrderByLimit { order_by, limit }))
+}
+
+/// Checks that only whitespace is left after a query (`expected` is what could have come instead)
+/// and turns the spans measured by the parser into offsets into `original`. `select` is the aggregate
+/// and the expression, `join` the condition of a join, which has to compare the keys with `=`.
+pub fn finish_query(original: &str, rest: &str, expected: &str, select: (Option<AggregateFunc>, (Expr, ExprSpans)), join: Option<(Expr, ExprSpans)>, filter: Option<(Expr, ExprSpans)>, order_by_limit: OrderByLimit) -> Result<(Query, QuerySpans), ParseError> {
+  let len = original.len();
+  if !rest.trim().is_empty() {
+    let message = format!("expected {}, found {}", expected, describe_input(rest));
+    return Err(ParseError { message, position: len - rest.trim_start().len() });
   }
-  let (_, filter) = opt(preceded(tuple((multispace1, tag_no_case("where"), multispace1)), parse_expr))(src).map_err(err_converter)?;
-  if let Some(filter) = &filter {
-    if get_type(filter) != DataType::Bool {
-      return Err("Filter must be a boolean expression".to_string());
-    }
+  let (join, join_spans) = match join {
+    Some((Expr::Application(BuiltIn::Equal, mut keys), spans)) if keys.len() == 2 => {
+      let right_key = keys.pop().unwrap();
+      let left_key = keys.pop().unwrap();
+      (Some(Join { left_key, right_key }), Some(spans.into_offsets(len)))
+    },
+    Some((_, spans)) => {
+      let message = "a join condition must compare the keys of both tables with `=`".to_string();
+      return Err(ParseError { message, position: len - spans.span.start });
+    },
+    None => (None, None),
+  };
+  let (aggregate, (expr, expr_spans)) = select;
+  let (filter, filter_spans) = filter.unzip();
+  let (order_by, order_by_spans) = order_by_limit.order_by.map(|((expr, spans), descending)| (OrderBy { expr, descending }, spans)).unzip();
+  let spans = QuerySpans {
+    expr: expr_spans.into_offsets(len),
+    join: join_spans,
+    filter: filter_spans.map(|spans| spans.into_offsets(len)),
+    order_by: order_by_spans.map(|spans| spans.into_offsets(len)),
+  };
+  // Types are checked in `Query::bind_with_spans` once we know the schema
+  Ok((Query { aggregate, filter, expr, join, order_by, limit: order_by_limit.limit }, spans))
+}
+
+/// The surface syntaxes a query can be written in
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Syntax {
+  /// `sum (+ a 2) where (> b 3)`
+  Lisp,
+  /// `SELECT sum(a + 2) FROM t WHERE b > 3`, see `sql.rs`
+  Sql,
+}
+
+/// Parses a query in the given syntax, see `parse_query_with_spans`
+pub fn parse_query(src: &str, syntax: Syntax) -> Result<(Query, QuerySpans), ParseError> {
+  match syntax {
+    Syntax::Lisp => parse_query_with_spans(src),
+    Syntax::Sql => parse_sql_query(src),
   }
-  Ok(Query { aggregate, filter, expr })
-}
\ No newline at end of file
+}
@@ -0,0 +1,158 @@
+// Cache of compiled queries, so that running a query again (or one that only differs in its literals)
+// doesn't generate code again. Queries are normalized before they are looked up: they are simplified,
+// which folds constants, and then every integer literal is replaced by a parameter. The values of the
+// literals are passed to the cached code when it runs, just like for a `PreparedQuery`. Literals the
+// code generator builds specialized code for stay as they are: lists of constants in `in` (which are
+// looked up in a table) and the bounds of `between` if both are constant (a single comparison).
+
+use std::{collections::HashMap, fmt::{self, Display, Formatter}};
+
+use crate::{query::{Atom, BuiltIn, EvalError, Expr, OrderBy, Query}, query_codegen::{CodeGenError, CodeGenOptions, OutputBuffer, PreparedQuery}, simplify::simplify, table::Table};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+    pub entries: usize,
+    /// Size of the code of all cached queries
+    pub code_bytes: usize,
+}
+
+impl Display for CacheStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} hits, {} misses, {} evictions, {} queries with {} bytes of code cached", self.hits, self.misses, self.evictions, self.entries, self.code_bytes)
+    }
+}
+
+struct CacheEntry {
+    code: PreparedQuery,
+    options: CodeGenOptions,
+    /// Value of `QueryCache::clock` when the entry was used last
+    last_used: u64,
+}
+
+/// The result of looking up a query: its code and the values of its literals to run the code with
+pub struct CacheLookup<'a> {
+    pub code: &'a PreparedQuery,
+    pub values: Vec<i64>,
+    pub hit: bool,
+    /// The statistics of the cache after the lookup
+    pub stats: CacheStats,
+}
+
+impl CacheLookup<'_> {
+    pub fn execute(&self, table: &Table, output: &mut OutputBuffer) -> Result<(), String> {
+        self.code.execute(table, &self.values, output)
+    }
+}
+
+/// Compiled queries for tables with one schema. Holds at most
+/// `capacity` bytes of code (but always the query that was compiled last), the query that
+/// wasn't used for the longest time is evicted first.
+pub struct QueryCache {
+    entries: HashMap<Query, CacheEntry>,
+    capacity: usize,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize) -> Self {
+        QueryCache { entries: HashMap::new(), capacity, clock: 0, stats: CacheStats::default() }
+    }
+
+    /// Returns the code for `query`, generating it if no query with the same normalized form was
+    /// compiled for the same schema, layout and options before. The query must be bound and can't have
+    /// parameters of its own (see `Query::with_parameters`).
+    pub fn get(&mut self, query: &Query, table: &Table, options: &CodeGenOptions) -> Result<CacheLookup<'_>, CodeGenError> {
+        debug_assert!(query.parameters().is_empty(), "Queries with parameters can't be cached");
+        let (normalized, values) = normalize(query, table).map_err(CodeGenError::Eval)?;
+        self.clock += 1;
+        let hit = match self.entries.get_mut(&normalized) {
+            Some(entry) if entry.code.is_prepared_for(table) && entry.options == *options => {
+                entry.last_used = self.clock;
+                true
+            },
+            _ => false,
+        };
+        if hit {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+            let code = PreparedQuery::new(&normalized, table.schema(), table.layout(), options)?;
+            let entry = CacheEntry { code, options: options.clone(), last_used: self.clock };
+            if let Some(replaced) = self.entries.insert(normalized.clone(), entry) {
+                self.stats.code_bytes -= replaced.code.code_len();
+            }
+            self.stats.code_bytes += self.entries[&normalized].code.code_len();
+            self.evict();
+        }
+        self.stats.entries = self.entries.len();
+        let entry = self.entries.get_mut(&normalized).unwrap();
+        if let (Some(LIMIT_PLACEHOLDER), Some(limit)) = (normalized.limit, query.limit) {
+            entry.code.set_limit(limit).expect("The code of a query with a limit other than 0 can change it");
+        }
+        Ok(CacheLookup { code: &entry.code, values, hit, stats: self.stats })
+    }
+
+    /// Evicts the least recently used queries until the cache fits into its capacity again
+    fn evict(&mut self) {
+        while self.stats.code_bytes > self.capacity && self.entries.len() > 1 {
+            let oldest = self.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(query, _)| query.clone()).unwrap();
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.stats.code_bytes -= entry.code.code_len();
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+/// The limit of the normalized form of queries without an `order by`, the real one is patched into
+/// the code (see `PreparedQuery::set_limit`)
+const LIMIT_PLACEHOLDER: usize = 1;
+
+/// Simplifies the query and replaces its integer literals (except the specialized ones, see
+/// `abstract_literals`) by the parameters `?1`, `?2`, ... in the order they appear. Equal literals share a parameter, so common subexpressions stay common.
+/// The limits of queries without an `order by` are replaced by `LIMIT_PLACEHOLDER` (unless they are 0).
+/// Returns the normalized query and the values of its parameters.
+fn normalize(query: &Query, table: &Table) -> Result<(Query, Vec<i64>), EvalError> {
+    let column_types = table.schema().value_types();
+    let nullable_columns = table.schema().nullable_columns();
+    let mut values = Vec::new();
+    let filter = match &query.filter {
+        Some(filter) => Some(abstract_literals(&simplify(filter, &column_types, &nullable_columns)?, &mut values)),
+        None => None,
+    };
+    let expr = abstract_literals(&simplify(&query.expr, &column_types, &nullable_columns)?, &mut values);
+    let order_by = match &query.order_by {
+        Some(order_by) => Some(OrderBy { expr: abstract_literals(&simplify(&order_by.expr, &column_types, &nullable_columns)?, &mut values), descending: order_by.descending }),
+        None => None,
+    };
+    let limit = match query.limit {
+        Some(limit) if limit > 0 && query.order_by.is_none() => Some(LIMIT_PLACEHOLDER),
+        limit => limit,
+    };
+    // Queries with a join can't be cached (there is only one table), so their keys are left as they are
+    Ok((Query { aggregate: query.aggregate, filter, expr, join: query.join.clone(), order_by, limit }, values))
+}
+
+/// Constant lists of `in` and constant bounds of `between` are kept, the code for them depends on
+/// their values (see `generate_in` and `generate_between`)
+fn abstract_literals(expr: &Expr, values: &mut Vec<i64>) -> Expr {
+    match expr {
+        Expr::Application(fun @ (BuiltIn::In | BuiltIn::Between), args) if args[1..].iter().all(|arg| matches!(arg, Expr::Constant(_))) => {
+            let mut args = args.clone();
+            args[0] = abstract_literals(&args[0], values);
+            Expr::Application(*fun, args)
+        },
+        Expr::Constant(Atom::Num(n)) => {
+            let position = values.iter().position(|v| v == n).unwrap_or_else(|| {
+                values.push(*n);
+                values.len() - 1
+            });
+            Expr::Parameter((position + 1).to_string())
+        },
+        Expr::Application(fun, args) => Expr::Application(*fun, args.iter().map(|arg| abstract_literals(arg, values)).collect()),
+        _ => expr.clone(),
+    }
+}
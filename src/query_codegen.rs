@@ -1,171 +1,75 @@
-use std::{fmt::Display, ops::Deref};
+use std::{any::Any, cell::RefCell, collections::{BTreeSet, HashMap, HashSet}, fmt::Display, ops::Deref, ptr, rc::Rc, slice, str, sync::Arc};
 
-use crate::{codegen::{CGCmp, CodegenCFunctionSignature, IntoBaseRef, Setable, TypedPtrRef, TypedPtrRefOffset, UntypedPtrRef}, query::{AggregateFunc, Atom, BuiltIn, Expr, Query}};
+use crate::{codegen::{get_data_type_size, CGCmp, CodegenCFunctionSignature, IntoBaseRef, PtrRefByteOffset, Setable, TypedPtrRef, UntypedPtrRef}, query::{isqrt, like, AggregateFunc, AggregateState, Atom, BuiltIn, EvalError, Expr, Join, OrderBy, Query, TopK}, schema::Schema, simplify::simplify, table::{Layout, Table}, typecheck::{can_be_null, TypeError}};
 
 #[cfg(feature = "print-asm")]
 use crate::codegen::disassemble;
 
-use crate::codegen::{ir::DataType, BoolRef, CGEq, CGValueRef, CodeGen, GeneratedCode, I64Ref};
+use crate::codegen::{ir::{ConstValue, DataType}, BoolRef, CGEq, CGValueRef, CodeGen, GeneratedCode, I64Ref};
 
-fn fold_op(fun: &BuiltIn, l: Atom, r: Atom) -> Option<Atom> {
-    match (fun, l, r) {
-        (BuiltIn::Plus, Atom::Num(l), Atom::Num(r)) => Some(Atom::Num(l + r)),
-        (BuiltIn::Times, Atom::Num(l), Atom::Num(r)) => Some(Atom::Num(l * r)),
-        (BuiltIn::Minus, Atom::Num(l), Atom::Num(r)) => Some(Atom::Num(l - r)),
-        (BuiltIn::Divide, Atom::Num(l), Atom::Num(r)) => Some(Atom::Num(l / r)),
-        (BuiltIn::Equal, Atom::Num(l), Atom::Num(r)) => Some(Atom::Boolean(l == r)),
-        (BuiltIn::Equal, Atom::Boolean(l), Atom::Boolean(r)) => Some(Atom::Boolean(l == r)),
-        _ => None,
-    }
-}
-
-fn is_commutative(fun: &BuiltIn) -> bool {
-    match fun {
-        BuiltIn::Plus | BuiltIn::Times | BuiltIn::Equal => true,
-        _ => false,
-    }
-}
-
-pub fn get_type(expr: &Expr) -> DataType {
+/// `column_types` are the types of the columns inside of expressions (see `Schema::value_types`).
+/// Only meaningful for expressions that passed `type_expr`, the type of `&` and `|` is taken
+/// from their first operand
+pub fn get_type(expr: &Expr, column_types: &[DataType]) -> DataType {
     match expr {
-        Expr::Constant(Atom::Num(_)) => DataType::I64,
+        Expr::Constant(Atom::Num(_) | Atom::Null) => DataType::I64,
         Expr::Constant(Atom::Boolean(_)) => DataType::Bool,
-        Expr::Variable(_) => DataType::I64,
+        Expr::Constant(Atom::Str(_)) => DataType::Str,
+        Expr::Variable(n) => column_types.get(*n).copied().unwrap_or(DataType::I64),
+        Expr::Column(_) | Expr::Joined(..) | Expr::Parameter(_) => DataType::I64,
         Expr::Application(fun, args) => {
             match fun {
-                BuiltIn::Plus | BuiltIn::Minus | BuiltIn::Times | BuiltIn::Divide |  BuiltIn::Rem => {
+                BuiltIn::Plus | BuiltIn::Minus | BuiltIn::Times | BuiltIn::Divide |  BuiltIn::Rem | BuiltIn::Length => {
                     DataType::I64
                 },
                 BuiltIn::Equal | BuiltIn::NotEqual | BuiltIn::GreaterThan | BuiltIn::GreaterThanOrEqual 
-                | BuiltIn::LessThan | BuiltIn::LessThanOrEqual => {
+                | BuiltIn::LessThan | BuiltIn::LessThanOrEqual | BuiltIn::In | BuiltIn::Between | BuiltIn::IsNull
+                | BuiltIn::StartsWith | BuiltIn::Like => {
                     DataType::Bool
                 },
-                BuiltIn::And | BuiltIn::Or => {
+                BuiltIn::And | BuiltIn::Or | BuiltIn::Coalesce => {
                     // Get type of first argument
-                    get_type(&args[0])
+                    get_type(&args[0], column_types)
                 },
             }
         },
     }
 }
 
-fn fold_constants(fun: &BuiltIn, args: &[Expr]) -> Option<Vec<Expr>> {
-    if is_commutative(fun) {
-        fold_all_constants_commutative(fun, args)
-    } else {
-        // We just merge all the constants that appear in the beginning of the list for now which should be safe to do
-        // assuming that a list of arguments just means folding the arguments using the function
-        let mut result = Vec::new();
-
-        let mut args_iter = args.iter().peekable();
-
-        let data_type = get_type(&args[0]);
-        
-        if let Some(Expr::Constant(n)) = args_iter.peek() {
-            let mut folded_constants = *n;
-            args_iter.next();
-            while let Some(Expr::Constant(n)) = args_iter.peek() {
-                folded_constants = fold_op(fun, folded_constants, *n)?;
-                args_iter.next();
-            }
-            result.push(Expr::Constant(folded_constants));
-        }
-
-        for arg in args_iter {
-            if get_type(&arg) != data_type {
-                return None;
-            }
-            match arg {
-                Expr::Application(fun2, s) => {
-                    let folded_s = fold_constants(fun2, s)?;
-                    if folded_s.len() == 1 {
-                        result.push(folded_s[0].clone());
-                    } else {
-                        result.push(Expr::Application(*fun2, folded_s));
-                    }
-                },
-                _ => result.push(arg.clone()),
-            }
-        }
-
-        Some(result)
-    }
-}
-
-fn fold_all_constants_commutative(fun: &BuiltIn, args: &[Expr]) -> Option<Vec<Expr>> {
-    let mut applications = Vec::new();
-    let mut variables = Vec::new();
-    let mut folded_constants = None;
-
-    let data_type = get_type(&args[0]);
-
-    // Add all the variables to result and fold all constants into one
-    for arg in args {
-        if get_type(&arg) != data_type {
-            return None;
-        }
-        match arg {
-            Expr::Constant(n) => {
-                if let Some(folded_constants_n) = folded_constants {
-                    folded_constants = Some(fold_op(fun, folded_constants_n, *n)?);
-                } else {
-                    folded_constants = Some(*n);
-                }
-            },
-            Expr::Variable(n) => {
-                variables.push(Expr::Variable(*n));
-            },
-            Expr::Application(fun2, s) => {
-                let folded_s = fold_constants(fun2, s)?;
-                if folded_s.len() == 1 {
-                    match folded_s[0] {
-                        Expr::Constant(n) => {
-                            if let Some(folded_constants_n) = folded_constants {
-                                folded_constants = Some(fold_op(fun, folded_constants_n, n)?);
-                            } else {
-                                folded_constants = Some(n);
-                            }
-                        },
-                        Expr::Variable(n) => {
-                            variables.push(Expr::Variable(n));
-                        },
-                        _ => unreachable!()
-                    }
-                } else {
-                    applications.push(Expr::Application(*fun2, folded_s));
-                }
-            },
-        }
-    }
-
-    // We make sure to execute applications first then variables so that
-    // we minimize the number of times we need to take from the stack
-    let mut result = Vec::new();
-    result.extend(applications);
-    result.extend(variables);
-
-    if let Some(folded_constants_n) = folded_constants {
-        result.push(Expr::Constant(folded_constants_n));
-    }
-
-    Some(result)
+/// All operands of an operation must have the same type
+fn operand_types_match(args: &[Expr], column_types: &[DataType]) -> bool {
+    args.iter().all(|arg| get_type(arg, column_types) == get_type(&args[0], column_types))
 }
 
 #[derive(Debug)]
 pub enum CodeGenError {
-    Const(Atom),
-    TypeError
+    TypeError(TypeError),
+    UnresolvedColumn(String),
+    UnboundParameter(String),
+    /// The query needs something the requested kind of code can't do
+    Unsupported(&'static str),
+    /// Interpreting the query failed (see `adaptive`)
+    Eval(EvalError),
 }
 
 impl Display for CodeGenError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            CodeGenError::Const(n) => {
-                write!(f, "Result(const): {}", n)
+            CodeGenError::TypeError(e) => {
+                write!(f, "Type error: {}", e)
+            },
+            CodeGenError::UnresolvedColumn(name) => {
+                write!(f, "Column \"{}\" has not been resolved against a schema", name)
+            },
+            CodeGenError::UnboundParameter(name) => {
+                write!(f, "Parameter \"{}\" has no value", name)
+            },
+            CodeGenError::Unsupported(message) => {
+                write!(f, "{}", message)
+            },
+            CodeGenError::Eval(e) => {
+                write!(f, "Error: {}", e)
             },
-            CodeGenError::TypeError => {
-                write!(f, "Type error")
-            }
         }
     }
 }
@@ -178,158 +82,845 @@ fn generate_atom<'cg>(cg: &'cg CodeGen, atom: &Atom) -> CGValueRef<'cg> {
         Atom::Boolean(b) => {
             cg.new_bool_const(*b).into()
         },
+        Atom::Null => unreachable!("Queries don't contain NULL constants"),
+        Atom::Str(_) => unreachable!("String constants need their length, see `generate_operand`"),
+    }
+}
+
+/// A value of the generated code and whether it is NULL. `null` is None if the value can't be NULL,
+/// so values of columns that aren't nullable (and everything computed from them) don't need any checks.
+/// The value of a string is a pointer to its first byte, `length` is only set for strings.
+#[derive(Clone)]
+struct Nullable<'cg> {
+    value: CGValueRef<'cg>,
+    null: Option<BoolRef<'cg>>,
+    length: Option<I64Ref<'cg>>,
+}
+
+impl<'cg> Nullable<'cg> {
+    fn new(value: impl Into<CGValueRef<'cg>>, null: Option<BoolRef<'cg>>) -> Self {
+        Nullable { value: value.into(), null, length: None }
     }
+
+    fn not_null(value: impl Into<CGValueRef<'cg>>) -> Self {
+        Nullable::new(value, None)
+    }
+
+    /// Whether a boolean value holds, which NULL doesn't
+    fn holds(self) -> BoolRef<'cg> {
+        let value = BoolRef::from(self.value);
+        match self.null {
+            Some(null) => value & &!null,
+            None => value,
+        }
+    }
+}
+
+/// Whether any of the values is NULL, None if none of them can be
+fn any_null<'a, 'cg: 'a>(nulls: impl IntoIterator<Item = Option<&'a BoolRef<'cg>>>) -> Option<BoolRef<'cg>> {
+    nulls.into_iter().flatten().fold(None, |any, null| Some(match any {
+        Some(any) => any | null,
+        None => null.clone(),
+    }))
 }
 
-fn generate_int_op<'cg>(_cg: &'cg CodeGen, fun: &BuiltIn, left: I64Ref<'cg>, right: I64Ref<'cg>) -> CGValueRef<'cg> {
+/// Whether an expression can be NULL given which of the columns it refers to are (see `typecheck::can_be_null`)
+fn can_be_null_in(expr: &Expr, input_values: &[Option<&Nullable>]) -> bool {
+    can_be_null(expr, &input_values.iter().map(|value| value.is_some_and(|value| value.null.is_some())).collect::<Vec<_>>())
+}
+
+/// An operand of `&` and `|` on booleans in three-valued logic: for `&` whether it can still be true (it
+/// is true or NULL), for `|` whether it is true (and not NULL). Combining these with `&`/`|` gives a
+/// result that is right unless the operands didn't decide it and one of them is NULL (see `logical_null`).
+fn logical_operand<'cg>(fun: &BuiltIn, value: BoolRef<'cg>, null: Option<&BoolRef<'cg>>) -> BoolRef<'cg> {
+    match (fun, null) {
+        (_, None) => value,
+        (BuiltIn::And, Some(null)) => value | null,
+        (_, Some(null)) => value & &!null.clone(),
+    }
+}
+
+/// Whether the result of `&`/`|` (combined from `logical_operand`s) is NULL, if any of the operands is
+fn logical_null<'cg>(fun: &BuiltIn, result: &BoolRef<'cg>, any_null: &BoolRef<'cg>) -> BoolRef<'cg> {
+    match fun {
+        BuiltIn::And => result.clone() & any_null,
+        _ => !result.clone() & any_null,
+    }
+}
+
+/// `&`/`|` on booleans without any branches, all operands are evaluated
+fn combine_logical<'cg>(fun: &BuiltIn, operands: Vec<Nullable<'cg>>) -> Nullable<'cg> {
+    let mut result: Option<BoolRef<'cg>> = None;
+    let mut nulls = Vec::new();
+    for Nullable { value, null, .. } in operands {
+        let operand = logical_operand(fun, BoolRef::from(value), null.as_ref());
+        result = Some(match result {
+            Some(result) if *fun == BuiltIn::And => result & &operand,
+            Some(result) => result | &operand,
+            None => operand,
+        });
+        nulls.extend(null);
+    }
+    let result = result.expect("`&`/`|` has operands");
+    let null = any_null(nulls.iter().map(Some)).map(|any_null| logical_null(fun, &result, &any_null));
+    Nullable::new(result, null)
+}
+
+fn generate_int_op<'cg>(_cg: &'cg CodeGen, fun: &BuiltIn, left: I64Ref<'cg>, right: &I64Ref<'cg>) -> CGValueRef<'cg> {
     match fun {
         BuiltIn::Plus => {
-            (left + &right).into()
+            (left + right).into()
         },
         BuiltIn::Times => {
-            (left * &right).into()
+            (left * right).into()
         },
         BuiltIn::Minus => {
-            (left - &right).into()
+            (left - right).into()
         },
         BuiltIn::Divide => {
-            (left / &right).into()
+            (left / right).into()
         },
         BuiltIn::Rem => {
-            (left % &right).into()
+            (left % right).into()
         },
         BuiltIn::Equal => {
-            left.cg_eq(&right).into()
+            left.cg_eq(right).into()
         },
         BuiltIn::NotEqual => {
-            left.cg_neq(&right).into()
+            left.cg_neq(right).into()
         },
         BuiltIn::LessThan => {
-            left.cg_lt(&right).into()
+            left.cg_lt(right).into()
         },
         BuiltIn::LessThanOrEqual => {
-            left.cg_lte(&right).into()
+            left.cg_lte(right).into()
         },
         BuiltIn::GreaterThan => {
-            left.cg_gt(&right).into()
+            left.cg_gt(right).into()
         },
         BuiltIn::GreaterThanOrEqual => {
-            left.cg_gte(&right).into()
+            left.cg_gte(right).into()
         },
         BuiltIn::And => {
-            (left & &right).into()
+            (left & right).into()
         },
         BuiltIn::Or => {
-            (left | &right).into()
+            (left | right).into()
+        },
+        BuiltIn::In | BuiltIn::Between | BuiltIn::IsNull | BuiltIn::Coalesce | BuiltIn::StartsWith | BuiltIn::Like | BuiltIn::Length => {
+            unreachable!("`{}` isn't applied to two integers at a time", fun.symbol())
         },
     }
 }
 
-fn generate_bool_op<'cg>(_cg: &'cg CodeGen, fun: &BuiltIn, left: BoolRef<'cg>, right: BoolRef<'cg>) -> BoolRef<'cg> {
+fn generate_bool_op<'cg>(_cg: &'cg CodeGen, fun: &BuiltIn, left: BoolRef<'cg>, right: &BoolRef<'cg>) -> BoolRef<'cg> {
     match fun {
         BuiltIn::Equal => {
-            left.cg_eq(&right)
+            left.cg_eq(right)
         },
         BuiltIn::NotEqual => {
-            left.cg_neq(&right)
+            left.cg_neq(right)
         },
         BuiltIn::And => {
-            left & &right
+            left & right
         },
         BuiltIn::Or => {
-            left | &right
+            left | right
         },
         _ => todo!()
     }
 }
 
-fn generate_code_application<'cg>(cg: &'cg CodeGen, fun: &BuiltIn, args: &[Expr], input_values: &[I64Ref<'cg>]) -> Result<CGValueRef<'cg>, CodeGenError> {
-    let first_variable = &args[0];
+/// Applications that occur more than once in a query (hash-consed by their structure) and the values
+/// they have already been computed into. Values computed inside of a branch are forgotten when the
+/// branch ends since the code after it can't rely on them being computed.
+struct SharedExprs<'q, 'cg> {
+    shared: HashSet<&'q Expr>,
+    values: RefCell<HashMap<&'q Expr, Rc<Nullable<'cg>>>>,
+    // Insertion order so that scopes can remove their values again
+    computed: RefCell<Vec<&'q Expr>>,
+    /// The tables that long `in` lists are looked up in and the string constants, they have to live as
+    /// long as the code
+    constant_data: &'q RefCell<Vec<Box<dyn Any + Send>>>,
+    /// Points to the `StringOperands` of the run if the code operates on strings
+    string_operands: Option<UntypedPtrRef<'cg>>,
+}
+
+impl<'q, 'cg> SharedExprs<'q, 'cg> {
+    fn new(exprs: impl IntoIterator<Item = &'q Expr>, constant_data: &'q RefCell<Vec<Box<dyn Any + Send>>>, string_operands: Option<UntypedPtrRef<'cg>>) -> Self {
+        fn count<'q>(expr: &'q Expr, counts: &mut HashMap<&'q Expr, usize>) {
+            if let Expr::Application(_, args) = expr {
+                let n = counts.entry(expr).or_insert(0);
+                *n += 1;
+                // The operands of a repeated application are only computed once anyway
+                if *n == 1 {
+                    args.iter().for_each(|arg| count(arg, counts));
+                }
+            }
+        }
+        let mut counts = HashMap::new();
+        exprs.into_iter().for_each(|expr| count(expr, &mut counts));
+        SharedExprs {
+            shared: counts.into_iter().filter(|(_, n)| *n > 1).map(|(expr, _)| expr).collect(),
+            values: RefCell::new(HashMap::new()),
+            computed: RefCell::new(Vec::new()),
+            constant_data,
+            string_operands,
+        }
+    }
+
+    fn get(&self, expr: &Expr) -> Option<Rc<Nullable<'cg>>> {
+        self.values.borrow().get(expr).cloned()
+    }
+
+    /// Remembers the value if the expression is used more than once
+    fn insert<'a>(&self, expr: &'q Expr, value: Nullable<'cg>) -> Operand<'a, 'cg> {
+        if !self.shared.contains(expr) {
+            return Operand::Owned(value);
+        }
+        let value = Rc::new(value);
+        self.values.borrow_mut().insert(expr, value.clone());
+        self.computed.borrow_mut().push(expr);
+        Operand::Cached(value)
+    }
+
+    /// Generates code that is only executed conditionally, e.g. the body of an if
+    fn scope<R>(&self, generate: impl FnOnce() -> R) -> R {
+        let len = self.computed.borrow().len();
+        let result = generate();
+        let removed = self.computed.borrow_mut().split_off(len);
+        let mut values = self.values.borrow_mut();
+        removed.into_iter().for_each(|expr| { values.remove(expr); });
+        result
+    }
+
+    /// Keeps a lookup table alive as long as the code and gives a pointer to its values
+    fn add_lookup_table(&self, values: Vec<i64>) -> *const i64 {
+        let values = values.into_boxed_slice();
+        let ptr = values.as_ptr();
+        self.constant_data.borrow_mut().push(Box::new(values));
+        ptr
+    }
+
+    /// Like `add_lookup_table` for the bytes of a string
+    fn add_string(&self, string: &Arc<str>) -> *const u8 {
+        self.constant_data.borrow_mut().push(Box::new(string.clone()));
+        string.as_ptr()
+    }
+}
+
+/// An operand that was either computed for a single use or is shared with other readers (columns
+/// and common subexpressions). Shared operands are only cloned when they are modified.
+enum Operand<'a, 'cg> {
+    Owned(Nullable<'cg>),
+    Borrowed(&'a Nullable<'cg>),
+    Cached(Rc<Nullable<'cg>>),
+}
+
+impl<'cg> Operand<'_, 'cg> {
+    fn get(&self) -> &Nullable<'cg> {
+        match self {
+            Operand::Owned(value) => value,
+            Operand::Borrowed(value) => value,
+            Operand::Cached(value) => value,
+        }
+    }
+
+    fn into_owned(self) -> Nullable<'cg> {
+        match self {
+            Operand::Owned(value) => value,
+            Operand::Borrowed(value) => value.clone(),
+            Operand::Cached(value) => value.deref().clone(),
+        }
+    }
+}
+
+/// Evaluates the operands of a boolean `&`/`|` one after another and skips the remaining ones as
+/// soon as the result is known. NULL operands don't decide the result, so they are only remembered.
+fn generate_short_circuit<'q, 'a, 'cg>(cg: &'cg CodeGen, fun: &BuiltIn, args: &'q [Expr], input_values: &[Option<&'a Nullable<'cg>>], column_types: &[DataType], options: &CodeGenOptions, shared: &SharedExprs<'q, 'cg>) -> Result<Nullable<'cg>, CodeGenError> {
+    #[allow(clippy::too_many_arguments)]
+    fn generate_operand_into<'q, 'cg>(cg: &'cg CodeGen, result: &BoolRef<'cg>, any_null: Option<&BoolRef<'cg>>, fun: &BuiltIn, arg: &'q Expr, input_values: &[Option<&Nullable<'cg>>], column_types: &[DataType], options: &CodeGenOptions, shared: &SharedExprs<'q, 'cg>) -> Result<(), CodeGenError> {
+        let Nullable { value, null, .. } = generate_code_inner(cg, arg, input_values, column_types, options, shared)?;
+        result.set(logical_operand(fun, BoolRef::from(value), null.as_ref()));
+        if let (Some(any_null), Some(null)) = (any_null, null) {
+            any_null.set(null | any_null);
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn generate_rest<'q, 'a, 'cg>(cg: &'cg CodeGen, result: &BoolRef<'cg>, any_null: Option<&BoolRef<'cg>>, fun: &BuiltIn, args: &'q [Expr], input_values: &[Option<&'a Nullable<'cg>>], column_types: &[DataType], options: &CodeGenOptions, shared: &SharedExprs<'q, 'cg>) -> Result<(), CodeGenError> {
+        let Some((next, rest)) = args.split_first() else {
+            return Ok(());
+        };
+        let condition = match fun {
+            BuiltIn::And => result.clone(),
+            _ => !result.clone(),
+        };
+        cg.gen_if(condition, || shared.scope(|| {
+            generate_operand_into(cg, result, any_null, fun, next, input_values, column_types, options, shared)?;
+            generate_rest(cg, result, any_null, fun, rest, input_values, column_types, options, shared)
+        }))
+    }
+
+    let result = cg.new_bool_var(false);
+    let any_null = args.iter().any(|arg| can_be_null_in(arg, input_values)).then(|| cg.new_bool_var(false));
+    generate_operand_into(cg, &result, any_null.as_ref(), fun, &args[0], input_values, column_types, options, shared)?;
+    generate_rest(cg, &result, any_null.as_ref(), fun, &args[1..], input_values, column_types, options, shared)?;
+    let null = any_null.map(|any_null| logical_null(fun, &result, &any_null));
+    Ok(Nullable::new(result, null))
+}
 
-    let mut cur = match first_variable {
-        Expr::Variable(n) => {
-            input_values[*n].clone().into()
+/// Evaluates the operands one after another until one of them isn't NULL
+fn generate_coalesce<'q, 'cg>(cg: &'cg CodeGen, args: &'q [Expr], input_values: &[Option<&Nullable<'cg>>], column_types: &[DataType], options: &CodeGenOptions, shared: &SharedExprs<'q, 'cg>) -> Result<Nullable<'cg>, CodeGenError> {
+    #[allow(clippy::too_many_arguments)]
+    fn generate_rest<'q, 'cg>(cg: &'cg CodeGen, result: &Nullable<'cg>, null: &BoolRef<'cg>, args: &'q [Expr], input_values: &[Option<&Nullable<'cg>>], column_types: &[DataType], options: &CodeGenOptions, shared: &SharedExprs<'q, 'cg>) -> Result<(), CodeGenError> {
+        let Some((next, rest)) = args.split_first() else {
+            return Ok(());
+        };
+        cg.gen_if(null.clone(), || shared.scope(|| {
+            let next = generate_code_inner(cg, next, input_values, column_types, options, shared)?;
+            result.value.set(&next.value);
+            if let (Some(length), Some(next_length)) = (&result.length, &next.length) {
+                length.set(next_length.deref());
+            }
+            match next.null {
+                Some(next_null) => null.set(next_null),
+                None => null.set(cg.new_bool_const(false)),
+            }
+            generate_rest(cg, result, null, rest, input_values, column_types, options, shared)
+        }))
+    }
+
+    let first = generate_code_inner(cg, &args[0], input_values, column_types, options, shared)?;
+    let Some(null) = first.null.clone() else {
+        return Ok(first);
+    };
+    generate_rest(cg, &first, &null, &args[1..], input_values, column_types, options, shared)?;
+    let null = args.iter().all(|arg| can_be_null_in(arg, input_values)).then_some(null);
+    Ok(Nullable { null, ..first })
+}
+
+/// `in` lists of constants with more values than this are looked up in a table instead of being
+/// compared with one value after another
+const MAX_COMPARE_CHAIN: usize = 8;
+
+/// A perfect hash table for an `in` list has at most this many slots per value
+const MAX_SLOTS_PER_VALUE: usize = 4;
+
+/// Looks for a multiplier that maps each of the (distinct) values to its own slot of a table, the
+/// slot of `x` is `(x * multiplier) % slots` computed on unsigned integers. Slots without a value
+/// hold one that belongs to a different slot, so a lookup only has to compare `x` with its slot.
+fn perfect_hash(values: &[i64]) -> Option<(u64, Vec<i64>)> {
+    // Consecutive values don't need a multiplier, otherwise a few pseudo random odd ones (splitmix64) are tried
+    let mut state = 0u64;
+    let multipliers = std::iter::once(1).chain(std::iter::repeat_with(|| {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        (z ^ (z >> 31)) | 1
+    })).take(64).collect::<Vec<_>>();
+    for slot_count in (1..=MAX_SLOTS_PER_VALUE).map(|k| k * values.len()) {
+        for &multiplier in &multipliers {
+            let slot = |x: i64| ((x as u64).wrapping_mul(multiplier) % slot_count as u64) as usize;
+            let mut slots = vec![None; slot_count];
+            if values.iter().all(|&x| slots[slot(x)].replace(x).is_none()) {
+                return Some((multiplier, slots.into_iter().map(|x| x.unwrap_or(values[0])).collect()));
+            }
+        }
+    }
+    None
+}
+
+/// Short lists and lists that aren't all constants are compared with one value after another without
+/// any branches. Long lists of constants are looked up in a perfect hash table if there is one,
+/// otherwise with a binary search over the sorted values.
+/// If the list contains NULL and no value equals `x`, the result is NULL since the NULL could be `x`.
+fn generate_in<'q, 'cg>(cg: &'cg CodeGen, args: &'q [Expr], input_values: &[Option<&Nullable<'cg>>], column_types: &[DataType], options: &CodeGenOptions, shared: &SharedExprs<'q, 'cg>) -> Result<Nullable<'cg>, CodeGenError> {
+    let value = generate_operand(cg, &args[0], input_values, column_types, options, shared)?;
+    let constants = args[1..].iter().map(|arg| match arg {
+        Expr::Constant(Atom::Num(n)) => Some(*n),
+        _ => None,
+    }).collect::<Option<Vec<_>>>();
+    let mut list = match constants {
+        Some(list) if list.len() > MAX_COMPARE_CHAIN => list,
+        _ => {
+            let mut result: Option<BoolRef<'cg>> = None;
+            let mut list_nulls = Vec::new();
+            for arg in &args[1..] {
+                let operand = generate_operand(cg, arg, input_values, column_types, options, shared)?;
+                let mut equal = match get_type(&args[0], column_types) {
+                    DataType::Str => compare_strings(cg, &BuiltIn::Equal, value.get(), operand.get(), shared),
+                    _ => I64Ref::view(&value.get().value).clone().cg_eq(I64Ref::view(&operand.get().value)),
+                };
+                if let Some(null) = &operand.get().null {
+                    equal = equal & &!null.clone();
+                    list_nulls.push(null.clone());
+                }
+                result = Some(match result {
+                    Some(result) => result | &equal,
+                    None => equal,
+                });
+            }
+            let result = result.expect("`in` has a list of values");
+            let list_null = any_null(list_nulls.iter().map(Some)).map(|list_null| !result.clone() & &list_null);
+            let null = any_null([value.get().null.as_ref(), list_null.as_ref()]);
+            return Ok(Nullable::new(result, null));
         },
-        Expr::Constant(n) => {
-            generate_atom(cg, n)
+    };
+    list.sort_unstable();
+    list.dedup();
+    let Nullable { value, null, .. } = value.into_owned();
+    let value = I64Ref::from(value);
+    let candidate = match perfect_hash(&list) {
+        Some((multiplier, slots)) => {
+            let slot_count = slots.len() as u64;
+            let slots = cg.new_ptr_const(shared.add_lookup_table(slots));
+            let hash = if multiplier == 1 { value.clone() } else { value.clone() * multiplier as i64 };
+            slots.byte_offset(&(hash.urem(slot_count) * 8)).load_widened(DataType::I64)
         },
-        Expr::Application(fun2, args2) => {
-            generate_code_application(cg,&fun2, &args2, input_values)?
+        None => {
+            // Unrolled since the length is known: `base` moves to the last value that is at most `value`
+            let len = list.len();
+            let sorted = cg.new_ptr_const(shared.add_lookup_table(list));
+            let base = cg.new_i64_var(0);
+            let mut remaining = len;
+            while remaining > 1 {
+                let half = remaining / 2;
+                let probe = I64Ref::from(sorted.clone().byte_offset(&((base.clone() + half as i64) * 8)).load_widened(DataType::I64));
+                cg.gen_if::<()>(probe.cg_lte(&value), || {
+                    base.set(base.clone() + half as i64);
+                    Ok(())
+                }).unwrap();
+                remaining -= half;
+            }
+            sorted.byte_offset(&(base * 8)).load_widened(DataType::I64)
+        },
+    };
+    Ok(Nullable::new(I64Ref::from(candidate).cg_eq(&value), null))
+}
+
+/// With constant bounds this is a single comparison: `x` is in the range exactly if `x - low` is at
+/// most `high - low` when both are taken as unsigned integers
+fn generate_between<'q, 'cg>(cg: &'cg CodeGen, args: &'q [Expr], input_values: &[Option<&Nullable<'cg>>], column_types: &[DataType], options: &CodeGenOptions, shared: &SharedExprs<'q, 'cg>) -> Result<Nullable<'cg>, CodeGenError> {
+    let value = generate_operand(cg, &args[0], input_values, column_types, options, shared)?;
+    if let (Expr::Constant(Atom::Num(low)), Expr::Constant(Atom::Num(high))) = (&args[1], &args[2]) {
+        let Nullable { value, null, .. } = value.into_owned();
+        if low > high {
+            return Ok(Nullable::new(cg.new_bool_const(false), null));
+        }
+        let offset = I64Ref::from(value) - *low;
+        return Ok(Nullable::new(offset.cg_ulte(high.wrapping_sub(*low) as u64), null));
+    }
+    let low = generate_operand(cg, &args[1], input_values, column_types, options, shared)?;
+    let high = generate_operand(cg, &args[2], input_values, column_types, options, shared)?;
+    let (value, low, high) = (value.get(), low.get(), high.get());
+    let above_low = Nullable::new(
+        I64Ref::view(&value.value).clone().cg_gte(I64Ref::view(&low.value)),
+        any_null([value.null.as_ref(), low.null.as_ref()]),
+    );
+    let below_high = Nullable::new(
+        I64Ref::view(&value.value).clone().cg_lte(I64Ref::view(&high.value)),
+        any_null([value.null.as_ref(), high.null.as_ref()]),
+    );
+    Ok(combine_logical(&BuiltIn::And, vec![above_low, below_high]))
+}
+
+/// Comparisons, `starts-with` and `like` on two strings. Only whether the lengths of strings that are
+/// compared for equality differ is checked inline, everything else is done by calling into Rust.
+fn generate_string_op<'q, 'cg>(cg: &'cg CodeGen, fun: &BuiltIn, args: &'q [Expr], input_values: &[Option<&Nullable<'cg>>], column_types: &[DataType], options: &CodeGenOptions, shared: &SharedExprs<'q, 'cg>) -> Result<Nullable<'cg>, CodeGenError> {
+    let left = generate_operand(cg, &args[0], input_values, column_types, options, shared)?;
+    let right = generate_operand(cg, &args[1], input_values, column_types, options, shared)?;
+    let (left, right) = (left.get(), right.get());
+    Ok(Nullable::new(compare_strings(cg, fun, left, right, shared), any_null([left.null.as_ref(), right.null.as_ref()])))
+}
+
+/// Whether the code for `expr` calls one of the functions that get `StringOperands`
+fn compares_strings(expr: &Expr, column_types: &[DataType]) -> bool {
+    match expr {
+        Expr::Application(fun, args) => {
+            (!matches!(fun, BuiltIn::Length | BuiltIn::Coalesce) && get_type(&args[0], column_types) == DataType::Str)
+                || args.iter().any(|arg| compares_strings(arg, column_types))
         },
+        _ => false,
+    }
+}
+
+/// Applies `fun` to two strings, ignoring whether they are NULL
+fn compare_strings<'cg>(cg: &'cg CodeGen, fun: &BuiltIn, left: &Nullable<'cg>, right: &Nullable<'cg>, shared: &SharedExprs<'_, 'cg>) -> BoolRef<'cg> {
+    let (left_length, right_length) = (left.length.as_ref().expect("Strings have a length"), right.length.as_ref().expect("Strings have a length"));
+    let operands = shared.string_operands.as_ref().expect("Code that compares strings has `StringOperands`");
+    let call = |func: CodegenCFunctionSignature| {
+        operands.write_to(&left.value);
+        TypedPtrRef::<I64Ref>::from(operands.clone().byte_offset(8)).write(left_length);
+        operands.clone().byte_offset(16).write_to(&right.value);
+        TypedPtrRef::<I64Ref>::from(operands.clone().byte_offset(24)).write(right_length);
+        cg.call_c_function(func, operands.clone()).into_i64()
     };
+    match fun {
+        BuiltIn::Equal | BuiltIn::NotEqual => {
+            let equal = cg.new_bool_var(false);
+            let same_length = (left_length.clone() - right_length).cg_eq(0);
+            cg.gen_if::<()>(same_length, || {
+                equal.set(call(string_compare).cg_eq(0));
+                Ok(())
+            }).unwrap();
+            if *fun == BuiltIn::Equal { equal } else { !equal }
+        },
+        BuiltIn::LessThan => call(string_compare).cg_lt(0),
+        BuiltIn::LessThanOrEqual => call(string_compare).cg_lte(0),
+        BuiltIn::GreaterThan => call(string_compare).cg_gt(0),
+        BuiltIn::GreaterThanOrEqual => call(string_compare).cg_gte(0),
+        BuiltIn::StartsWith => call(string_starts_with).cg_neq(0),
+        BuiltIn::Like => call(string_like).cg_neq(0),
+        _ => unreachable!("`{}` isn't an operation on two strings", fun.symbol()),
+    }
+}
+
+/// Any NULL operand makes the result NULL, except for the operands of `&`, `|`, `in`, `is-null` and `coalesce`
+fn generate_code_application<'q, 'a, 'cg>(cg: &'cg CodeGen, fun: &BuiltIn, args: &'q [Expr], input_values: &[Option<&'a Nullable<'cg>>], column_types: &[DataType], options: &CodeGenOptions, shared: &SharedExprs<'q, 'cg>) -> Result<Nullable<'cg>, CodeGenError> {
+    if !operand_types_match(args, column_types) {
+        return Err(CodeGenError::TypeError(TypeError::new(format!("Operands of `{}` have different types", fun.symbol()), None)));
+    }
+    if options.short_circuit && matches!(fun, BuiltIn::And | BuiltIn::Or) && get_type(&args[0], column_types) == DataType::Bool {
+        return generate_short_circuit(cg, fun, args, input_values, column_types, options, shared);
+    }
+    match fun {
+        BuiltIn::In => return generate_in(cg, args, input_values, column_types, options, shared),
+        BuiltIn::Between => return generate_between(cg, args, input_values, column_types, options, shared),
+        BuiltIn::Coalesce => return generate_coalesce(cg, args, input_values, column_types, options, shared),
+        _ if get_type(&args[0], column_types) == DataType::Str && *fun != BuiltIn::Length => {
+            return generate_string_op(cg, fun, args, input_values, column_types, options, shared);
+        },
+        BuiltIn::Length => {
+            let Nullable { null, length, .. } = generate_operand(cg, &args[0], input_values, column_types, options, shared)?.into_owned();
+            return Ok(Nullable::new(length.expect("Strings have a length"), null));
+        },
+        BuiltIn::IsNull => {
+            let operand = generate_operand(cg, &args[0], input_values, column_types, options, shared)?;
+            let null = match &operand.get().null {
+                Some(null) => null.clone(),
+                None => cg.new_bool_const(false),
+            };
+            return Ok(Nullable::not_null(null));
+        },
+        BuiltIn::And | BuiltIn::Or if get_type(&args[0], column_types) == DataType::Bool => {
+            let operands = args.iter()
+                .map(|arg| generate_code_inner(cg, arg, input_values, column_types, options, shared))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(combine_logical(fun, operands));
+        },
+        _ => {},
+    }
 
+    let Nullable { value: mut cur, mut null, .. } = generate_operand(cg, &args[0], input_values, column_types, options, shared)?.into_owned();
     for arg in args.iter().skip(1) {
-        let next: CGValueRef<'cg> = match arg {
-            Expr::Variable(n) => {
-                input_values[*n].clone().into()
-            },
-            Expr::Constant(n) => {
-                generate_atom(cg, n)
-            },
-            Expr::Application(fun2, args2) => {
-                // Save the current result to the stack 
-                let folded_args = if let Some(fa) = fold_constants(fun2, args2) {
-                    fa
-                } else {
-                    return Err(CodeGenError::TypeError);
-                };
-                generate_code_application(cg, &fun2, &folded_args, input_values)?
-            },
-        };
+        let next = generate_operand(cg, arg, input_values, column_types, options, shared)?;
+        let next = next.get();
+        null = any_null([null.as_ref(), next.null.as_ref()]);
         match cur.data_type {
+            DataType::I64 if null.is_some() && matches!(fun, BuiltIn::Divide | BuiltIn::Rem) => {
+                // A NULL divisor is replaced by 1 so it can't trap
+                let divisor = cg.new_i64_var(1);
+                let divisor_null = next.null.clone().unwrap_or_else(|| cg.new_bool_const(false));
+                cg.gen_if::<()>(!divisor_null, || {
+                    divisor.set(&next.value);
+                    Ok(())
+                }).unwrap();
+                cur = generate_int_op(cg, fun, I64Ref::from(cur), &divisor);
+            },
             DataType::I64 => {
-                let left = I64Ref::from(cur);
-                let right = I64Ref::from(next);
-                cur = generate_int_op(cg, fun, left, right).into();
+                cur = generate_int_op(cg, fun, I64Ref::from(cur), I64Ref::view(&next.value));
             },
             DataType::Bool => {
-                let left = BoolRef::from(cur);
-                let right = BoolRef::from(next);
-                cur = generate_bool_op(cg, fun, left, right).into();
+                cur = generate_bool_op(cg, fun, BoolRef::from(cur), BoolRef::view(&next.value)).into();
             },
             _ => todo!("For the moment only int64s and bools")
         }
     }
-    Ok(cur)
+    Ok(Nullable::new(cur, null))
 }
 
-fn generate_code_inner<'cg>(cg: &'cg CodeGen, expr: &Expr, input_values: &[I64Ref<'cg>]) -> Result<CGValueRef<'cg>, CodeGenError> {
+fn generate_operand<'q, 'a, 'cg>(cg: &'cg CodeGen, expr: &'q Expr, input_values: &[Option<&'a Nullable<'cg>>], column_types: &[DataType], options: &CodeGenOptions, shared: &SharedExprs<'q, 'cg>) -> Result<Operand<'a, 'cg>, CodeGenError> {
     Ok(match expr {
-        Expr::Constant(a) => {
-            return Ok(generate_atom(cg, a))
+        Expr::Constant(Atom::Str(s)) => {
+            let value = cg.new_ptr_const(shared.add_string(s));
+            Operand::Owned(Nullable { length: Some(cg.new_i64_const(s.len() as i64)), ..Nullable::not_null(value) })
+        },
+        Expr::Constant(a) => Operand::Owned(Nullable::not_null(generate_atom(cg, a))),
+        Expr::Variable(n) => Operand::Borrowed(input_values[*n].expect("Referenced column has not been loaded")),
+        Expr::Column(name) => {
+            return Err(CodeGenError::UnresolvedColumn(name.clone()));
         },
-        Expr::Variable(n) => {
-            input_values[*n].clone().into()
+        Expr::Joined(side, column) => {
+            return Err(CodeGenError::UnresolvedColumn(side.qualify(column)));
+        },
+        Expr::Parameter(name) => {
+            return Err(CodeGenError::UnboundParameter(name.clone()));
         },
         Expr::Application(fun, args) => {
-            let folded_args = if let Some(fa) = fold_constants(fun, args) {
-                fa
-            } else {
-                return Err(CodeGenError::TypeError);
-            };
-            if folded_args.len() == 1 {
-                match &folded_args[0] {
-                    // TODO: Once we introduce operators that also do something to
-                    //       a single argument, this is no longer correct like this
-                    //       but constant folding will need to be imroved anyway then
-                    Expr::Constant(n) => {
-                        return Err(CodeGenError::Const(n.clone()));
-                    },
-                    Expr::Variable(n) => {
-                        input_values[*n].clone().into()
-                    },
-                    _ => {
-                        generate_code_application(cg, fun, &folded_args, input_values)?
-                    },
-                }
-            } else {
-                generate_code_application(cg, fun, &folded_args, input_values)?
+            if let Some(value) = shared.get(expr) {
+                return Ok(Operand::Cached(value));
             }
+            let value = generate_code_application(cg, fun, args, input_values, column_types, options, shared)?;
+            shared.insert(expr, value)
         },
     })
 }
 
-fn generate_aggregation_code<'cg>(cg: &'cg CodeGen, query: &Query, result: CGValueRef<'cg>, aggregate_values: &[I64Ref<'cg>], result_consumer: CodegenCFunctionSignature){
+/// Gives a closure that processes a row a signature that ties the row's values to the code generator,
+/// which a closure can't do with the types of its arguments alone
+fn row_processor<'cg, F: Fn(&[Option<&Nullable<'cg>>]) -> Result<(), CodeGenError>>(f: F) -> F {
+    f
+}
+
+/// Like `row_processor` for a closure that loads the columns of a row, given a pointer to the row
+fn column_loader<'cg, F: Fn(Option<UntypedPtrRef<'cg>>, &BTreeSet<usize>) -> Vec<Option<Nullable<'cg>>>>(f: F) -> F {
+    f
+}
+
+/// Expressions have to be simplified (see `simplify`) before generating code for them
+fn generate_code_inner<'q, 'a, 'cg>(cg: &'cg CodeGen, expr: &'q Expr, input_values: &[Option<&'a Nullable<'cg>>], column_types: &[DataType], options: &CodeGenOptions, shared: &SharedExprs<'q, 'cg>) -> Result<Nullable<'cg>, CodeGenError> {
+    Ok(generate_operand(cg, expr, input_values, column_types, options, shared)?.into_owned())
+}
+
+/// Passes a result of the interpreter to a result consumer of generated code, which gets booleans as 0 or 1
+pub fn consume_result(result_consumer: CodegenCFunctionSignature, result: Atom) {
+    let value = match result {
+        Atom::Num(n) => n,
+        Atom::Boolean(b) => b as i64,
+        Atom::Null => unreachable!("Results that can be NULL aren't supported (see `check_results_not_null`)"),
+        Atom::Str(_) => unreachable!("`Query::check_types` rejects string results"),
+    };
+    unsafe {
+        result_consumer(ptr::null_mut(), value as *mut u8, ptr::null_mut());
+    }
+}
+
+// Extern helpers for aggregates that we can't (or don't want to) express with stencils
+
+/// The set of `count distinct` for one run of the code, which frees it with `distinct_free`
+unsafe extern "C" fn distinct_new(_: *mut u8, _: *mut u8, _: *mut u8) -> *mut u8 {
+    Box::into_raw(Box::new(HashSet::<i64>::new())) as *mut u8
+}
+
+unsafe extern "C" fn distinct_free(_: *mut u8, set: *mut u8, _: *mut u8) -> *mut u8 {
+    drop(Box::from_raw(set as *mut HashSet<i64>));
+    ptr::null_mut()
+}
+
+unsafe extern "C" fn distinct_insert(_: *mut u8, set: *mut u8, value: *mut u8) -> *mut u8 {
+    let set = &mut *(set as *mut HashSet<i64>);
+    set.insert(value as i64);
+    ptr::null_mut()
+}
+
+unsafe extern "C" fn push_result(_: *mut u8, results: *mut u8, value: *mut u8) -> *mut u8 {
+    let results = &mut *(results as *mut Vec<i64>);
+    results.push(value as i64);
+    ptr::null_mut()
+}
+
+unsafe extern "C" fn distinct_count(_: *mut u8, set: *mut u8, _: *mut u8) -> *mut u8 {
+    let set = &mut *(set as *mut HashSet<i64>);
+    let count = set.len();
+    // Resumable code gets the same set again
+    set.clear();
+    count as *mut u8
+}
+
+unsafe extern "C" fn isqrt_extern(_: *mut u8, value: *mut u8, _: *mut u8) -> *mut u8 {
+    isqrt(value as i64) as *mut u8
+}
+
+/// The two strings of an operation on strings. The functions only get two arguments, so the generated
+/// code writes the pointers and the lengths of both strings to this before it calls them. Every run
+/// of the code gets one of its own, since partial code runs on several threads at once.
+#[repr(C)]
+struct StringOperands {
+    // The generated code accesses the fields at their fixed offsets
+    left_ptr: *const u8,
+    left_len: usize,
+    right_ptr: *const u8,
+    right_len: usize,
+}
+
+/// The operands for one run of the code, which frees them with `string_operands_free`
+unsafe extern "C" fn string_operands_new(_: *mut u8, _: *mut u8, _: *mut u8) -> *mut u8 {
+    Box::into_raw(Box::new(StringOperands { left_ptr: ptr::null(), left_len: 0, right_ptr: ptr::null(), right_len: 0 })) as *mut u8
+}
+
+unsafe extern "C" fn string_operands_free(_: *mut u8, operands: *mut u8, _: *mut u8) -> *mut u8 {
+    drop(Box::from_raw(operands as *mut StringOperands));
+    ptr::null_mut()
+}
+
+unsafe fn string_operands<'a>(operands: *mut u8) -> (&'a str, &'a str) {
+    let operands = &*(operands as *const StringOperands);
+    // Tables and literals only contain whole strings
+    (
+        str::from_utf8_unchecked(slice::from_raw_parts(operands.left_ptr, operands.left_len)),
+        str::from_utf8_unchecked(slice::from_raw_parts(operands.right_ptr, operands.right_len)),
+    )
+}
+
+/// -1, 0 or 1 if the left string is ordered before, the same as or after the right one
+unsafe extern "C" fn string_compare(_: *mut u8, operands: *mut u8, _: *mut u8) -> *mut u8 {
+    let (left, right) = string_operands(operands);
+    left.cmp(right) as i64 as *mut u8
+}
+
+unsafe extern "C" fn string_starts_with(_: *mut u8, operands: *mut u8, _: *mut u8) -> *mut u8 {
+    let (left, right) = string_operands(operands);
+    left.starts_with(right) as usize as *mut u8
+}
+
+unsafe extern "C" fn string_like(_: *mut u8, operands: *mut u8, _: *mut u8) -> *mut u8 {
+    let (left, right) = string_operands(operands);
+    like(left, right) as usize as *mut u8
+}
+
+unsafe extern "C" fn flush_output(_: *mut u8, buffer: *mut u8, len: *mut u8) -> *mut u8 {
+    let buffer = &mut *(buffer as *mut OutputBuffer);
+    (buffer.consumer)(&buffer.storage[..len as usize]);
+    ptr::null_mut()
+}
+
+/// What the generated code needs for `order by`: it writes the key and the value of a result to
+/// the first two fields before it adds the result and reads the sorted results once the scan is done
+#[repr(C)]
+struct TopKState {
+    // The generated code accesses the first four fields at their fixed offsets
+    key: i64,
+    value: i64,
+    sorted_ptr: *const i64,
+    sorted_len: i64,
+    sorted: Vec<i64>,
+    top_k: TopK,
+}
+
+// `sorted_ptr` points into `sorted`, which moves together with it
+unsafe impl Send for TopKState {}
+
+unsafe extern "C" fn top_k_add(_: *mut u8, state: *mut u8, _: *mut u8) -> *mut u8 {
+    let state = &mut *(state as *mut TopKState);
+    state.top_k.add(state.key, state.value);
+    ptr::null_mut()
+}
+
+unsafe extern "C" fn top_k_sort(_: *mut u8, state: *mut u8, _: *mut u8) -> *mut u8 {
+    let state = &mut *(state as *mut TopKState);
+    // Taking the results resets the state so that the code can be called again
+    state.sorted = state.top_k.take_sorted();
+    state.sorted_ptr = state.sorted.as_ptr();
+    state.sorted_len = state.sorted.len() as i64;
+    ptr::null_mut()
+}
+
+/// The hash table of a join. The build loop writes the key and the row of the joined table to the
+/// first two fields before it inserts the row. The probe loop writes the key before it looks up the
+/// rows with that key, which it then reads from the next two fields.
+#[repr(C)]
+struct JoinState {
+    // The generated code accesses the first four fields at their fixed offsets
+    key: i64,
+    row: i64,
+    matches_ptr: *const i64,
+    matches_len: i64,
+    rows: HashMap<i64, Vec<i64>>,
+}
+
+// `matches_ptr` points into `rows`, which moves together with it
+unsafe impl Send for JoinState {}
+
+unsafe extern "C" fn join_insert(_: *mut u8, state: *mut u8, _: *mut u8) -> *mut u8 {
+    let state = &mut *(state as *mut JoinState);
+    state.rows.entry(state.key).or_default().push(state.row);
+    ptr::null_mut()
+}
+
+unsafe extern "C" fn join_lookup(_: *mut u8, state: *mut u8, _: *mut u8) -> *mut u8 {
+    let state = &mut *(state as *mut JoinState);
+    let matches = state.rows.get(&state.key).map_or(&[][..], Vec::as_slice);
+    state.matches_ptr = matches.as_ptr();
+    state.matches_len = matches.len() as i64;
+    ptr::null_mut()
+}
+
+unsafe extern "C" fn join_clear(_: *mut u8, state: *mut u8, _: *mut u8) -> *mut u8 {
+    let state = &mut *(state as *mut JoinState);
+    // Frees the hash table once the probe is done, the next run builds a new one
+    state.rows = HashMap::new();
+    ptr::null_mut()
+}
+
+/// Where code generated by `generate_batched_code` writes its results to. The consumer gets them a
+/// slice at a time: whenever the buffer is full and once more when the code is done.
+#[repr(C)]
+pub struct OutputBuffer<'a> {
+    // The generated code reads the first two fields at their fixed offsets
+    values: *mut i64,
+    capacity: i64,
+    storage: Vec<i64>,
+    consumer: &'a mut dyn FnMut(&[i64]),
+}
+
+impl<'a> OutputBuffer<'a> {
+    pub fn new(capacity: usize, consumer: &'a mut dyn FnMut(&[i64])) -> Self {
+        let mut storage = vec![0; capacity.max(1)];
+        OutputBuffer { values: storage.as_mut_ptr(), capacity: storage.len() as i64, storage, consumer }
+    }
+}
+
+/// Where the generated code puts its results
+enum ResultSink<'cg> {
+    /// Calls the result consumer for every result
+    Consumer(CodegenCFunctionSignature),
+    /// Writes the results into an `OutputBuffer`, `len` of them are in it at the moment
+    Buffer { buffer: UntypedPtrRef<'cg>, len: I64Ref<'cg> },
+}
+
+impl<'cg> ResultSink<'cg> {
+    fn emit(&self, cg: &'cg CodeGen, value: UntypedPtrRef<'cg>) {
+        match self {
+            ResultSink::Consumer(result_consumer) => {
+                cg.call_c_function(*result_consumer, value);
+            },
+            ResultSink::Buffer { buffer, len } => {
+                let values = UntypedPtrRef::from(buffer.load_from(DataType::Ptr));
+                let value: CGValueRef = value.into();
+                TypedPtrRef::<I64Ref>::from(values.byte_offset(&(len.clone() * 8))).write(&I64Ref::from(value));
+                len.set(len.clone() + 1);
+                let capacity = I64Ref::from(buffer.clone().byte_offset(8).load_widened(DataType::I64));
+                cg.gen_if::<()>(len.clone().cg_eq(&capacity), || {
+                    self.flush(cg);
+                    Ok(())
+                }).unwrap();
+            },
+        }
+    }
+
+    /// Passes the results in the buffer on
+    fn flush(&self, cg: &'cg CodeGen) {
+        if let ResultSink::Buffer { buffer, len } = self {
+            cg.call_c_function_2(flush_output, buffer.clone(), UntypedPtrRef::from(len.clone().into_base()));
+            len.set(cg.new_i64_const(0));
+        }
+    }
+}
+
+/// Makes sure a divisor is at least 1 so that empty inputs don't crash the generated code
+fn clamp_divisor<'cg>(cg: &'cg CodeGen, divisor: &I64Ref<'cg>) {
+    cg.gen_if::<()>(divisor.clone().cg_lt(1), || {
+        divisor.set(cg.new_i64_const(1));
+        Ok(())
+    }).unwrap();
+}
+
+/// `external_state` points to the state that doesn't live in the generated code: the set of `count distinct`
+/// or the `Vec` partial code collects the results of queries without an aggregate in
+fn generate_aggregation_code<'cg>(cg: &'cg CodeGen, query: &Query, result: CGValueRef<'cg>, aggregate_values: &[I64Ref<'cg>], external_state: Option<&UntypedPtrRef<'cg>>, sink: &ResultSink<'cg>){
     match query.aggregate {
         Some(AggregateFunc::Sum) => {
             let aggregate_value = &aggregate_values[0];
@@ -363,72 +954,766 @@ fn generate_aggregation_code<'cg>(cg: &'cg CodeGen, query: &Query, result: CGVal
                 Ok(())
             }).unwrap();
         },
-        None => {
-            cg.call_c_function(result_consumer, UntypedPtrRef::from(result));
+        Some(AggregateFunc::Count) | Some(AggregateFunc::CountStar) => {
+            // Without NULLs the value itself doesn't matter
+            let aggregate_count = &aggregate_values[0];
+            aggregate_count.set(aggregate_count.clone() + 1);
+        },
+        Some(AggregateFunc::CountDistinct) => {
+            let set = external_state.unwrap().clone();
+            cg.call_c_function_2(distinct_insert, set, UntypedPtrRef::from(result));
+        },
+        Some(AggregateFunc::Any) => {
+            let aggregate_value = &aggregate_values[0];
+            cg.gen_if::<()>(BoolRef::from(result), || {
+                aggregate_value.set(cg.new_i64_const(1));
+                Ok(())
+            }).unwrap();
+        },
+        Some(AggregateFunc::All) => {
+            let aggregate_value = &aggregate_values[0];
+            cg.gen_if::<()>(BoolRef::from(result).cg_eq(false), || {
+                aggregate_value.set(cg.new_i64_const(0));
+                Ok(())
+            }).unwrap();
+        },
+        Some(AggregateFunc::VarPop) | Some(AggregateFunc::VarSamp)
+        | Some(AggregateFunc::StddevPop) | Some(AggregateFunc::StddevSamp) => {
+            let aggregate_count = &aggregate_values[0];
+            let aggregate_sum = &aggregate_values[1];
+            let aggregate_sum_squares = &aggregate_values[2];
+            let result = I64Ref::from(result);
+            let square = result.clone() * &result;
+            aggregate_count.set(aggregate_count.clone() + 1);
+            aggregate_sum.set(result + aggregate_sum);
+            aggregate_sum_squares.set(square + aggregate_sum_squares);
+        },
+        None => match external_state {
+            Some(results) => {
+                cg.call_c_function_2(push_result, results.clone(), UntypedPtrRef::from(result));
+            },
+            None => {
+                sink.emit(cg, UntypedPtrRef::from(result));
+            },
         },
     }
 }
 
-pub fn generate_code(query: &Query, columns: usize, result_consumer: CodegenCFunctionSignature) -> Result<GeneratedCode, CodeGenError> {
+/// Computes the final aggregate value after the scan and passes it to the sink
+fn generate_aggregation_result<'cg>(cg: &'cg CodeGen, query: &Query, mut aggregate_values: Vec<I64Ref<'cg>>, external_state: Option<&UntypedPtrRef<'cg>>, sink: &ResultSink<'cg>) {
+    match query.aggregate {
+        Some(AggregateFunc::Avg) => {
+            let aggregate_count = aggregate_values.pop().unwrap();
+            let aggregate_value = aggregate_values.pop().unwrap();
+            clamp_divisor(cg, &aggregate_count);
+            let avg = aggregate_value / &aggregate_count;
+            sink.emit(cg, UntypedPtrRef::from(avg.into_base()));
+        },
+        Some(AggregateFunc::CountDistinct) => {
+            let set = external_state.unwrap().clone();
+            let count = cg.call_c_function(distinct_count, set);
+            sink.emit(cg, count);
+        },
+        Some(f @ AggregateFunc::VarPop) | Some(f @ AggregateFunc::VarSamp)
+        | Some(f @ AggregateFunc::StddevPop) | Some(f @ AggregateFunc::StddevSamp) => {
+            // Same integer arithmetic as query::finish_variance
+            let aggregate_sum_squares = aggregate_values.pop().unwrap();
+            let aggregate_sum = aggregate_values.pop().unwrap();
+            let aggregate_count = aggregate_values.pop().unwrap();
+            let n = aggregate_count.clone();
+            clamp_divisor(cg, &n);
+            let m2 = aggregate_sum_squares - &((aggregate_sum.clone() * &aggregate_sum) / &n);
+            let variance = match f {
+                AggregateFunc::VarPop | AggregateFunc::StddevPop => m2 / &n,
+                _ => {
+                    let n_minus_one = aggregate_count - 1;
+                    clamp_divisor(cg, &n_minus_one);
+                    m2 / &n_minus_one
+                }
+            };
+            let result = match f {
+                AggregateFunc::StddevPop | AggregateFunc::StddevSamp => {
+                    cg.call_c_function(isqrt_extern, UntypedPtrRef::from(variance.into_base()))
+                },
+                _ => UntypedPtrRef::from(variance.into_base()),
+            };
+            sink.emit(cg, result);
+        },
+        Some(_) => {
+            sink.emit(cg, UntypedPtrRef::from(aggregate_values.pop().unwrap().into_base()));
+        },
+        None => {},
+    }
+}
+
+/// In which order the operands of boolean `&`/`|` in the filter are evaluated
+#[derive(Debug, Clone, PartialEq)]
+pub enum OperandOrder {
+    /// Just like they are written in the query
+    AsWritten,
+    /// Cheapest first (see `estimated_cost`)
+    Cost,
+    /// Operands that are cheap and likely decide the result first. Contains the observed fraction
+    /// of rows for which each operand of the filter's top level `&`/`|` is true (see
+    /// `query::observe_selectivity`), nested operations are ordered by cost.
+    Selectivity(Vec<f64>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeGenOptions {
+    /// Compile boolean `&`/`|` to branches that skip the remaining operands once the result is known.
+    /// Otherwise all operands are evaluated and combined without any branches, so an operand that
+    /// traps (a division by zero) traps even for rows where an earlier operand decides the result.
+    /// Only turn it off for queries whose operands can't trap.
+    pub short_circuit: bool,
+    /// Reordering only makes sense with short circuiting. Note that it can make a query trap
+    /// (e.g. divide by zero) if the operand that guarded against it is moved back.
+    pub operand_order: OperandOrder,
+}
+
+impl Default for CodeGenOptions {
+    fn default() -> Self {
+        CodeGenOptions { short_circuit: true, operand_order: OperandOrder::AsWritten }
+    }
+}
+
+/// A rough estimate of how expensive evaluating an expression is
+pub fn estimated_cost(expr: &Expr) -> usize {
+    match expr {
+        Expr::Constant(_) => 0,
+        Expr::Variable(_) | Expr::Column(_) | Expr::Joined(..) | Expr::Parameter(_) => 1,
+        Expr::Application(fun, args) => {
+            let op_cost = match fun {
+                BuiltIn::Divide | BuiltIn::Rem => 8,
+                _ => 1,
+            };
+            args.iter().map(estimated_cost).sum::<usize>() + op_cost * (args.len() - 1)
+        },
+    }
+}
+
+/// Whether evaluating `expr` can trap, i.e. whether it divides by something that isn't a constant
+pub fn can_trap(expr: &Expr) -> bool {
+    match expr {
+        Expr::Application(BuiltIn::Divide | BuiltIn::Rem, args) if args[1..].iter().any(|arg| !matches!(arg, Expr::Constant(_))) => true,
+        Expr::Application(_, args) => args.iter().any(can_trap),
+        Expr::Joined(_, expr) => can_trap(expr),
+        _ => false,
+    }
+}
+
+/// Operands that can trap are barriers that no other operand is moved across, so the guard of a
+/// division stays in front of it
+fn reorder_operands(expr: &Expr, order: &OperandOrder, column_types: &[DataType]) -> Expr {
+    let Expr::Application(fun, args) = expr else {
+        return expr.clone();
+    };
+    if *order == OperandOrder::AsWritten {
+        return expr.clone();
+    }
+    let args = args.iter().map(|arg| reorder_operands(arg, &OperandOrder::Cost, column_types)).collect::<Vec<_>>();
+    let is_bool_op = matches!(fun, BuiltIn::And | BuiltIn::Or) && get_type(&args[0], column_types) == DataType::Bool;
+    if !is_bool_op {
+        return Expr::Application(*fun, args);
+    }
+    let ranks = match order {
+        OperandOrder::Selectivity(selectivity) if selectivity.len() == args.len() => {
+            // The chance that an operand decides the result, cheap and decisive operands come first
+            let rank = |arg: &Expr, true_fraction: f64| {
+                let decides = if *fun == BuiltIn::And { 1.0 - true_fraction } else { true_fraction };
+                (estimated_cost(arg) + 1) as f64 / decides.max(f64::EPSILON)
+            };
+            args.iter().zip(selectivity).map(|(arg, s)| rank(arg, *s)).collect::<Vec<_>>()
+        },
+        _ => args.iter().map(|arg| estimated_cost(arg) as f64).collect(),
+    };
+    let mut ranked = ranks.into_iter().zip(args).collect::<Vec<_>>();
+    for operands in ranked.split_mut(|(_, arg)| can_trap(arg)) {
+        operands.sort_by(|(l, _), (r, _)| l.total_cmp(r));
+    }
+    let args = ranked.into_iter().map(|(_, arg)| arg).collect();
+    Expr::Application(*fun, args)
+}
+
+/// The number of arguments of generated code with the data of a table that come before the validity
+/// bitmaps: the buffers and the offsets and bytes of every string column (see `Table::call_args`)
+fn value_args(schema: &Schema, layout: Layout) -> usize {
+    let buffers = match layout {
+        Layout::RowMajor => 1,
+        Layout::Columnar => schema.column_count(),
+    };
+    buffers + 2 * schema.columns().iter().filter(|c| c.data_type == DataType::Str).count()
+}
+
+/// The types of the arguments of generated code for the data of a table (see `Table::call_args`),
+/// which are followed by the number of rows
+fn data_arg_types(schema: &Schema, layout: Layout) -> Vec<DataType> {
+    let nullable = schema.nullable_columns().into_iter().filter(|&nullable| nullable).count();
+    let mut types = vec![DataType::Ptr; value_args(schema, layout) + nullable];
+    if nullable > 0 {
+        types.push(DataType::I64);
+    }
+    types
+}
 
+/// Loads the string of column `j` in `row`, a pointer to its first byte and its length.
+/// `first_arg` is the first argument that belongs to the table.
+fn load_string<'cg>(cg: &'cg CodeGen, schema: &Schema, layout: Layout, first_arg: usize, j: usize, row: &I64Ref<'cg>) -> (CGValueRef<'cg>, I64Ref<'cg>) {
+    let buffers = match layout {
+        Layout::RowMajor => 1,
+        Layout::Columnar => schema.column_count(),
+    };
+    let offsets_arg = first_arg + buffers + 2 * schema.columns()[..j].iter().filter(|c| c.data_type == DataType::Str).count();
+    let offsets = UntypedPtrRef::from(cg.get_arg(offsets_arg)).byte_offset(&(row.clone() * 8));
+    let start = I64Ref::from(offsets.clone().load_widened(DataType::I64));
+    let end = I64Ref::from(offsets.byte_offset(8).load_widened(DataType::I64));
+    let bytes = UntypedPtrRef::from(cg.get_arg(offsets_arg + 1)).byte_offset(&start);
+    (bytes.into(), end - &start)
+}
+
+/// Loads whether column `j` is NULL in `row` from its validity bitmap, None if the column isn't nullable.
+/// `first_arg` is the first argument that belongs to the table.
+fn load_null<'cg>(cg: &'cg CodeGen, schema: &Schema, layout: Layout, first_arg: usize, j: usize, row: &I64Ref<'cg>) -> Option<BoolRef<'cg>> {
+    let nullable = schema.nullable_columns();
+    if !nullable[j] {
+        return None;
+    }
+    let value_args = value_args(schema, layout);
+    let validity = UntypedPtrRef::from(cg.get_arg(first_arg + value_args + nullable[..j].iter().filter(|&&nullable| nullable).count()));
+    let first_bit = I64Ref::from(cg.get_arg(first_arg + value_args + nullable.iter().filter(|&&nullable| nullable).count()));
+    let bit = first_bit + row;
+    let word_index = bit.clone() >> 6;
+    let word = I64Ref::from(validity.byte_offset(&(word_index.clone() * 8)).load_widened(DataType::I64));
+    let bit_in_word = bit - &(word_index << 6);
+    // The bit is set for values, so moving it to the sign bit gives a negative number for them
+    Some(((word >> &bit_in_word) << 63).cg_gte(0))
+}
+
+/// Generated code passes on results that aren't aggregated as they are, so they can't be NULL
+pub fn check_results_not_null(query: &Query, schema: &Schema) -> Result<(), CodeGenError> {
+    if query.aggregate.is_none() && can_be_null(&query.expr, &schema.nullable_columns()) {
+        return Err(CodeGenError::Unsupported("Results of generated code can't be NULL, use `coalesce` to replace NULLs (aggregates skip them)"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub fn generate_code(query: &Query, schema: &Schema, layout: Layout, result_consumer: CodegenCFunctionSignature) -> Result<GeneratedCode, CodeGenError> {
+    generate_code_with_options(query, schema, layout, &CodeGenOptions::default(), result_consumer)
+}
+
+/// What the generated code does with the aggregation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScanMode {
+    /// Starts a new aggregation and passes its result to the result consumer
+    Complete,
+    /// Continues the aggregation of the state it gets and passes the result to the result consumer
+    Resume,
+    /// Continues the aggregation of the state it gets and writes the state back instead of finishing it
+    Partial,
+}
+
+#[cfg(test)]
+pub fn generate_code_with_options(query: &Query, schema: &Schema, layout: Layout, options: &CodeGenOptions, result_consumer: CodegenCFunctionSignature) -> Result<GeneratedCode, CodeGenError> {
+    generate_scan(query, schema, layout, options, Some(result_consumer), ScanMode::Complete, None)
+}
+
+/// Like `generate_code_with_options`, but instead of calling a result consumer for every result the code
+/// writes them into an `OutputBuffer`, which it takes a pointer to as its last argument
+pub fn generate_batched_code(query: &Query, schema: &Schema, layout: Layout, options: &CodeGenOptions) -> Result<GeneratedCode, CodeGenError> {
+    generate_scan(query, schema, layout, options, None, ScanMode::Complete, None)
+}
+
+/// Code for a query with a join (see `Query::join`) that writes its results into an `OutputBuffer` like
+/// `generate_batched_code`. The data pointers and the number of rows of the table it joins with come
+/// right after the ones of the table it runs on.
+pub fn generate_join_code(query: &Query, schema: &Schema, layout: Layout, right_schema: &Schema, right_layout: Layout, options: &CodeGenOptions) -> Result<GeneratedCode, CodeGenError> {
+    generate_scan(query, schema, layout, options, None, ScanMode::Complete, Some((right_schema, right_layout)))
+}
+
+/// Like `generate_code_with_options`, but the code continues an aggregation instead of starting a new one.
+/// It takes a pointer to the `AggregateState::values` to start from as its last argument, for
+/// `count distinct` a pointer to the `AggregateState::distinct` set (which is emptied by the code).
+pub fn generate_resumable_code(query: &Query, schema: &Schema, layout: Layout, options: &CodeGenOptions, result_consumer: CodegenCFunctionSignature) -> Result<GeneratedCode, CodeGenError> {
+    generate_scan(query, schema, layout, options, Some(result_consumer), ScanMode::Resume, None)
+}
+
+/// Code for a part of the rows of a parallel scan (see `parallel`). It takes the same arguments as
+/// resumable code, but writes the aggregate state back instead of passing a result on. Queries
+/// without an aggregate get a pointer to a `Vec<i64>` instead, which they add their results to.
+/// The code doesn't have any state of its own, so several threads can run it at once.
+pub fn generate_partial_code(query: &Query, schema: &Schema, layout: Layout, options: &CodeGenOptions) -> Result<GeneratedCode, CodeGenError> {
+    unsafe extern "C" fn no_result_consumer(_: *mut u8, _: *mut u8, _: *mut u8) -> *mut u8 {
+        unreachable!("Partial code doesn't pass results on")
+    }
+    generate_scan(query, schema, layout, options, Some(no_result_consumer), ScanMode::Partial, None)
+}
+
+/// Without a result consumer the results go to an `OutputBuffer`. `right` is the schema and the layout
+/// of the table a query with a join joins with.
+fn generate_scan(query: &Query, schema: &Schema, layout: Layout, options: &CodeGenOptions, result_consumer: Option<CodegenCFunctionSignature>, mode: ScanMode, right: Option<(&Schema, Layout)>) -> Result<GeneratedCode, CodeGenError> {
+    let right = match (&query.join, right) {
+        (Some(_), Some(right)) => Some(right),
+        (Some(_), None) => return Err(CodeGenError::Unsupported("Queries with a join need the table they join with, see `generate_join_code`")),
+        (None, _) => None,
+    };
+    // The columns of the table of a join come after the ones of the table the query runs on
+    let joined_schema = right.map(|(right_schema, _)| schema.joined(right_schema));
+    let full_schema = joined_schema.as_ref().unwrap_or(schema);
+    let left_columns = schema.column_count();
+
+    query.check_types(full_schema, None).map_err(CodeGenError::TypeError)?;
+    check_results_not_null(query, full_schema)?;
+    if mode != ScanMode::Complete && (query.order_by.is_some() || query.limit.is_some() || query.join.is_some()) {
+        return Err(CodeGenError::Unsupported("Queries with a join, `order by` or `limit` can only be compiled for all rows at once"));
+    }
+    if let Some(join) = &query.join {
+        // `Query::bind_join` makes sure of this, otherwise the build and the probe would load columns of the wrong table
+        if join.left_key.referenced_columns().iter().any(|&j| j >= left_columns) || join.right_key.referenced_columns().iter().any(|&j| j < left_columns) {
+            return Err(CodeGenError::Unsupported("Each key of a join must only refer to the columns of its table"));
+        }
+    }
+    // Parameters are handled like additional columns after the ones of the table, their values
+    // are loaded once before the loop instead of for every row
+    let parameters = query.parameters();
+    let mut column_types = full_schema.value_types();
+    column_types.extend(parameters.iter().map(|_| DataType::I64));
+    let nullable_columns = full_schema.nullable_columns();
+    let as_variable = |name: &str| Expr::Variable(full_schema.column_count() + parameters.iter().position(|p| p == name).unwrap());
+    let simplified = |expr: &Expr| simplify(&expr.replace_parameters(&as_variable), &column_types, &nullable_columns).map_err(CodeGenError::Eval);
+    let query = &Query {
+        aggregate: query.aggregate,
+        filter: query.filter.as_ref().map(simplified).transpose()?,
+        expr: simplified(&query.expr)?,
+        join: match &query.join {
+            Some(join) => Some(Join { left_key: simplified(&join.left_key)?, right_key: simplified(&join.right_key)? }),
+            None => None,
+        },
+        order_by: match &query.order_by {
+            Some(order_by) => Some(OrderBy { expr: simplified(&order_by.expr)?, descending: order_by.descending }),
+            None => None,
+        },
+        limit: query.limit,
+    };
+    let filter = query.filter.as_ref().map(|filter| reorder_operands(filter, &options.operand_order, &column_types));
+
+    // Row major code gets a pointer to the table, columnar code one pointer per column, both followed by
+    // the validity bitmaps (see `Table::call_args`). The number of rows comes next, then the same for the
+    // table of a join, a pointer to the values of the parameters (if there are any) and the aggregate
+    // state for resumable code. Code without a result consumer gets its output buffer last.
+    let mut arg_types = data_arg_types(schema, layout);
+    let data_args = arg_types.len();
     // TODO: I64 doesn't make sense for data length. Use U64 as soon as the wrapper is implemented
-    let cg = CodeGen::new(&[DataType::Ptr, DataType::I64]);
+    arg_types.push(DataType::I64);
+    let right_data_args = right.map(|(right_schema, right_layout)| {
+        let right_arg_types = data_arg_types(right_schema, right_layout);
+        let right_data_args = right_arg_types.len();
+        arg_types.extend(right_arg_types);
+        arg_types.push(DataType::I64);
+        right_data_args
+    });
+    // Where the arguments after the ones of the tables start
+    let table_args = arg_types.len();
+    if !parameters.is_empty() {
+        arg_types.push(DataType::Ptr);
+    }
+    if mode != ScanMode::Complete {
+        arg_types.push(DataType::Ptr);
+    }
+    if result_consumer.is_none() {
+        arg_types.push(DataType::Ptr);
+    }
+    let cg = CodeGen::new(&arg_types);
+    let sink = match result_consumer {
+        Some(result_consumer) => ResultSink::Consumer(result_consumer),
+        None => ResultSink::Buffer { buffer: UntypedPtrRef::from(cg.get_arg(arg_types.len() - 1)), len: cg.new_i64_var(0) },
+    };
 
-    let data_ptr = TypedPtrRef::<I64Ref>::from(cg.get_arg(0));
+    let parameter_values = if parameters.is_empty() {
+        Vec::new()
+    } else {
+        let values_ptr = UntypedPtrRef::from(cg.get_arg(table_args));
+        (0..parameters.len()).map(|k| Nullable::not_null(values_ptr.clone().byte_offset(8 * k as i64).load_widened(DataType::I64))).collect()
+    };
+
+    let referenced_columns = query.referenced_columns().into_iter().filter(|&j| j < full_schema.column_count()).collect::<BTreeSet<_>>();
+    let data_ptrs = match layout {
+        Layout::RowMajor => vec![Some(UntypedPtrRef::from(cg.get_arg(0)))],
+        Layout::Columnar => (0..schema.column_count()).map(|j| {
+            referenced_columns.contains(&j).then(|| UntypedPtrRef::from(cg.get_arg(j)))
+        }).collect(),
+    };
+    let right_data_ptrs = match right {
+        Some((_, Layout::RowMajor)) => vec![Some(UntypedPtrRef::from(cg.get_arg(data_args + 1)))],
+        Some((right_schema, Layout::Columnar)) => (0..right_schema.column_count()).map(|j| {
+            referenced_columns.contains(&(left_columns + j)).then(|| UntypedPtrRef::from(cg.get_arg(data_args + 1 + j)))
+        }).collect(),
+        None => Vec::new(),
+    };
     let i = cg.new_i64_var(0);
+    // The row of the table of a join that is inserted into the hash table or combined with row `i`
+    let right_row = right.map(|_| cg.new_i64_var(0));
 
 
-    let mut aggregate_values: Vec<I64Ref> = match query.aggregate {
-        Some(AggregateFunc::Avg) => vec![cg.new_i64_var(0), cg.new_i64_var(0)],
-        Some(AggregateFunc::Prod) => vec![cg.new_i64_var(1)],
-        Some(AggregateFunc::Max) => vec![cg.new_i64_var(i64::MIN)],
-        Some(AggregateFunc::Min) => vec![cg.new_i64_var(i64::MAX)],
-        Some(_) => vec![cg.new_i64_var(0)],
-        None => Vec::new(),
+    let state_ptr = (mode != ScanMode::Complete).then(|| UntypedPtrRef::from(cg.get_arg(table_args + !parameters.is_empty() as usize)));
+    let aggregate_values = AggregateState::new(query.aggregate).values.into_iter().enumerate().map(|(k, init)| match &state_ptr {
+        Some(state_ptr) => {
+            let value = cg.new_i64_var(0);
+            value.set(I64Ref::from(state_ptr.clone().byte_offset(8 * k as i64).load_widened(DataType::I64)));
+            value
+        },
+        None => cg.new_i64_var(init),
+    }).collect::<Vec<_>>();
+
+    // Every run gets a set of its own, so the code can run several times and on several threads at
+    // once. Resumable and partial code use the set of the state they get instead.
+    let external_state = match (query.aggregate, mode) {
+        (Some(AggregateFunc::CountDistinct), ScanMode::Complete) => Some(cg.call_c_function(distinct_new, cg.new_ptr_const(ptr::null::<u8>()))),
+        (Some(AggregateFunc::CountDistinct), _) | (None, ScanMode::Partial) => state_ptr.clone(),
+        _ => None,
     };
+    // Operations on strings get their operands through this, every run allocates its own as well
+    let string_operands = [Some(&query.expr), query.filter.as_ref(), query.order_by.as_ref().map(|order_by| &order_by.expr)].into_iter()
+        .chain(query.join.iter().flat_map(|join| [Some(&join.left_key), Some(&join.right_key)]))
+        .flatten()
+        .any(|expr| compares_strings(expr, &column_types))
+        .then(|| cg.call_c_function(string_operands_new, cg.new_ptr_const(ptr::null::<u8>())));
+    // Results are only passed on once the scan is done (and they are sorted). The state lives as long
+    // as the code.
+    let top_k = query.order_by.as_ref().map(|order_by| Box::new(TopKState {
+        key: 0,
+        value: 0,
+        sorted_ptr: ptr::null(),
+        sorted_len: 0,
+        sorted: Vec::new(),
+        top_k: TopK::new(query.limit, order_by.descending),
+    }));
+    let top_k_ptr = top_k.as_deref().map(|top_k| cg.new_ptr_const(top_k));
+    // Without an order the scan stops once `limit` results were passed on. The limit is the first
+    // patchable constant of the code, so `PreparedQuery::set_limit` can change it.
+    let limit = query.limit.filter(|_| query.order_by.is_none());
+    let limit_var = limit.filter(|&limit| limit > 0).map(|limit| cg.new_patchable_i64(limit as i64).0);
+    let result_count = limit.map(|_| cg.new_i64_var(0));
+    let order_columns = query.order_by.as_ref().map(|order_by| order_by.expr.referenced_columns()).unwrap_or_default();
+    // The hash table of a join, which also lives as long as the code
+    let join_state = query.join.as_ref().map(|_| Box::new(JoinState { key: 0, row: 0, matches_ptr: ptr::null(), matches_len: 0, rows: HashMap::new() }));
+    let join_ptr = join_state.as_deref().map(|join_state| cg.new_ptr_const(join_state));
+    // The sorted values and hash tables of long `in` lists
+    let lookup_tables = RefCell::new(Vec::new());
+
+    // Only the columns the query refers to are loaded. Columns of the table the query runs on are loaded
+    // from row `i` (`row_ptr` points to it for row major tables), columns of the table of a join from `right_row`.
+    let load_columns = column_loader(|row_ptr, columns| {
+        (0..full_schema.column_count()).map(|j| columns.contains(&j).then(|| {
+            if j < left_columns {
+                let data_type = schema.columns()[j].data_type;
+                let null = load_null(&cg, schema, layout, 0, j, &i);
+                if data_type == DataType::Str {
+                    let (value, length) = load_string(&cg, schema, layout, 0, j, &i);
+                    return Nullable { length: Some(length), ..Nullable::new(value, null) };
+                }
+                let column_ptr = match layout {
+                    Layout::RowMajor => row_ptr.clone().unwrap().byte_offset(schema.column_offset(j) as i64),
+                    Layout::Columnar => {
+                        data_ptrs[j].clone().unwrap().byte_offset(&(i.clone() * get_data_type_size(&data_type) as i64))
+                    },
+                };
+                Nullable::new(column_ptr.load_widened(data_type), null)
+            } else {
+                let ((right_schema, right_layout), right_row, j) = (right.unwrap(), right_row.as_ref().unwrap(), j - left_columns);
+                let data_type = right_schema.columns()[j].data_type;
+                let null = load_null(&cg, right_schema, right_layout, data_args + 1, j, right_row);
+                if data_type == DataType::Str {
+                    let (value, length) = load_string(&cg, right_schema, right_layout, data_args + 1, j, right_row);
+                    return Nullable { length: Some(length), ..Nullable::new(value, null) };
+                }
+                let column_ptr = match right_layout {
+                    Layout::RowMajor => {
+                        let row_ptr = right_data_ptrs[0].clone().unwrap().byte_offset(&(right_row.clone() * right_schema.row_size() as i64));
+                        row_ptr.byte_offset(right_schema.column_offset(j) as i64)
+                    },
+                    Layout::Columnar => {
+                        right_data_ptrs[j].clone().unwrap().byte_offset(&(right_row.clone() * get_data_type_size(&data_type) as i64))
+                    },
+                };
+                Nullable::new(column_ptr.load_widened(data_type), null)
+            }
+        })).collect::<Vec<_>>()
+    });
 
-   cg.gen_while::<CodeGenError>(|| {
-        let num = I64Ref::from(cg.get_arg(1));
-        Ok(i.clone().cg_lt(&num))
-    }, || {
-        // We assume we actually need the majority of our columns. We could also analyze the expression
-        // And only load the columns that are actually used here.
-        let row_ptr = data_ptr.typed_offset(&(i.clone() * columns as i64));
-        let row = (0..columns).map(|j| {
-            row_ptr.clone().typed_offset(j as i64).read()
-        }).collect::<Vec<_>>();
-        if let Some(filter) = &query.filter {
-            let filter = generate_code_inner(&cg, filter, &row)?;
-            let result = BoolRef::from(filter);
-            cg.gen_if(result, || {
-                let return_value = generate_code_inner(&cg, &query.expr, &row)?;
-                generate_aggregation_code(&cg, query, return_value, &aggregate_values, result_consumer);
+    if let (Some(join), Some(join_ptr), Some(right_row), Some(right_data_args)) = (&query.join, &join_ptr, &right_row, right_data_args) {
+        // Build: every row of the table of the join goes into the hash table under its key
+        cg.gen_while::<CodeGenError>(|| {
+            let num = I64Ref::from(cg.get_arg(data_args + 1 + right_data_args));
+            Ok(right_row.clone().cg_lt(&num))
+        }, || {
+            let values = load_columns(None, &join.right_key.referenced_columns());
+            let row = values.iter().map(Option::as_ref).chain(parameter_values.iter().map(Some)).collect::<Vec<_>>();
+            // Rows whose key is NULL never match, so they aren't inserted
+            let key = generate_code_inner(&cg, &join.right_key, &row, &column_types, options, &SharedExprs::new(None, &lookup_tables, string_operands.clone()))?;
+            cg.gen_if::<()>(!key.null.clone().unwrap_or_else(|| cg.new_bool_const(false)), || {
+                TypedPtrRef::<I64Ref>::from(join_ptr.clone()).write(I64Ref::view(&key.value));
+                TypedPtrRef::<I64Ref>::from(join_ptr.clone().byte_offset(8)).write(right_row);
+                cg.call_c_function(join_insert, join_ptr.clone());
                 Ok(())
-            })?;
-        } else {
-            let return_value = generate_code_inner(&cg, &query.expr, &row)?;
-            generate_aggregation_code(&cg, query, return_value, &aggregate_values, result_consumer);
-        }
-        i.set(i.clone() + 1);
-        Ok(())
-    })?;
+            }).unwrap();
+            right_row.set(right_row.clone() + 1);
+            Ok(())
+        })?;
+    }
 
-    match query.aggregate {
-        Some(AggregateFunc::Avg) => {
-            let aggregate_count = aggregate_values.pop().unwrap();
-            let aggregate_value = aggregate_values.pop().unwrap();
-            let avg = aggregate_value / &aggregate_count;
-            cg.call_c_function(result_consumer, UntypedPtrRef::from(avg.into_base()));
-        },
-        Some(_) => {
-            cg.call_c_function(result_consumer, UntypedPtrRef::from(aggregate_values.pop().unwrap().into_base()));
+    if limit != Some(0) {
+        cg.gen_while::<CodeGenError>(|| {
+            let num = I64Ref::from(cg.get_arg(data_args));
+            Ok(i.clone().cg_lt(&num))
+        }, || {
+            // Columns that only the expression needs are loaded after the filter passed, so rows that are
+            // filtered out never touch them
+            let row_ptr = match layout {
+                Layout::RowMajor if referenced_columns.iter().any(|&j| j < left_columns) => {
+                    Some(data_ptrs[0].clone().unwrap().byte_offset(&(i.clone() * schema.row_size() as i64)))
+                },
+                _ => None,
+            };
+            // The match of a join that is combined with the row
+            let match_index = join_ptr.as_ref().map(|_| cg.new_i64_var(0));
+            // Subexpressions the filter, the expression and the order have in common are only computed once
+            let shared = SharedExprs::new(filter.iter().chain([&query.expr]).chain(query.order_by.as_ref().map(|order_by| &order_by.expr)), &lookup_tables, string_operands.clone());
+            let process_row = row_processor(|row| {
+                // Only aggregates can get NULLs (see `check_results_not_null`), neither can order keys (see `Query::check_types`)
+                let Nullable { value: return_value, null, .. } = generate_code_inner(&cg, &query.expr, row, &column_types, options, &shared)?;
+                if let (Some(order_by), Some(top_k_ptr)) = (&query.order_by, &top_k_ptr) {
+                    let key = generate_code_inner(&cg, &order_by.expr, row, &column_types, options, &shared)?.value;
+                    let key = if get_type(&order_by.expr, &column_types) == DataType::Bool {
+                        let key_i64 = cg.new_i64_var(0);
+                        cg.gen_if::<()>(BoolRef::from(key), || {
+                            key_i64.set(cg.new_i64_const(1));
+                            Ok(())
+                        }).unwrap();
+                        key_i64
+                    } else {
+                        I64Ref::from(key)
+                    };
+                    TypedPtrRef::<I64Ref>::from(top_k_ptr.clone()).write(&key);
+                    TypedPtrRef::<I64Ref>::from(top_k_ptr.clone().byte_offset(8)).write(&I64Ref::from(return_value));
+                    cg.call_c_function(top_k_add, top_k_ptr.clone());
+                    return Ok(());
+                }
+                if let Some(null) = null {
+                    // Aggregates skip NULLs
+                    cg.gen_if::<()>(!null, || {
+                        generate_aggregation_code(&cg, query, return_value.clone(), &aggregate_values, external_state.as_ref(), &sink);
+                        Ok(())
+                    }).unwrap();
+                    return Ok(());
+                }
+                generate_aggregation_code(&cg, query, return_value, &aggregate_values, external_state.as_ref(), &sink);
+                if let (Some(limit), Some(result_count)) = (&limit_var, &result_count) {
+                    result_count.set(result_count.clone() + 1);
+                    cg.gen_if::<()>(result_count.clone().cg_eq(limit), || {
+                        // Makes this the last iteration of the loop (and of the loop over the matches of a join)
+                        i.set(I64Ref::from(cg.get_arg(data_args)));
+                        if let (Some(match_index), Some(join_ptr)) = (&match_index, &join_ptr) {
+                            match_index.set(I64Ref::from(join_ptr.clone().byte_offset(24).load_widened(DataType::I64)));
+                        }
+                        Ok(())
+                    }).unwrap();
+                }
+                Ok(())
+            });
+            let scan_row = || -> Result<(), CodeGenError> {
+                if let Some(filter) = &filter {
+                    let filter_columns = filter.referenced_columns();
+                    let filter_values = load_columns(row_ptr.clone(), &filter_columns);
+                    let row = filter_values.iter().map(Option::as_ref).chain(parameter_values.iter().map(Some)).collect::<Vec<_>>();
+                    let result = generate_code_inner(&cg, filter, &row, &column_types, options, &shared)?.holds();
+                    cg.gen_if(result, || shared.scope(|| {
+                        let expr_values = load_columns(row_ptr.clone(), &(&(&query.expr.referenced_columns() | &order_columns) - &filter_columns));
+                        let row = filter_values.iter().zip(&expr_values).map(|(f, e)| f.as_ref().or(e.as_ref())).chain(parameter_values.iter().map(Some)).collect::<Vec<_>>();
+                        process_row(&row)
+                    }))
+                } else {
+                    let values = load_columns(row_ptr.clone(), &(&query.expr.referenced_columns() | &order_columns));
+                    let row = values.iter().map(Option::as_ref).chain(parameter_values.iter().map(Some)).collect::<Vec<_>>();
+                    process_row(&row)
+                }
+            };
+            match (&query.join, &join_ptr, &match_index, &right_row) {
+                (Some(join), Some(join_ptr), Some(match_index), Some(right_row)) => {
+                    // Probe: the row is combined with every row of the table of the join that has the same key
+                    let values = load_columns(row_ptr.clone(), &join.left_key.referenced_columns());
+                    let row = values.iter().map(Option::as_ref).chain(parameter_values.iter().map(Some)).collect::<Vec<_>>();
+                    let key = generate_code_inner(&cg, &join.left_key, &row, &column_types, options, &SharedExprs::new(None, &lookup_tables, string_operands.clone()))?;
+                    let probe = || {
+                        TypedPtrRef::<I64Ref>::from(join_ptr.clone()).write(I64Ref::view(&key.value));
+                        cg.call_c_function(join_lookup, join_ptr.clone());
+                        cg.gen_while::<CodeGenError>(|| {
+                            let len = I64Ref::from(join_ptr.clone().byte_offset(24).load_widened(DataType::I64));
+                            Ok(match_index.clone().cg_lt(&len))
+                        }, || {
+                            let matches = UntypedPtrRef::from(join_ptr.clone().byte_offset(16).load_from(DataType::Ptr));
+                            right_row.set(I64Ref::from(matches.byte_offset(&(match_index.clone() * 8)).load_widened(DataType::I64)));
+                            scan_row()?;
+                            match_index.set(match_index.clone() + 1);
+                            Ok(())
+                        })
+                    };
+                    // A NULL key has no matches
+                    match &key.null {
+                        Some(null) => cg.gen_if(!null.clone(), probe)?,
+                        None => probe()?,
+                    }
+                },
+                _ => scan_row()?,
+            }
+            i.set(i.clone() + 1);
+            Ok(())
+        })?;
+    }
+    if let Some(join_ptr) = &join_ptr {
+        cg.call_c_function(join_clear, join_ptr.clone());
+    }
+
+    match (mode, &state_ptr) {
+        (ScanMode::Partial, Some(state_ptr)) => {
+            for (k, value) in aggregate_values.iter().enumerate() {
+                TypedPtrRef::<I64Ref>::from(state_ptr.clone().byte_offset(8 * k as i64)).write(value);
+            }
         },
-        _ => {},
+        _ => generate_aggregation_result(&cg, query, aggregate_values, external_state.as_ref(), &sink),
+    }
+    if let (Some(AggregateFunc::CountDistinct), ScanMode::Complete, Some(set)) = (query.aggregate, mode, &external_state) {
+        cg.call_c_function(distinct_free, set.clone());
+    }
+    if let Some(string_operands) = &string_operands {
+        cg.call_c_function(string_operands_free, string_operands.clone());
+    }
+    if let Some(top_k_ptr) = &top_k_ptr {
+        cg.call_c_function(top_k_sort, top_k_ptr.clone());
+        let j = cg.new_i64_var(0);
+        cg.gen_while::<()>(|| {
+            let len = I64Ref::from(top_k_ptr.clone().byte_offset(24).load_widened(DataType::I64));
+            Ok(j.clone().cg_lt(&len))
+        }, || {
+            let sorted = UntypedPtrRef::from(top_k_ptr.clone().byte_offset(16).load_from(DataType::Ptr));
+            let value = sorted.byte_offset(&(j.clone() * 8)).load_widened(DataType::I64);
+            sink.emit(&cg, UntypedPtrRef::from(value));
+            j.set(j.clone() + 1);
+            Ok(())
+        }).unwrap();
+    }
+    if let ResultSink::Buffer { len, .. } = &sink {
+        cg.gen_if::<()>(len.clone().cg_gt(0), || {
+            sink.flush(&cg);
+            Ok(())
+        }).unwrap();
     }
 
     cg.gen_return(None);
 
-    let gc = cg.generate_code();
+    let mut gc = cg.generate_code();
+
+    if let Some(top_k) = top_k {
+        gc.keep_alive(top_k);
+    }
+    if let Some(join_state) = join_state {
+        gc.keep_alive(join_state);
+    }
+    let lookup_tables = lookup_tables.into_inner();
+    if !lookup_tables.is_empty() {
+        gc.keep_alive(Box::new(lookup_tables));
+    }
 
     Ok(gc)
-}
\ No newline at end of file
+}
+
+/// A query that is compiled once and can then run with different values for its parameters.
+/// Its results go to an `OutputBuffer`.
+pub struct PreparedQuery {
+    code: GeneratedCode,
+    parameters: Vec<String>,
+    /// The schema and layout of the table the code reads, followed by the ones of the table of a join.
+    /// The code is only ever called on tables that have them.
+    tables: Vec<(Schema, Layout)>,
+}
+
+impl PreparedQuery {
+    pub fn new(query: &Query, schema: &Schema, layout: Layout, options: &CodeGenOptions) -> Result<Self, CodeGenError> {
+        let code = generate_batched_code(query, schema, layout, options)?;
+        Ok(PreparedQuery { code, parameters: query.parameters(), tables: vec![(schema.clone(), layout)] })
+    }
+
+    /// Prepares a query with a join of a table with `schema` and `layout` (`l`) with one with
+    /// `right_schema` and `right_layout` (`r`)
+    pub fn new_join(query: &Query, schema: &Schema, layout: Layout, right_schema: &Schema, right_layout: Layout, options: &CodeGenOptions) -> Result<Self, CodeGenError> {
+        let code = generate_join_code(query, schema, layout, right_schema, right_layout, options)?;
+        Ok(PreparedQuery { code, parameters: query.parameters(), tables: vec![(schema.clone(), layout), (right_schema.clone(), right_layout)] })
+    }
+
+    /// Whether the query has a join, so it has to run with `execute_join`
+    pub fn has_join(&self) -> bool {
+        self.tables.len() == 2
+    }
+
+    /// Whether the query was prepared for the schema and layout of `table` (the left one of a join)
+    pub fn is_prepared_for(&self, table: &Table) -> bool {
+        let (schema, layout) = &self.tables[0];
+        table.schema() == schema && table.layout() == *layout
+    }
+
+    /// The names of the parameters in the order `execute` takes their values (see `Query::parameters`)
+    pub fn parameters(&self) -> &[String] {
+        &self.parameters
+    }
+
+    pub fn code_len(&self) -> usize {
+        self.code.code_len
+    }
+
+    /// Changes the `limit` of a query without an `order by` in the code, so it doesn't have to be
+    /// generated again. The query must have had a limit other than 0 already.
+    pub fn set_limit(&mut self, limit: usize) -> Result<(), String> {
+        if limit == 0 {
+            return Err("The limit can't be changed to 0".to_string());
+        }
+        let &hole = self.code.patchable_consts().first().ok_or("The query has no limit that can be changed")?;
+        self.code.patch_const(hole, ConstValue::I64(limit as i64))
+    }
+
+    /// Runs the query on `table`. Fails if the table doesn't have the schema and layout the query was
+    /// prepared for.
+    pub fn execute(&self, table: &Table, values: &[i64], output: &mut OutputBuffer) -> Result<(), String> {
+        self.execute_on(&[table], values, output)
+    }
+
+    /// Runs a query with a join on `table` and `right`. Fails unless they have the schemas and
+    /// layouts the query was prepared for.
+    pub fn execute_join(&self, table: &Table, right: &Table, values: &[i64], output: &mut OutputBuffer) -> Result<(), String> {
+        self.execute_on(&[table, right], values, output)
+    }
+
+    fn execute_on(&self, tables: &[&Table], values: &[i64], output: &mut OutputBuffer) -> Result<(), String> {
+        if self.has_join() != (tables.len() == 2) {
+            return Err(if self.has_join() { "The query has a join, it needs the table it joins with" } else { "The query has no join" }.to_string());
+        }
+        for (i, (table, (schema, layout))) in tables.iter().zip(&self.tables).enumerate() {
+            let name = if i == 0 { "table" } else { "table of the join" };
+            if table.schema() != schema {
+                return Err(format!("The {} doesn't have the schema the query was prepared for", name));
+            }
+            if table.layout() != *layout {
+                return Err(format!("The query was prepared for a {} with {:?} layout, not {:?}", name, layout, table.layout()));
+            }
+        }
+        if values.len() != self.parameters.len() {
+            return Err(format!("The query has {} parameters but {} values were given", self.parameters.len(), values.len()));
+        }
+        let mut args = tables.iter().flat_map(|table| table.call_args()).collect::<Vec<_>>();
+        if !values.is_empty() {
+            args.push(values.as_ptr() as usize);
+        }
+        args.push(output as *mut OutputBuffer as usize);
+        self.code.call(&args);
+        Ok(())
+    }
+}
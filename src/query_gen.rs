@@ -0,0 +1,131 @@
+//! Random query generation for differential testing of the generated code against the interpreter.
+//!
+//! Queries are generated as source text so that the parser is exercised as well. Columns are
+//! referenced by position or by their name in `schema`, so queries have to be bound to it. All
+//! generated queries are well typed and never divide by zero (or `i64::MIN` by -1), so any difference
+//! between the compiled and interpreted results (or any error from `generate_code`) is a bug.
+
+use proptest::{collection::vec, prelude::*, sample::select};
+
+use crate::{codegen::ir::DataType, schema::{ColumnDef, Schema}};
+
+/// The names of the columns, the last one has to be quoted
+const COLUMN_NAMES: [&str; 3] = ["a", "num1", "order id"];
+
+/// The schema generated queries are bound to, with at most 3 i64 columns
+pub fn schema(columns: usize) -> Schema {
+    Schema::new(COLUMN_NAMES[..columns].iter().map(|name| ColumnDef::new(name, DataType::I64)).collect()).unwrap()
+}
+
+/// Values that tend to break arithmetic
+const EDGE_CASES: [i64; 9] = [0, 1, -1, 2, -2, i64::MAX, i64::MIN, i64::MAX - 1, i64::MIN + 1];
+
+const AGGREGATES: [&str; 17] = [
+    "", "sum", "prod", "avg", "max", "min", "count", "count(*)", "count distinct",
+    "any", "all", "var_pop", "var_samp", "stddev_pop", "stddev_samp", "variance", "stddev",
+];
+
+fn format_num(n: i64) -> String {
+    // `i64::MIN` has no literal since its absolute value doesn't fit into an i64
+    if n == i64::MIN {
+        format!("(- {} 1)", i64::MIN + 1)
+    } else {
+        n.to_string()
+    }
+}
+
+pub fn value() -> impl Strategy<Value = i64> {
+    prop_oneof![
+        select(&EDGE_CASES[..]),
+        -100i64..100,
+        any::<i64>(),
+    ]
+}
+
+/// Divisors are restricted to constants that can't trap
+fn divisor() -> impl Strategy<Value = i64> {
+    prop_oneof![
+        2i64..1000,
+        -1000i64..-1,
+        select(&[i64::MAX, i64::MIN + 1][..]),
+    ]
+}
+
+fn variable(columns: usize) -> impl Strategy<Value = String> {
+    (0..columns, any::<bool>()).prop_map(|(c, by_name)| match COLUMN_NAMES[c] {
+        _ if !by_name => format!("${c}"),
+        name if name.contains(' ') => format!("\"{name}\""),
+        name => name.to_string(),
+    })
+}
+
+fn int_operand(columns: usize) -> impl Strategy<Value = String> {
+    prop_oneof![
+        variable(columns),
+        value().prop_map(format_num),
+    ]
+}
+
+/// Integer expressions over the given columns
+pub fn int_expr(columns: usize) -> BoxedStrategy<String> {
+    int_operand(columns).prop_recursive(4, 24, 3, move |inner| {
+        let operand = prop_oneof![inner.clone(), int_operand(columns)];
+        prop_oneof![
+            (select(&["+", "-", "*", "&", "|"][..]), operand.clone(), vec(operand.clone().prop_map(|o| format!(" {o}")), 0..5))
+                .prop_map(|(op, first, rest)| format!("({op} {first}{})", rest.concat())),
+            (select(&["/", "%"][..]), operand, vec(divisor().prop_map(|d| format!(" {d}")), 1..3))
+                .prop_map(|(op, first, divisors)| format!("({op} {first}{})", divisors.concat())),
+        ]
+    }).boxed()
+}
+
+/// Boolean expressions over the given columns
+pub fn bool_expr(columns: usize) -> BoxedStrategy<String> {
+    let comparison = (
+        select(&["=", "!=", "<", ">", "<=", ">="][..]),
+        int_expr(columns),
+        int_expr(columns),
+    ).prop_map(|(op, l, r)| format!("({op} {l} {r})"));
+    // Lists of constants that are long enough to be looked up in a table as well as short lists with columns
+    let in_list = (
+        int_expr(columns),
+        prop_oneof![vec(value().prop_map(format_num), 1..20), vec(int_operand(columns), 1..6)],
+    ).prop_map(|(value, list)| format!("(in {value} {})", list.join(" ")));
+    let between = (int_expr(columns), int_operand(columns), int_operand(columns))
+        .prop_map(|(value, low, high)| format!("(between {value} {low} {high})"));
+    let comparison = prop_oneof![3 => comparison, 1 => in_list, 1 => between];
+    comparison.prop_recursive(3, 12, 3, |inner| {
+        let operand = prop_oneof![inner.clone(), select(&["#t", "#f"][..]).prop_map(String::from)];
+        prop_oneof![
+            (select(&["&", "|"][..]), operand.clone(), vec(operand.clone().prop_map(|o| format!(" {o}")), 0..5))
+                .prop_map(|(op, first, rest)| format!("({op} {first}{})", rest.concat())),
+            (select(&["=", "!="][..]), operand.clone(), operand)
+                .prop_map(|(op, l, r)| format!("({op} {l} {r})")),
+        ]
+    }).boxed()
+}
+
+/// A complete query over `columns` columns
+pub fn query(columns: usize) -> impl Strategy<Value = String> {
+    let filter = proptest::option::of(bool_expr(columns));
+    (select(&AGGREGATES[..]), int_expr(columns), bool_expr(columns), filter)
+        .prop_map(|(aggregate, int_expr, bool_expr, filter)| {
+            let mut query = match aggregate {
+                "count(*)" => aggregate.to_string(),
+                "any" | "all" => format!("{aggregate} {bool_expr}"),
+                "" => int_expr,
+                _ => format!("{aggregate} {int_expr}"),
+            };
+            if let Some(filter) = filter {
+                query.push_str(&format!(" where {filter}"));
+            }
+            query
+        })
+}
+
+/// A query together with the number of columns and row-major data to run it on
+pub fn query_with_data() -> impl Strategy<Value = (String, usize, Vec<i64>)> {
+    (1usize..4).prop_flat_map(|columns| {
+        (query(columns), Just(columns), (0usize..24).prop_flat_map(move |rows| vec(value(), rows * columns)))
+    })
+}
@@ -0,0 +1,159 @@
+// The schema describes the columns of the input table so that queries can refer to
+// columns by name instead of only by position ($0, $1, ...) and so that every column
+// can be stored with its real width.
+
+use std::{fs, path::Path};
+
+use crate::codegen::{get_data_type_size, ir::DataType};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnDef {
+    // None for columns without a name (e.g. CSV files without a header)
+    pub name: Option<String>,
+    pub data_type: DataType,
+    /// Whether values can be missing (NULL). Tables keep a validity bitmap for these columns.
+    pub nullable: bool,
+}
+
+impl ColumnDef {
+    pub fn new(name: &str, data_type: DataType) -> Self {
+        ColumnDef { name: Some(name.to_string()), data_type, nullable: false }
+    }
+
+    /// A column that can contain NULLs
+    pub fn nullable(name: &str, data_type: DataType) -> Self {
+        ColumnDef { nullable: true, ..ColumnDef::new(name, data_type) }
+    }
+}
+
+/// Rows are stored one after another, inside of a row every column is aligned to its
+/// own size and the row size is a multiple of 8 bytes. String columns take no space
+/// inside of the row, the table keeps their values on the side.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    columns: Vec<ColumnDef>,
+    offsets: Vec<usize>,
+    row_size: usize,
+}
+
+impl Schema {
+    /// Floating point columns can't be stored or used in queries yet, so they are rejected here
+    pub fn new(columns: Vec<ColumnDef>) -> Result<Self, String> {
+        if let Some(column) = columns.iter().find(|column| column.data_type.is_float()) {
+            let name = column.name.as_deref().map_or("without a name".to_string(), |name| format!("\"{}\"", name));
+            return Err(format!("Column {} has type {}, floating point columns aren't supported", name, column.data_type));
+        }
+        Ok(Schema::with_layout(columns))
+    }
+
+    /// Computes where the columns are in a row, the columns must already be valid
+    fn with_layout(columns: Vec<ColumnDef>) -> Self {
+        let mut offsets = Vec::with_capacity(columns.len());
+        let mut row_size: usize = 0;
+        for column in columns.iter() {
+            let size = get_data_type_size(&column.data_type);
+            row_size = row_size.next_multiple_of(size.max(1));
+            offsets.push(row_size);
+            row_size += size;
+        }
+        Schema { columns, offsets, row_size: row_size.next_multiple_of(8) }
+    }
+
+    /// A schema of i64 columns that can only be addressed by position
+    pub fn unnamed(columns: usize) -> Self {
+        Schema::with_layout(vec![ColumnDef { name: None, data_type: DataType::I64, nullable: false }; columns])
+    }
+
+    /// Reads a schema file with one column per line in the form `<name> <type> [null]`, e.g. `price i32`
+    /// or `discount i16 null` for a column that can contain NULLs. Names containing whitespace can be
+    /// put in double quotes, lines starting with # are ignored.
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("Can't read schema file {:?}: {}", path, e))?;
+        let mut columns = Vec::new();
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, rest) = match line.strip_prefix('"') {
+                Some(rest) => rest.split_once('"'),
+                None => line.split_once(char::is_whitespace),
+            }.ok_or_else(|| format!("Line {} of the schema file must look like `<name> <type> [null]`", n + 1))?;
+            let (data_type, nullable) = match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [data_type] => (data_type, false),
+                [data_type, null] if null.eq_ignore_ascii_case("null") => (data_type, true),
+                _ => return Err(format!("Line {} of the schema file must look like `<name> <type> [null]`", n + 1)),
+            };
+            let data_type = data_type.parse().map_err(|e| format!("Line {} of the schema file: {}", n + 1, e))?;
+            columns.push(if nullable { ColumnDef::nullable(name, data_type) } else { ColumnDef::new(name, data_type) });
+        }
+        Schema::new(columns)
+    }
+
+    /// The schema of the rows of a join: the columns of this table followed by the ones of `right`.
+    /// Only describes the types of the columns, the tables are still stored on their own.
+    pub fn joined(&self, right: &Schema) -> Schema {
+        Schema::with_layout(self.columns.iter().chain(&right.columns).cloned().collect())
+    }
+
+    pub fn column_count(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn columns(&self) -> &[ColumnDef] {
+        &self.columns
+    }
+
+    pub fn column_name(&self, index: usize) -> Option<&str> {
+        self.columns.get(index)?.name.as_deref()
+    }
+
+    /// Name for error messages, falls back to the positional name
+    pub fn display_name(&self, index: usize) -> String {
+        self.column_name(index).map_or(format!("${}", index), |n| format!("\"{}\"", n))
+    }
+
+    pub fn column_offset(&self, index: usize) -> usize {
+        self.offsets[index]
+    }
+
+    pub fn row_size(&self) -> usize {
+        self.row_size
+    }
+
+    /// The type a column has inside of expressions. All integers are widened to i64.
+    pub fn value_type(&self, index: usize) -> DataType {
+        match self.columns[index].data_type {
+            DataType::Bool => DataType::Bool,
+            ty if ty.is_integer() => DataType::I64,
+            ty => ty,
+        }
+    }
+
+    pub fn value_types(&self) -> Vec<DataType> {
+        (0..self.column_count()).map(|i| self.value_type(i)).collect()
+    }
+
+    /// Whether each of the columns can contain NULLs
+    pub fn nullable_columns(&self) -> Vec<bool> {
+        self.columns.iter().map(|c| c.nullable).collect()
+    }
+
+    pub fn index_of(&self, name: &str) -> Result<usize, String> {
+        let mut matches = self.columns.iter().enumerate()
+            .filter(|(_, c)| c.name.as_deref() == Some(name))
+            .map(|(i, _)| i);
+        match (matches.next(), matches.next()) {
+            (Some(i), None) => Ok(i),
+            (Some(_), Some(_)) => Err(format!("Column name \"{}\" is ambiguous", name)),
+            (None, _) => {
+                let known = self.columns.iter().filter_map(|c| c.name.as_ref()).map(|c| format!("\"{}\"", c)).collect::<Vec<_>>();
+                if known.is_empty() {
+                    Err(format!("Unknown column \"{}\" (columns have no names, use $0..${})", name, self.column_count().saturating_sub(1)))
+                } else {
+                    Err(format!("Unknown column \"{}\", available columns: {}", name, known.join(", ")))
+                }
+            },
+        }
+    }
+}
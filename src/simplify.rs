@@ -0,0 +1,207 @@
+// Constant folding and algebraic simplification of expressions. This runs before code generation
+// so that the code generator doesn't have to deal with constant subexpressions. A simplified
+// expression always evaluates to the same value as the original one (see `eval_expression`), the
+// only difference is that subexpressions that would trap might be dropped, e.g. in `(* (/ $0 $1) 0)`.
+// Subexpressions that can be NULL are never dropped since they would make the result NULL.
+// Divisions that trap no matter what the row contains (by a constant 0 or `i64::MIN / -1` on
+// constants) are reported as errors instead of leaving them to the generated code.
+
+use crate::{codegen::ir::DataType, query::{eval_expression, Atom, BuiltIn, EvalError, Expr}, query_codegen::get_type, typecheck::can_be_null};
+
+/// Applies `fun` to constant arguments. Returns `None` if the arguments don't fit the operation
+/// or if evaluating it would trap (division by zero or `i64::MIN / -1`).
+pub fn fold_op(fun: BuiltIn, args: &[Atom]) -> Option<Atom> {
+    let nums = args.iter().map(|a| match a {
+        Atom::Num(n) => Some(*n),
+        Atom::Boolean(_) | Atom::Str(_) | Atom::Null => None,
+    }).collect::<Option<Vec<_>>>();
+    let all_bools = args.iter().all(|a| matches!(a, Atom::Boolean(_)));
+    let all_strings = args.iter().all(|a| matches!(a, Atom::Str(_)));
+    match fun {
+        _ if args.is_empty() => None,
+        BuiltIn::Divide | BuiltIn::Rem if nums.is_none() => None,
+        BuiltIn::Equal | BuiltIn::NotEqual if nums.is_none() && !all_bools && !all_strings => None,
+        BuiltIn::And | BuiltIn::Or if nums.is_none() && !all_bools => None,
+        BuiltIn::LessThan | BuiltIn::GreaterThan | BuiltIn::LessThanOrEqual | BuiltIn::GreaterThanOrEqual
+        | BuiltIn::In if nums.is_none() && !all_strings => None,
+        BuiltIn::Plus | BuiltIn::Minus | BuiltIn::Times | BuiltIn::Between if nums.is_none() => None,
+        _ => eval_expression(&Expr::Application(fun, args.iter().cloned().map(Expr::Constant).collect()), &[]).ok(),
+    }
+}
+
+fn as_constant(expr: &Expr) -> Option<Atom> {
+    match expr {
+        Expr::Constant(c) => Some(c.clone()),
+        _ => None,
+    }
+}
+
+/// Operations where nested applications can be merged into one and the operands can be reordered
+fn is_associative(fun: BuiltIn) -> bool {
+    matches!(fun, BuiltIn::Plus | BuiltIn::Times | BuiltIn::And | BuiltIn::Or)
+}
+
+/// Fails if the division traps for every row: a divisor is a constant 0 or the constants it
+/// starts with can't be divided (`i64::MIN / -1`).
+fn check_division(fun: BuiltIn, args: &[Expr]) -> Result<(), EvalError> {
+    if args[1..].iter().any(|arg| matches!(arg, Expr::Constant(Atom::Num(0)))) {
+        return Err(EvalError::DivisionByZero);
+    }
+    let leading = args.iter().map_while(|arg| match arg {
+        Expr::Constant(Atom::Num(n)) => Some(Expr::Constant(Atom::Num(*n))),
+        _ => None,
+    }).collect::<Vec<_>>();
+    if leading.len() < 2 {
+        return Ok(());
+    }
+    eval_expression(&Expr::Application(fun, leading), &[]).map(|_| ())
+}
+
+/// `column_types` are the types of the columns inside of expressions (see `Schema::value_types`),
+/// `nullable_columns` says which of them can be NULL (see `Schema::nullable_columns`)
+pub fn simplify(expr: &Expr, column_types: &[DataType], nullable_columns: &[bool]) -> Result<Expr, EvalError> {
+    let Expr::Application(fun, original_args) = expr else {
+        return Ok(expr.clone());
+    };
+    let fun = *fun;
+    let mut args = Vec::with_capacity(original_args.len());
+    for (i, arg) in original_args.iter().enumerate() {
+        match simplify(arg, column_types, nullable_columns)? {
+            // (+ a (+ b c)) is (+ a b c) and (- (- a b) c) is (- a b c)
+            Expr::Application(inner, inner_args) if inner == fun && (is_associative(fun) || (fun == BuiltIn::Minus && i == 0)) => {
+                args.extend(inner_args);
+            },
+            arg => args.push(arg),
+        }
+    }
+
+    if matches!(fun, BuiltIn::Divide | BuiltIn::Rem) {
+        check_division(fun, &args)?;
+    }
+    if let Some(constants) = args.iter().map(as_constant).collect::<Option<Vec<_>>>() {
+        return Ok(match fold_op(fun, &constants) {
+            Some(result) => Expr::Constant(result),
+            None => Expr::Application(fun, args),
+        });
+    }
+
+    let data_type = get_type(&args[0], column_types);
+    let can_be_null = |arg: &Expr| can_be_null(arg, nullable_columns);
+    let simplified = match fun {
+        BuiltIn::Plus | BuiltIn::Times | BuiltIn::And | BuiltIn::Or => simplify_associative(fun, &args, data_type, can_be_null),
+        BuiltIn::Minus => simplify_minus(args.clone()),
+        BuiltIn::Divide | BuiltIn::Rem => simplify_division(fun, args.clone(), can_be_null),
+        // The checks for NULL are only needed for values that can be NULL
+        BuiltIn::IsNull if !can_be_null(&args[0]) => Some(Expr::Constant(Atom::Boolean(false))),
+        BuiltIn::Coalesce => {
+            let end = args.iter().position(|arg| !can_be_null(arg)).map_or(args.len(), |i| i + 1);
+            let mut args = args[..end].to_vec();
+            Some(if args.len() == 1 { args.pop().unwrap() } else { Expr::Application(fun, args) })
+        },
+        _ => None,
+    };
+    Ok(simplified.unwrap_or(Expr::Application(fun, args)))
+}
+
+/// Merges all constants into one and applies the identities `(+ x 0)`, `(* x 1)`, `(* x 0)`,
+/// `(& #t p)`, `(& #f p)`, `(| #f p)`, `(| #t p)` and their bitwise equivalents on integers.
+/// `(* x 0)` and the bitwise ones only apply if `x` can't be NULL, unlike `(& #f p)` in three-valued logic.
+fn simplify_associative(fun: BuiltIn, args: &[Expr], data_type: DataType, can_be_null: impl Fn(&Expr) -> bool) -> Option<Expr> {
+    let (identity, absorbing) = match (fun, data_type) {
+        (BuiltIn::Plus, _) => (Atom::Num(0), None),
+        (BuiltIn::Times, _) => (Atom::Num(1), Some(Atom::Num(0))),
+        (BuiltIn::And, DataType::Bool) => (Atom::Boolean(true), Some(Atom::Boolean(false))),
+        (BuiltIn::And, _) => (Atom::Num(-1), Some(Atom::Num(0))),
+        (BuiltIn::Or, DataType::Bool) => (Atom::Boolean(false), Some(Atom::Boolean(true))),
+        (BuiltIn::Or, _) => (Atom::Num(0), Some(Atom::Num(-1))),
+        _ => return None,
+    };
+    let mut constant = identity.clone();
+    let mut rest = Vec::new();
+    for arg in args {
+        match arg {
+            Expr::Constant(c) => constant = fold_op(fun, &[constant, c.clone()])?,
+            _ => rest.push(arg.clone()),
+        }
+    }
+    if Some(&constant) == absorbing.as_ref() && (data_type == DataType::Bool || !rest.iter().any(&can_be_null)) {
+        return Some(Expr::Constant(constant));
+    }
+    if data_type != DataType::Bool {
+        // Computing the applications first means fewer intermediate results have to be kept around.
+        // Boolean operands keep their order since they short circuit.
+        rest.sort_by_key(|arg| !matches!(arg, Expr::Application(..)));
+    }
+    if constant != identity {
+        // The code generator prefers constants as the last operand
+        rest.push(Expr::Constant(constant));
+    }
+    Some(if rest.len() == 1 { rest.pop().unwrap() } else { Expr::Application(fun, rest) })
+}
+
+/// Merges all constants that are subtracted and removes double negation `(- 0 (- 0 x))`
+fn simplify_minus(args: Vec<Expr>) -> Option<Expr> {
+    let mut args = args.into_iter();
+    let first = args.next()?;
+    let mut subtracted = 0i64;
+    let mut rest = Vec::new();
+    for arg in args {
+        match arg {
+            Expr::Constant(Atom::Num(n)) => subtracted = subtracted.wrapping_add(n),
+            Expr::Constant(Atom::Boolean(_)) => return None,
+            _ => rest.push(arg),
+        }
+    }
+    let first = match first {
+        Expr::Constant(Atom::Num(n)) => {
+            let n = n.wrapping_sub(subtracted);
+            if let (0, [Expr::Application(BuiltIn::Minus, negated)]) = (n, &rest[..]) {
+                if let [Expr::Constant(Atom::Num(0)), x] = &negated[..] {
+                    return Some(x.clone());
+                }
+            }
+            Expr::Constant(Atom::Num(n))
+        },
+        first => {
+            if subtracted != 0 {
+                rest.push(Expr::Constant(Atom::Num(subtracted)));
+            }
+            first
+        },
+    };
+    if rest.is_empty() {
+        return Some(first);
+    }
+    rest.insert(0, first);
+    Some(Expr::Application(BuiltIn::Minus, rest))
+}
+
+/// Folds leading constants and applies `(/ x 1)` and `(% x 1)` (unless `x` can be NULL)
+fn simplify_division(fun: BuiltIn, args: Vec<Expr>, can_be_null: impl Fn(&Expr) -> bool) -> Option<Expr> {
+    let mut args = args.into_iter().peekable();
+    let mut result = Vec::new();
+    if let Some(Expr::Constant(mut folded)) = args.peek().cloned() {
+        args.next();
+        while let Some(Expr::Constant(n)) = args.peek() {
+            match fold_op(fun, &[folded.clone(), n.clone()]) {
+                Some(f) => folded = f,
+                None => break,
+            }
+            args.next();
+        }
+        result.push(Expr::Constant(folded));
+    }
+    for arg in args {
+        match (fun, &arg) {
+            (BuiltIn::Divide, Expr::Constant(Atom::Num(1))) if !result.is_empty() => {},
+            _ => result.push(arg),
+        }
+    }
+    // Only if 1 is the only divisor, otherwise the following ones could still be 0
+    if let (BuiltIn::Rem, [x, Expr::Constant(Atom::Num(1))]) = (fun, &result[..]) {
+        if !can_be_null(x) {
+            return Some(Expr::Constant(Atom::Num(0)));
+        }
+    }
+    Some(if result.len() == 1 { result.pop().unwrap() } else { Expr::Application(fun, result) })
+}
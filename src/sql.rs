@@ -0,0 +1,359 @@
+// SQL-like surface syntax for queries: `SELECT sum(a + 2) FROM t WHERE b > 3`. It is parsed into the
+// same `Query` as the lisp syntax, so everything after parsing (binding, type checking, simplification
+// and code generation) doesn't know which syntax a query was written in.
+//
+// Precedence from loosest to tightest: `OR`, `AND`, comparisons (including `IN`, `BETWEEN`, `IS [NOT] NULL` and `[NOT] LIKE`),
+// `|`, `&`, `+ -`, `* / %`, unary `-`.
+// `AND`/`OR` and `&`/`|` are the same operations (logical on booleans, bitwise on integers), they only
+// bind differently. Chains of the same operator become one application, `a - b - c` is `(- a b c)`.
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, tag_no_case, take_while},
+    character::complete::{char, multispace0, satisfy},
+    combinator::{cut, map, not, opt, value, verify},
+    error::{context, VerboseError},
+    multi::separated_list1,
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+    IResult,
+};
+
+use crate::query::{err_converter, failure, finish_query, identifier, parse_joined_column, parse_num, parse_order_by_limit, parse_parameter, parse_string, parse_variable, AggregateFunc, Atom, BuiltIn, Expr, ExprSpans, ParseError, Query, QuerySpans, Span};
+
+type ParseResult<'a, O> = IResult<&'a str, O, VerboseError<&'a str>>;
+
+/// An expression together with where it is in the input. Like in the lisp parser spans are measured
+/// from the end of the input until `finish_query` turns them into offsets.
+type Spanned = (Expr, ExprSpans);
+
+const KEYWORDS: [&str; 20] = ["select", "from", "join", "on", "where", "and", "or", "not", "in", "between", "like", "is", "null", "true", "false", "distinct", "order", "limit", "asc", "desc"];
+
+/// A case insensitive keyword that isn't just the start of a longer identifier
+pub fn keyword<'a>(keyword: &'static str) -> impl FnMut(&'a str) -> ParseResult<'a, &'a str> {
+    terminated(tag_no_case(keyword), not(satisfy(|c| c.is_alphanumeric() || c == '_')))
+}
+
+/// Column names are identifiers that aren't keywords, or anything in double quotes
+fn parse_column(i: &str) -> ParseResult<Expr> {
+    let plain = verify(identifier, |name: &str| !KEYWORDS.contains(&name.to_ascii_lowercase().as_str()));
+    let quoted = delimited(char('"'), take_while(|c| c != '"'), char('"'));
+    map(alt((plain, quoted)), |name: &str| Expr::Column(name.to_string()))(i)
+}
+
+/// Wraps a parser for an expression without operands to also return its span
+fn leaf<'a>(mut parser: impl FnMut(&'a str) -> ParseResult<'a, Expr>) -> impl FnMut(&'a str) -> ParseResult<'a, Spanned> {
+    move |i: &'a str| {
+        let (rest, expr) = parser(i)?;
+        Ok((rest, (expr, ExprSpans { span: Span { start: i.len(), end: rest.len() }, args: Vec::new() })))
+    }
+}
+
+fn application(fun: BuiltIn, args: Vec<Spanned>) -> Spanned {
+    let span = Span { start: args[0].1.span.start, end: args[args.len() - 1].1.span.end };
+    let (args, spans) = args.into_iter().unzip();
+    (Expr::Application(fun, args), ExprSpans { span, args: spans })
+}
+
+/// `coalesce(a, b, ...)`, `starts_with(a, b)` or `length(a)`. A column with the name of one of the
+/// functions is still a column.
+fn parse_function(i: &str) -> ParseResult<Spanned> {
+    let name = alt((
+        value(BuiltIn::Coalesce, keyword("coalesce")),
+        value(BuiltIn::StartsWith, keyword("starts_with")),
+        value(BuiltIn::Length, keyword("length")),
+    ));
+    let args = separated_list1(preceded(multispace0, char(',')), parse_expr);
+    let end = context("expected `)`", preceded(multispace0, char(')')));
+    let (rest, (fun, args)) = pair(terminated(name, pair(multispace0, char('('))), cut(terminated(args, end)))(i)?;
+    let (expr, mut spans) = application(fun, args);
+    spans.span = Span { start: i.len(), end: rest.len() };
+    Ok((rest, (expr, spans)))
+}
+
+fn parse_primary(i: &str) -> ParseResult<Spanned> {
+    let (i, _) = multispace0(i)?;
+    context("expected an expression", alt((
+        delimited(char('('), parse_expr, context("expected `)`", cut(preceded(multispace0, char(')'))))),
+        parse_function,
+        leaf(map(parse_num, Expr::Constant)),
+        leaf(map(parse_string, Expr::Constant)),
+        leaf(value(Expr::Constant(Atom::Boolean(true)), keyword("true"))),
+        leaf(value(Expr::Constant(Atom::Boolean(false)), keyword("false"))),
+        leaf(parse_variable),
+        leaf(parse_parameter),
+        leaf(|i| parse_joined_column(i, parse_column)),
+        leaf(parse_column),
+    )))(i)
+}
+
+/// `-x` is `(- 0 x)`, negative number literals are parsed as constants right away
+fn parse_unary(i: &str) -> ParseResult<Spanned> {
+    let (i, _) = multispace0(i)?;
+    match parse_primary(i) {
+        Err(nom::Err::Error(_)) if i.starts_with('-') => {
+            let (rest, operand) = preceded(char('-'), cut(parse_unary))(i)?;
+            // The 0 stands for the `-`
+            let zero = (Expr::Constant(Atom::Num(0)), ExprSpans { span: Span { start: i.len(), end: i.len() - 1 }, args: Vec::new() });
+            Ok((rest, application(BuiltIn::Minus, vec![zero, operand])))
+        },
+        result => result,
+    }
+}
+
+/// Parses `operand (operator operand)*` into left associative applications
+fn binary_chain<'a>(i: &'a str, operand: fn(&'a str) -> ParseResult<'a, Spanned>, mut operator: impl FnMut(&'a str) -> ParseResult<'a, BuiltIn>) -> ParseResult<'a, Spanned> {
+    let (mut i, first) = operand(i)?;
+    let mut current = None;
+    let mut args = vec![first];
+    loop {
+        let (rest, fun) = match preceded(multispace0, &mut operator)(i) {
+            Ok(result) => result,
+            Err(nom::Err::Error(_)) => break,
+            Err(e) => return Err(e),
+        };
+        let (rest, arg) = cut(operand)(rest)?;
+        match current {
+            Some(previous) if previous != fun => args = vec![application(previous, args)],
+            _ => {},
+        }
+        current = Some(fun);
+        args.push(arg);
+        i = rest;
+    }
+    Ok((i, match current {
+        Some(fun) => application(fun, args),
+        None => args.pop().unwrap(),
+    }))
+}
+
+fn parse_multiplicative(i: &str) -> ParseResult<Spanned> {
+    binary_chain(i, parse_unary, alt((
+        value(BuiltIn::Times, char('*')),
+        value(BuiltIn::Divide, char('/')),
+        value(BuiltIn::Rem, char('%')),
+    )))
+}
+
+fn parse_additive(i: &str) -> ParseResult<Spanned> {
+    binary_chain(i, parse_multiplicative, alt((
+        value(BuiltIn::Plus, char('+')),
+        value(BuiltIn::Minus, char('-')),
+    )))
+}
+
+fn parse_bit_and(i: &str) -> ParseResult<Spanned> {
+    binary_chain(i, parse_additive, value(BuiltIn::And, char('&')))
+}
+
+fn parse_bit_or(i: &str) -> ParseResult<Spanned> {
+    binary_chain(i, parse_bit_and, value(BuiltIn::Or, char('|')))
+}
+
+/// The part after the first operand of `a IN (b, c)` and `a BETWEEN b AND c`, which bind like comparisons
+fn parse_in_or_between(i: &str) -> ParseResult<(BuiltIn, Vec<Spanned>)> {
+    let list = delimited(
+        pair(multispace0, context("expected `(` before the list of values", char('('))),
+        separated_list1(preceded(multispace0, char(',')), parse_expr),
+        context("expected `)`", preceded(multispace0, char(')'))),
+    );
+    let in_list = map(preceded(keyword("in"), cut(list)), |values| (BuiltIn::In, values));
+    let bounds = separated_pair(parse_bit_or, preceded(multispace0, context("expected `AND`", keyword("and"))), parse_bit_or);
+    let between = map(preceded(keyword("between"), cut(bounds)), |(low, high)| (BuiltIn::Between, vec![low, high]));
+    preceded(multispace0, alt((in_list, between)))(i)
+}
+
+/// `IS NULL` or `IS NOT NULL` after an operand, gives whether it is negated
+fn parse_is_null(i: &str) -> ParseResult<bool> {
+    let negated = map(opt(preceded(multispace0, keyword("not"))), |not| not.is_some());
+    let null = preceded(multispace0, context("expected `NULL`", keyword("null")));
+    preceded(pair(multispace0, keyword("is")), cut(terminated(negated, null)))(i)
+}
+
+/// `LIKE pattern` or `NOT LIKE pattern` after an operand, gives whether it is negated and the pattern
+fn parse_like(i: &str) -> ParseResult<(bool, Spanned)> {
+    let negated = map(opt(terminated(keyword("not"), multispace0)), |not| not.is_some());
+    preceded(multispace0, pair(negated, preceded(keyword("like"), cut(parse_bit_or))))(i)
+}
+
+/// Comparisons don't chain, `a < b < c` is an error
+fn parse_comparison(i: &str) -> ParseResult<Spanned> {
+    let (i, left) = parse_bit_or(i)?;
+    match parse_in_or_between(i) {
+        Ok((rest, (fun, operands))) => return Ok((rest, application(fun, [left].into_iter().chain(operands).collect()))),
+        Err(nom::Err::Error(_)) => {},
+        Err(e) => return Err(e),
+    }
+    match parse_is_null(i) {
+        Ok((rest, negated)) => {
+            let span = Span { start: left.1.span.start, end: rest.len() };
+            let (expr, mut spans) = application(BuiltIn::IsNull, vec![left]);
+            spans.span = span;
+            if !negated {
+                return Ok((rest, (expr, spans)));
+            }
+            // `a IS NOT NULL` is `(= (is-null a) #f)`
+            let not = (Expr::Constant(Atom::Boolean(false)), ExprSpans { span, args: Vec::new() });
+            return Ok((rest, application(BuiltIn::Equal, vec![(expr, spans), not])));
+        },
+        Err(nom::Err::Error(_)) => {},
+        Err(e) => return Err(e),
+    }
+    match parse_like(i) {
+        Ok((rest, (negated, pattern))) => {
+            let like = application(BuiltIn::Like, vec![left, pattern]);
+            if !negated {
+                return Ok((rest, like));
+            }
+            // `a NOT LIKE p` is `(= (like a p) #f)`
+            let not = (Expr::Constant(Atom::Boolean(false)), ExprSpans { span: like.1.span, args: Vec::new() });
+            return Ok((rest, application(BuiltIn::Equal, vec![like, not])));
+        },
+        Err(nom::Err::Error(_)) => {},
+        Err(e) => return Err(e),
+    }
+    let operator = alt((
+        // Have to come before "<", ">" and "=" since alt takes the first match
+        value(BuiltIn::LessThanOrEqual, tag("<=")),
+        value(BuiltIn::GreaterThanOrEqual, tag(">=")),
+        value(BuiltIn::NotEqual, alt((tag("<>"), tag("!=")))),
+        value(BuiltIn::Equal, tag("=")),
+        value(BuiltIn::LessThan, tag("<")),
+        value(BuiltIn::GreaterThan, tag(">")),
+    ));
+    match opt(preceded(multispace0, operator))(i)? {
+        (rest, Some(fun)) => {
+            let (rest, right) = cut(parse_bit_or)(rest)?;
+            Ok((rest, application(fun, vec![left, right])))
+        },
+        (rest, None) => Ok((rest, left)),
+    }
+}
+
+fn parse_and(i: &str) -> ParseResult<Spanned> {
+    binary_chain(i, parse_comparison, value(BuiltIn::And, keyword("and")))
+}
+
+fn parse_expr(i: &str) -> ParseResult<Spanned> {
+    binary_chain(i, parse_and, value(BuiltIn::Or, keyword("or")))
+}
+
+/// `count(*)`, `count(DISTINCT expr)`, another aggregate function like `sum(expr)` or just an expression
+fn parse_select_item(i: &str) -> ParseResult<(Option<AggregateFunc>, Spanned)> {
+    let (start, _) = multispace0(i)?;
+    let call = terminated(identifier, pair(multispace0, char('(')))(start);
+    let aggregate = call.ok().and_then(|(rest, name)| Some((rest, AggregateFunc::from_name(name)?)));
+    let Some((rest, aggregate)) = aggregate else {
+        return map(parse_expr, |expr| (None, expr))(i);
+    };
+    if aggregate == AggregateFunc::Count {
+        let star: ParseResult<_> = tuple((multispace0, char('*'), multispace0, char(')')))(rest);
+        if let Ok((rest, _)) = star {
+            // There is no expression to aggregate so we just count a constant for every row
+            let spans = ExprSpans { span: Span { start: start.len(), end: rest.len() }, args: Vec::new() };
+            return Ok((rest, (Some(AggregateFunc::CountStar), (Expr::Constant(Atom::Num(1)), spans))));
+        }
+    }
+    let (after_distinct, distinct) = opt(preceded(multispace0, keyword("distinct")))(rest)?;
+    let aggregate = match (aggregate, distinct) {
+        (AggregateFunc::Count, Some(_)) => AggregateFunc::CountDistinct,
+        (_, Some(_)) => return failure(rest, "only count supports DISTINCT"),
+        (aggregate, None) => aggregate,
+    };
+    let rest = after_distinct;
+    let (rest, expr) = terminated(cut(parse_expr), context("expected `)`", cut(preceded(multispace0, char(')')))))(rest)?;
+    Ok((rest, (Some(aggregate), expr)))
+}
+
+/// Parses `SELECT <expression or aggregate> [FROM <table> [JOIN <table> ON <left key> = <right key>]] [WHERE <filter>]
+/// [ORDER BY <expression> [ASC|DESC]] [LIMIT <n>] [;]` and remembers where each of the expressions is in `original`.
+/// The tables are the ones the query runs on (`l`) and joins with (`r`), so their names are ignored.
+pub fn parse_sql_query(original: &str) -> Result<(Query, QuerySpans), ParseError> {
+    let to_parse_error = |e| err_converter(original, e);
+    let (src, _) = preceded(multispace0, context("expected `SELECT`", keyword("select")))(original).map_err(to_parse_error)?;
+    let (src, select) = parse_select_item(src).map_err(to_parse_error)?;
+    let join_clause = preceded(
+        tuple((
+            keyword("join"),
+            context("expected a table name after `JOIN`", cut(preceded(multispace0, identifier))),
+            multispace0,
+            context("expected `ON` after the table name", cut(keyword("on"))),
+        )),
+        context("expected a join condition after `ON`", cut(parse_expr)),
+    );
+    let from_clause = preceded(keyword("from"), context("expected a table name after `FROM`", cut(preceded(multispace0, identifier))));
+    let (src, join) = opt(preceded(pair(multispace0, from_clause), opt(preceded(multispace0, join_clause))))(src).map_err(to_parse_error)?;
+    let where_clause = preceded(keyword("where"), cut(parse_expr));
+    let (src, filter) = opt(preceded(multispace0, where_clause))(src).map_err(to_parse_error)?;
+    let (src, order_by_limit) = parse_order_by_limit(src, parse_expr).map_err(to_parse_error)?;
+    let (src, _) = opt(preceded(multispace0, char(';')))(src).map_err(to_parse_error)?;
+    finish_query(original, src, "`FROM`, `JOIN`, `WHERE`, `ORDER BY`, `LIMIT` or the end of the query", select, join.flatten(), filter, order_by_limit)
+}
+
+/// Writes a query in SQL syntax. Parsing the result gives a query that computes the same values,
+/// although applications with a single operand (like `(- x)`, which is just `x`) are left out.
+/// The tests use it to check that both syntaxes parse to the same queries.
+#[cfg(test)]
+pub fn to_sql(query: &Query) -> String {
+    let select = match query.aggregate {
+        None => expr_to_sql(&query.expr),
+        Some(AggregateFunc::CountStar) => "count(*)".to_string(),
+        Some(AggregateFunc::CountDistinct) => format!("count(DISTINCT {})", expr_to_sql(&query.expr)),
+        Some(aggregate) => format!("{}({})", aggregate.name(), expr_to_sql(&query.expr)),
+    };
+    let mut sql = match &query.join {
+        Some(join) => format!("SELECT {} FROM l JOIN r ON {} = {}", select, expr_to_sql(&join.left_key), expr_to_sql(&join.right_key)),
+        None => format!("SELECT {} FROM t", select),
+    };
+    if let Some(filter) = &query.filter {
+        sql += &format!(" WHERE {}", expr_to_sql(filter));
+    }
+    if let Some(order_by) = &query.order_by {
+        sql += &format!(" ORDER BY {} {}", expr_to_sql(&order_by.expr), if order_by.descending { "DESC" } else { "ASC" });
+    }
+    if let Some(limit) = query.limit {
+        sql += &format!(" LIMIT {}", limit);
+    }
+    sql
+}
+
+#[cfg(test)]
+fn expr_to_sql(expr: &Expr) -> String {
+    match expr {
+        Expr::Constant(Atom::Num(n)) => n.to_string(),
+        Expr::Constant(Atom::Boolean(b)) => if *b { "TRUE" } else { "FALSE" }.to_string(),
+        Expr::Constant(Atom::Null) => "NULL".to_string(),
+        // Quoted and escaped the same way in both syntaxes
+        Expr::Constant(s @ Atom::Str(_)) => s.to_string(),
+        Expr::Variable(i) => format!("${}", i),
+        Expr::Parameter(name) if name.parse::<usize>().is_ok() => format!("?{}", name),
+        Expr::Parameter(name) => format!(":{}", name),
+        Expr::Column(name) if parse_column(name).is_ok_and(|(rest, _)| rest.is_empty()) => name.clone(),
+        Expr::Column(name) => format!("\"{}\"", name),
+        Expr::Joined(side, column) => format!("{}{}", side.prefix(), expr_to_sql(column)),
+        Expr::Application(BuiltIn::In, args) => {
+            let values = args[1..].iter().map(expr_to_sql).collect::<Vec<_>>();
+            format!("({} IN ({}))", expr_to_sql(&args[0]), values.join(", "))
+        },
+        Expr::Application(BuiltIn::Between, args) => {
+            let bounds = args[1..].iter().map(expr_to_sql).collect::<Vec<_>>();
+            format!("({} BETWEEN {})", expr_to_sql(&args[0]), bounds.join(" AND "))
+        },
+        Expr::Application(BuiltIn::IsNull, args) if args.len() == 1 => format!("({} IS NULL)", expr_to_sql(&args[0])),
+        Expr::Application(BuiltIn::Coalesce, args) => {
+            format!("coalesce({})", args.iter().map(expr_to_sql).collect::<Vec<_>>().join(", "))
+        },
+        Expr::Application(BuiltIn::StartsWith, args) => {
+            format!("starts_with({})", args.iter().map(expr_to_sql).collect::<Vec<_>>().join(", "))
+        },
+        Expr::Application(BuiltIn::Length, args) => {
+            format!("length({})", args.iter().map(expr_to_sql).collect::<Vec<_>>().join(", "))
+        },
+        Expr::Application(BuiltIn::Like, args) if args.len() == 2 => format!("({} LIKE {})", expr_to_sql(&args[0]), expr_to_sql(&args[1])),
+        Expr::Application(_, args) if args.len() == 1 => expr_to_sql(&args[0]),
+        // Every application is parenthesized so precedence doesn't matter
+        Expr::Application(fun, args) => {
+            let args = args.iter().map(expr_to_sql).collect::<Vec<_>>();
+            format!("({})", args.join(&format!(" {} ", fun.symbol())))
+        },
+    }
+}
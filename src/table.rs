@@ -0,0 +1,388 @@
+// In memory table with the layout described by its schema. This is what generated code
+// and the interpreter run on.
+
+use std::{error::Error, fs::File, ops::Range, path::Path, sync::Arc};
+
+use csv::ReaderBuilder;
+
+use crate::{codegen::{get_data_type_size, ir::{ConstValue, DataType}}, query::Atom, schema::{ColumnDef, Schema}};
+
+/// How the values of a table are arranged in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// One row after another, as described by the `Schema`
+    RowMajor,
+    /// Every column is stored in its own densely packed array
+    Columnar,
+}
+
+/// A value of a row that is appended to a table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Const(ConstValue),
+    Str(&'a str),
+}
+
+/// The values of a string column: the bytes of all strings one after another and where each of
+/// them starts. `offsets` has one more entry than there are rows, so the string of row `r` is
+/// `bytes[offsets[r]..offsets[r + 1]]`. NULLs are empty strings.
+#[derive(Debug, Clone, Default)]
+struct Strings {
+    offsets: Vec<u64>,
+    bytes: Vec<u8>,
+}
+
+pub struct Table {
+    schema: Schema,
+    layout: Layout,
+    // A single buffer for row major tables and one buffer per column otherwise.
+    // u64 so that every column is properly aligned
+    buffers: Vec<Vec<u64>>,
+    // One bitmap per nullable column (None for the others), a set bit means that the row has a value.
+    // The value of a NULL is stored as 0.
+    validity: Vec<Option<Vec<u64>>>,
+    // The values of each string column (None for the others), the buffers only reserve 0 bytes for them
+    strings: Vec<Option<Strings>>,
+    rows: usize,
+}
+
+impl Table {
+    pub fn new(schema: Schema) -> Self {
+        Table::with_layout(schema, Layout::RowMajor)
+    }
+
+    pub fn with_layout(schema: Schema, layout: Layout) -> Self {
+        let buffers = match layout {
+            Layout::RowMajor => vec![Vec::new()],
+            Layout::Columnar => vec![Vec::new(); schema.column_count()],
+        };
+        let validity = schema.columns().iter().map(|c| c.nullable.then(Vec::new)).collect();
+        let strings = schema.columns().iter().map(|c| (c.data_type == DataType::Str).then(|| Strings { offsets: vec![0], bytes: Vec::new() })).collect();
+        Table { schema, layout, buffers, validity, strings, rows: 0 }
+    }
+
+    /// Creates a table of unnamed i64 columns from row-major data
+    pub fn from_i64(columns: usize, data: &[i64]) -> Self {
+        let mut table = Table::new(Schema::unnamed(columns));
+        for row in data.chunks_exact(columns) {
+            table.push_row(&row.iter().map(|v| ConstValue::I64(*v)).collect::<Vec<_>>());
+        }
+        table
+    }
+
+    /// Copies the table into the given layout
+    pub fn to_layout(&self, layout: Layout) -> Table {
+        let mut table = Table::with_layout(self.schema.clone(), layout);
+        for row in 0..self.rows {
+            let values = (0..self.schema.column_count()).map(|column| (!self.is_null(row, column)).then(|| match self.strings[column] {
+                Some(_) => Value::Str(self.string(row, column)),
+                None => Value::Const(self.value(row, column)),
+            }));
+            table.push_nullable_row(&values.collect::<Vec<_>>());
+        }
+        table
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// The arguments for code generated for this table's layout: a pointer to the data (one per
+    /// column for columnar tables), a pointer to the offsets and one to the bytes of every string column,
+    /// then if there are nullable columns a pointer to the validity bitmap of each of them and the index
+    /// of the first row's bit in the bitmaps, followed by the number of rows
+    pub fn call_args(&self) -> Vec<usize> {
+        self.call_args_for(0..self.rows)
+    }
+
+    /// Like `call_args`, but the code only sees the rows in `rows`
+    pub fn call_args_for(&self, rows: Range<usize>) -> Vec<usize> {
+        let mut args = self.buffers.iter().enumerate().map(|(buffer, b)| {
+            let row_size = match self.layout {
+                Layout::RowMajor => self.schema.row_size(),
+                Layout::Columnar => get_data_type_size(&self.schema.columns()[buffer].data_type),
+            };
+            b.as_ptr() as usize + rows.start * row_size
+        }).collect::<Vec<_>>();
+        for strings in self.strings.iter().flatten() {
+            args.push(strings.offsets.as_ptr() as usize + rows.start * 8);
+            args.push(strings.bytes.as_ptr() as usize);
+        }
+        let bitmaps = self.validity.iter().flatten().collect::<Vec<_>>();
+        if !bitmaps.is_empty() {
+            args.extend(bitmaps.iter().map(|bitmap| bitmap.as_ptr() as usize + rows.start / 64 * 8));
+            args.push(rows.start % 64);
+        }
+        args.push(rows.len());
+        args
+    }
+
+    /// Buffer and byte offset inside of it of a single value
+    fn location(&self, row: usize, column: usize) -> (usize, usize) {
+        match self.layout {
+            Layout::RowMajor => (0, row * self.schema.row_size() + self.schema.column_offset(column)),
+            Layout::Columnar => (column, row * get_data_type_size(&self.schema.columns()[column].data_type)),
+        }
+    }
+
+    fn bytes(&self, buffer: usize) -> &[u8] {
+        let buffer = &self.buffers[buffer];
+        unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const u8, buffer.len() * 8) }
+    }
+
+    fn bytes_mut(&mut self, buffer: usize) -> &mut [u8] {
+        let buffer = &mut self.buffers[buffer];
+        unsafe { std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, buffer.len() * 8) }
+    }
+
+    /// Appends a row without strings, the values must have exactly the types of the columns
+    pub fn push_row(&mut self, values: &[ConstValue]) {
+        self.push_nullable_row(&values.iter().copied().map(|v| Some(Value::Const(v))).collect::<Vec<_>>());
+    }
+
+    /// Like `push_row`, None is NULL (which only nullable columns can contain)
+    pub fn push_nullable_row(&mut self, values: &[Option<Value>]) {
+        assert_eq!(values.len(), self.schema.column_count(), "Row doesn't match the schema");
+        match self.layout {
+            Layout::RowMajor => {
+                let len = (self.rows + 1) * self.schema.row_size() / 8;
+                self.buffers[0].resize(len, 0);
+            },
+            Layout::Columnar => {
+                for (buffer, column) in self.buffers.iter_mut().zip(self.schema.columns()) {
+                    let len = ((self.rows + 1) * get_data_type_size(&column.data_type)).div_ceil(8);
+                    buffer.resize(len, 0);
+                }
+            },
+        }
+        for (i, (bitmap, value)) in self.validity.iter_mut().zip(values).enumerate() {
+            match bitmap {
+                Some(bitmap) => {
+                    if self.rows.is_multiple_of(64) {
+                        bitmap.push(0);
+                    }
+                    if value.is_some() {
+                        *bitmap.last_mut().unwrap() |= 1 << (self.rows % 64);
+                    }
+                },
+                None => assert!(value.is_some(), "Column {} can't be NULL", self.schema.display_name(i)),
+            }
+        }
+        for (strings, value) in self.strings.iter_mut().zip(values) {
+            if let Some(strings) = strings {
+                match value {
+                    Some(Value::Str(s)) => strings.bytes.extend_from_slice(s.as_bytes()),
+                    Some(Value::Const(_)) => panic!("Value doesn't match the column type"),
+                    None => {},
+                }
+                strings.offsets.push(strings.bytes.len() as u64);
+            }
+        }
+        for (i, value) in values.iter().enumerate() {
+            let Some(Value::Const(value)) = value else {
+                continue;
+            };
+            let data_type = self.schema.columns()[i].data_type;
+            assert_eq!(value.get_type(), data_type, "Value doesn't match the column type");
+            let size = get_data_type_size(&data_type);
+            let (buffer, start) = self.location(self.rows, i);
+            self.bytes_mut(buffer)[start..start + size].copy_from_slice(&value.bitcast_to_u64().to_le_bytes()[..size]);
+        }
+        self.rows += 1;
+    }
+
+    pub fn is_null(&self, row: usize, column: usize) -> bool {
+        self.validity[column].as_ref().is_some_and(|bitmap| bitmap[row / 64] & (1 << (row % 64)) == 0)
+    }
+
+    /// The value of a string column, which is empty for NULLs
+    pub fn string(&self, row: usize, column: usize) -> &str {
+        let strings = self.strings[column].as_ref().expect("Not a string column");
+        let bytes = &strings.bytes[strings.offsets[row] as usize..strings.offsets[row + 1] as usize];
+        // Only whole strings are ever appended
+        std::str::from_utf8(bytes).unwrap()
+    }
+
+    /// The stored value, which is 0 for NULLs (see `is_null`). Not for string columns (see `string`).
+    pub fn value(&self, row: usize, column: usize) -> ConstValue {
+        let data_type = self.schema.columns()[column].data_type;
+        let size = get_data_type_size(&data_type);
+        let (buffer, start) = self.location(row, column);
+        let mut raw = [0u8; 8];
+        raw[..size].copy_from_slice(&self.bytes(buffer)[start..start + size]);
+        let raw = u64::from_le_bytes(raw);
+        match data_type {
+            DataType::Bool => ConstValue::Bool(raw != 0),
+            DataType::I8 => ConstValue::I8(raw as i8),
+            DataType::I16 => ConstValue::I16(raw as i16),
+            DataType::I32 => ConstValue::I32(raw as i32),
+            DataType::I64 => ConstValue::I64(raw as i64),
+            DataType::U8 => ConstValue::U8(raw as u8),
+            DataType::U16 => ConstValue::U16(raw as u16),
+            DataType::U32 => ConstValue::U32(raw as u32),
+            DataType::U64 | DataType::Ptr => ConstValue::U64(raw),
+            DataType::F32 | DataType::F64 => unreachable!("`Schema::new` rejects floating point columns"),
+            DataType::Str => panic!("Strings aren't constant values"),
+        }
+    }
+
+    /// The value as the query language sees it (integers widened to i64).
+    pub fn atom(&self, row: usize, column: usize) -> Atom {
+        if self.is_null(row, column) {
+            return Atom::Null;
+        }
+        if self.strings[column].is_some() {
+            return Atom::Str(Arc::from(self.string(row, column)));
+        }
+        match self.value(row, column) {
+            ConstValue::Bool(b) => Atom::Boolean(b),
+            ConstValue::I8(v) => Atom::Num(v as i64),
+            ConstValue::I16(v) => Atom::Num(v as i64),
+            ConstValue::I32(v) => Atom::Num(v as i64),
+            ConstValue::I64(v) => Atom::Num(v),
+            ConstValue::U8(v) => Atom::Num(v as i64),
+            ConstValue::U16(v) => Atom::Num(v as i64),
+            ConstValue::U32(v) => Atom::Num(v as i64),
+            // Reinterpreted just like in the generated code
+            ConstValue::U64(v) => Atom::Num(v as i64),
+            ConstValue::F32(_) | ConstValue::F64(_) => unreachable!("`Schema::new` rejects floating point columns"),
+        }
+    }
+
+    /// Appends the values of a column in `rows` to `out` like `atom` would read them, with booleans
+    /// as 0 or 1 (and strings and NULLs as 0). Much faster than reading them one at a time.
+    pub fn read_column(&self, column: usize, rows: Range<usize>, out: &mut Vec<i64>) {
+        fn read<const N: usize>(bytes: &[u8], start: usize, stride: usize, count: usize, out: &mut Vec<i64>, convert: impl Fn([u8; N]) -> i64) {
+            out.extend((0..count).map(|i| {
+                let offset = start + i * stride;
+                convert(bytes[offset..offset + N].try_into().unwrap())
+            }));
+        }
+        let data_type = self.schema.columns()[column].data_type;
+        let stride = match self.layout {
+            Layout::RowMajor => self.schema.row_size(),
+            Layout::Columnar => get_data_type_size(&data_type),
+        };
+        let (buffer, start) = self.location(rows.start, column);
+        let (bytes, count) = (self.bytes(buffer), rows.len());
+        match data_type {
+            DataType::Bool => read(bytes, start, stride, count, out, |b: [u8; 1]| (b[0] != 0) as i64),
+            DataType::I8 => read(bytes, start, stride, count, out, |b| i8::from_le_bytes(b) as i64),
+            DataType::I16 => read(bytes, start, stride, count, out, |b| i16::from_le_bytes(b) as i64),
+            DataType::I32 => read(bytes, start, stride, count, out, |b| i32::from_le_bytes(b) as i64),
+            DataType::I64 | DataType::U64 | DataType::Ptr => read(bytes, start, stride, count, out, i64::from_le_bytes),
+            DataType::U8 => read(bytes, start, stride, count, out, |b| u8::from_le_bytes(b) as i64),
+            DataType::U16 => read(bytes, start, stride, count, out, |b| u16::from_le_bytes(b) as i64),
+            DataType::U32 => read(bytes, start, stride, count, out, |b| u32::from_le_bytes(b) as i64),
+            DataType::F32 | DataType::F64 | DataType::Str => out.resize(out.len() + count, 0),
+        }
+    }
+}
+
+fn parse_bool(field: &str) -> Option<bool> {
+    match field.to_ascii_lowercase().as_str() {
+        "true" | "#t" => Some(true),
+        "false" | "#f" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_value(field: &str, data_type: DataType) -> Result<ConstValue, String> {
+    fn parse<T: std::str::FromStr>(field: &str) -> Result<T, String> where T::Err: std::fmt::Display {
+        field.parse::<T>().map_err(|e| e.to_string())
+    }
+    Ok(match data_type {
+        DataType::Bool => ConstValue::Bool(parse_bool(field).ok_or("not a boolean")?),
+        DataType::I8 => ConstValue::I8(parse(field)?),
+        DataType::I16 => ConstValue::I16(parse(field)?),
+        DataType::I32 => ConstValue::I32(parse(field)?),
+        DataType::I64 => ConstValue::I64(parse(field)?),
+        DataType::U8 => ConstValue::U8(parse(field)?),
+        DataType::U16 => ConstValue::U16(parse(field)?),
+        DataType::U32 => ConstValue::U32(parse(field)?),
+        DataType::U64 => ConstValue::U64(parse(field)?),
+        DataType::F32 | DataType::F64 => return Err("floating point columns aren't supported".to_string()),
+        DataType::Ptr => return Err("pointers can't be stored in tables".to_string()),
+        DataType::Str => unreachable!("Strings are taken as they are"),
+    })
+}
+
+/// Picks the narrowest type all values of a column fit into. Empty fields are NULLs and don't count.
+fn infer_type<'a>(mut fields: impl Iterator<Item = &'a str> + Clone) -> DataType {
+    if fields.clone().next().is_none() {
+        return DataType::I64;
+    }
+    if fields.clone().all(|f| parse_bool(f).is_some()) {
+        return DataType::Bool;
+    }
+    if let Some((min, max)) = fields.clone().try_fold((0i64, 0i64), |(min, max), f| {
+        f.parse::<i64>().ok().map(|v| (min.min(v), max.max(v)))
+    }) {
+        return [DataType::I8, DataType::I16, DataType::I32].into_iter()
+            .find(|ty| {
+                let bits = get_data_type_size(ty) * 8 - 1;
+                min >= -(1 << bits) && max < (1 << bits)
+            })
+            .unwrap_or(DataType::I64);
+    }
+    // Not supported yet, `Schema::new` rejects the column instead of taking the numbers as strings
+    if fields.all(|f| f.parse::<f64>().is_ok()) {
+        return DataType::F64;
+    }
+    DataType::Str
+}
+
+/// Reads a CSV file. If `has_header` is set the first record provides the column names.
+/// Without an explicit schema the column types are inferred from the data and columns with empty
+/// fields are nullable. Empty fields are NULLs, which only nullable columns can contain, except for
+/// string columns that can't be NULL where they are empty strings.
+pub fn load_csv(path: &Path, has_header: bool, schema: Option<Schema>) -> Result<Table, Box<dyn Error>> {
+    let mut rdr = ReaderBuilder::new().has_headers(has_header).trim(csv::Trim::All).from_reader(File::open(path)?);
+    let names = if has_header {
+        Some(rdr.headers()?.iter().map(|h| h.to_string()).collect::<Vec<_>>())
+    } else {
+        None
+    };
+    // The csv reader already makes sure that all records have the same length
+    let records = rdr.records().collect::<Result<Vec<_>, _>>()?;
+    let columns = names.as_ref().map_or(records.first().map_or(0, |r| r.len()), |n| n.len());
+
+    let schema = match schema {
+        Some(schema) => {
+            if schema.column_count() != columns {
+                return Err(format!("The schema has {} columns but the csv file has {}", schema.column_count(), columns).into());
+            }
+            schema
+        },
+        None => Schema::new((0..columns).map(|i| ColumnDef {
+            name: names.as_ref().map(|n| n[i].clone()),
+            data_type: infer_type(records.iter().map(move |r| &r[i]).filter(|field| !field.is_empty())),
+            nullable: records.iter().any(|r| r[i].is_empty()),
+        }).collect()).map_err(|e| format!("{} (declare it as str in a schema file to load it anyway)", e))?,
+    };
+
+    let mut table = Table::new(schema);
+    for (row, record) in records.iter().enumerate() {
+        let values = record.iter().enumerate().map(|(i, field)| {
+            let ColumnDef { data_type, nullable, .. } = table.schema().columns()[i];
+            match field {
+                "" if nullable => Ok(None),
+                _ if data_type == DataType::Str => Ok(Some(Value::Str(field))),
+                "" => Err(format!("Row {}, column {}: the value is missing but the column can't be NULL (add `null` to its line in the schema)", row + 1, table.schema().display_name(i))),
+                _ => parse_value(field, data_type).map(|v| Some(Value::Const(v))).map_err(|e| {
+                    format!("Row {}, column {}: can't parse \"{}\" as {} ({})", row + 1, table.schema().display_name(i), field, data_type, e)
+                }),
+            }
+        }).collect::<Result<Vec<_>, _>>()?;
+        table.push_nullable_row(&values);
+    }
+    Ok(table)
+}
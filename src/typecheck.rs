@@ -0,0 +1,149 @@
+// Type checking of queries. Every expression gets a type before any code is generated, so the
+// interpreter, `simplify` and the code generator can rely on the operands of an operation having the
+// types it expects. Errors point to the expression that caused them if the spans from parsing are known.
+
+use std::fmt::{self, Display, Formatter};
+
+use crate::{codegen::ir::DataType, query::{Atom, BuiltIn, Expr, ExprSpans, Span}, schema::Schema};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TypeError {
+    pub message: String,
+    /// The expression that caused the error, if known
+    pub span: Option<Span>,
+}
+
+impl TypeError {
+    pub fn new(message: impl Into<String>, span: Option<Span>) -> Self {
+        TypeError { message: message.into(), span }
+    }
+
+    /// The error message below the query, with carets marking the expression that caused it
+    pub fn diagnostic(&self, src: &str) -> String {
+        match self.span {
+            Some(span) => span.diagnostic(src, &self.message),
+            None => self.message.clone(),
+        }
+    }
+}
+
+impl Display for TypeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// The type of an expression and where it is in the query string
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TypedExpr {
+    pub data_type: DataType,
+    pub span: Option<Span>,
+}
+
+/// Infers the type of `expr`, checking all of its subexpressions. `spans` are the spans `expr` was
+/// parsed with (see `parse_query_with_spans`), if there are any.
+pub fn type_expr(expr: &Expr, spans: Option<&ExprSpans>, schema: &Schema) -> Result<TypedExpr, TypeError> {
+    let span = spans.map(|s| s.span);
+    let data_type = match expr {
+        Expr::Constant(Atom::Num(_)) => DataType::I64,
+        Expr::Constant(Atom::Boolean(_)) => DataType::Bool,
+        Expr::Constant(Atom::Str(_)) => DataType::Str,
+        Expr::Constant(Atom::Null) => {
+            return Err(TypeError::new("NULL can't be used as a constant, use `is-null` to check for it".to_string(), span));
+        },
+        Expr::Variable(i) if *i >= schema.column_count() => {
+            let message = format!("Column ${} doesn't exist, the input only has {} columns", i, schema.column_count());
+            return Err(TypeError::new(message, span));
+        },
+        Expr::Variable(i) => schema.value_type(*i),
+        Expr::Parameter(_) => DataType::I64,
+        Expr::Column(name) => {
+            return Err(TypeError::new(format!("Column \"{}\" has not been resolved", name), span));
+        },
+        Expr::Joined(side, column) => {
+            return Err(TypeError::new(format!("Column {} has not been resolved", side.qualify(column)), span));
+        },
+        Expr::Application(fun, args) => {
+            let args = args.iter().enumerate()
+                .map(|(i, arg)| type_expr(arg, spans.and_then(|s| s.args.get(i)), schema))
+                .collect::<Result<Vec<_>, _>>()?;
+            type_application(*fun, &args, span)?
+        },
+    };
+    Ok(TypedExpr { data_type, span })
+}
+
+fn type_application(fun: BuiltIn, args: &[TypedExpr], span: Option<Span>) -> Result<DataType, TypeError> {
+    let is_comparison = matches!(fun, BuiltIn::Equal | BuiltIn::NotEqual | BuiltIn::LessThan | BuiltIn::GreaterThan
+        | BuiltIn::LessThanOrEqual | BuiltIn::GreaterThanOrEqual);
+    // The code generator only compares two values at a time
+    if is_comparison && args.len() != 2 {
+        return Err(TypeError::new(format!("`{}` takes exactly 2 operands, found {}", fun.symbol(), args.len()), span));
+    }
+    if fun == BuiltIn::Between && args.len() != 3 {
+        return Err(TypeError::new(format!("`between` takes exactly 3 operands, found {}", args.len()), span));
+    }
+    if matches!(fun, BuiltIn::IsNull | BuiltIn::Length) && args.len() != 1 {
+        return Err(TypeError::new(format!("`{}` takes exactly 1 operand, found {}", fun.symbol(), args.len()), span));
+    }
+    if matches!(fun, BuiltIn::StartsWith | BuiltIn::Like) && args.len() != 2 {
+        return Err(TypeError::new(format!("`{}` takes exactly 2 operands, found {}", fun.symbol(), args.len()), span));
+    }
+    if fun == BuiltIn::In && args.len() < 2 {
+        return Err(TypeError::new(format!("`in` takes at least 2 operands, found {}", args.len()), span));
+    }
+    if args.is_empty() {
+        return Err(TypeError::new(format!("`{}` takes at least 1 operand, found 0", fun.symbol()), span));
+    }
+
+    match fun {
+        BuiltIn::IsNull => Ok(DataType::Bool),
+        BuiltIn::StartsWith | BuiltIn::Like | BuiltIn::Length => {
+            if let Some(arg) = args.iter().find(|arg| arg.data_type != DataType::Str) {
+                let message = format!("`{}` expects {} operands, found {}", fun.symbol(), DataType::Str, arg.data_type);
+                return Err(TypeError::new(message, arg.span));
+            }
+            Ok(if fun == BuiltIn::Length { DataType::I64 } else { DataType::Bool })
+        },
+        // Work on integers, booleans and strings, as long as all operands have the same type. Strings
+        // can only be compared.
+        BuiltIn::Equal | BuiltIn::NotEqual | BuiltIn::And | BuiltIn::Or | BuiltIn::Coalesce => {
+            let first = args[0].data_type;
+            if let Some(arg) = args.iter().find(|arg| arg.data_type != first) {
+                let message = format!("Operands of `{}` must have the same type, the first one is {} but this one is {}", fun.symbol(), first, arg.data_type);
+                return Err(TypeError::new(message, arg.span));
+            }
+            if first == DataType::Str && matches!(fun, BuiltIn::And | BuiltIn::Or) {
+                return Err(TypeError::new(format!("`{}` expects {} or {} operands, found {}", fun.symbol(), DataType::I64, DataType::Bool, first), args[0].span));
+            }
+            Ok(if is_comparison { DataType::Bool } else { first })
+        },
+        // Ordered by value or byte by byte, `in` compares for equality
+        _ if (is_comparison || fun == BuiltIn::In) && args[0].data_type == DataType::Str => {
+            if let Some(arg) = args.iter().find(|arg| arg.data_type != DataType::Str) {
+                let message = format!("Operands of `{}` must have the same type, the first one is {} but this one is {}", fun.symbol(), DataType::Str, arg.data_type);
+                return Err(TypeError::new(message, arg.span));
+            }
+            Ok(DataType::Bool)
+        },
+        _ => {
+            if let Some(arg) = args.iter().find(|arg| arg.data_type != DataType::I64) {
+                let message = format!("`{}` expects {} operands, found {}", fun.symbol(), DataType::I64, arg.data_type);
+                return Err(TypeError::new(message, arg.span));
+            }
+            Ok(if is_comparison || matches!(fun, BuiltIn::In | BuiltIn::Between) { DataType::Bool } else { DataType::I64 })
+        },
+    }
+}
+
+/// Whether the value of a well typed expression can be NULL, given which columns can contain NULLs
+/// (see `Schema::nullable_columns`). Columns that aren't in `nullable_columns` can't be NULL.
+pub fn can_be_null(expr: &Expr, nullable_columns: &[bool]) -> bool {
+    match expr {
+        Expr::Variable(i) => nullable_columns.get(*i).copied().unwrap_or(false),
+        Expr::Constant(_) | Expr::Parameter(_) | Expr::Column(_) | Expr::Joined(..) => false,
+        Expr::Application(BuiltIn::IsNull, _) => false,
+        Expr::Application(BuiltIn::Coalesce, args) => args.iter().all(|arg| can_be_null(arg, nullable_columns)),
+        Expr::Application(_, args) => args.iter().any(|arg| can_be_null(arg, nullable_columns)),
+    }
+}
@@ -0,0 +1,245 @@
+// Vectorized interpreter: a baseline for the generated code that is closer to what real engines do than
+// `query::run_query`. The table is processed in batches of `BATCH_SIZE` rows, the referenced columns of
+// a batch are read into plain i64 arrays (booleans as 0 or 1) and every operation of an expression runs
+// in a tight loop over all rows of the batch it applies to. Filters don't produce boolean vectors but
+// selection vectors, the indices of the rows that pass. `&` narrows the selection operand by operand
+// and `|` only evaluates an operand for the rows the earlier ones didn't select, so operands are
+// skipped for the same rows as with the short circuiting of the other engines.
+
+use std::ops::Range;
+
+use crate::{codegen::ir::DataType, query::{AggregateFunc, AggregateState, Atom, BuiltIn, EvalError, Expr, Query, TopK}, query_codegen::get_type, table::Table};
+
+/// Rows that are processed at a time
+pub const BATCH_SIZE: usize = 1024;
+
+/// The referenced columns of the rows of one batch
+struct Batch<'a> {
+    /// Indexed by column, empty for columns the query doesn't reference
+    columns: Vec<Vec<i64>>,
+    column_types: &'a [DataType],
+}
+
+impl Batch<'_> {
+    /// The value of `expr` for each of the rows in `selection`
+    fn eval(&self, expr: &Expr, selection: &[u32]) -> Result<Vec<i64>, EvalError> {
+        Ok(match expr {
+            Expr::Constant(Atom::Num(n)) => vec![*n; selection.len()],
+            Expr::Constant(Atom::Boolean(b)) => vec![*b as i64; selection.len()],
+            Expr::Constant(Atom::Null) => unreachable!("Queries don't contain NULL constants"),
+            Expr::Constant(Atom::Str(_)) => unreachable!("Strings aren't supported"),
+            Expr::Variable(i) => {
+                let column = &self.columns[*i];
+                selection.iter().map(|&row| column[row as usize]).collect()
+            },
+            Expr::Column(_) | Expr::Joined(..) | Expr::Parameter(_) => panic!("The query must be bound and can't have parameters"),
+            Expr::Application(BuiltIn::And | BuiltIn::Or, args) if get_type(&args[0], self.column_types) == DataType::Bool => {
+                let selected = self.select(expr, selection)?;
+                let mut selected = selected.iter().peekable();
+                selection.iter().map(|row| selected.next_if_eq(&row).is_some() as i64).collect()
+            },
+            Expr::Application(op, args) => {
+                let args = args.iter().map(|arg| self.eval(arg, selection)).collect::<Result<Vec<_>, _>>()?;
+                let mut args = args.into_iter();
+                let first = args.next().unwrap();
+                match op {
+                    // Arithmetic wraps around just like it does in the interpreter
+                    BuiltIn::Plus => fold(first, args, i64::wrapping_add),
+                    BuiltIn::Minus => fold(first, args, i64::wrapping_sub),
+                    BuiltIn::Times => fold(first, args, i64::wrapping_mul),
+                    BuiltIn::Divide => try_fold(first, args, i64::checked_div)?,
+                    BuiltIn::Rem => try_fold(first, args, i64::checked_rem)?,
+                    // Bitwise operations on integers
+                    BuiltIn::And => fold(first, args, |a, b| a & b),
+                    BuiltIn::Or => fold(first, args, |a, b| a | b),
+                    BuiltIn::LessThan => compare_first(first, args, |a, b| a < b),
+                    BuiltIn::GreaterThan => compare_first(first, args, |a, b| a > b),
+                    BuiltIn::LessThanOrEqual => compare_first(first, args, |a, b| a <= b),
+                    BuiltIn::GreaterThanOrEqual => compare_first(first, args, |a, b| a >= b),
+                    // Every operand is compared with the one before it
+                    BuiltIn::Equal => compare_adjacent(first, args, 1, |result, equal| result & equal),
+                    BuiltIn::NotEqual => compare_adjacent(first, args, 0, |result, equal| result | !equal),
+                    BuiltIn::In => any_equal(first, args),
+                    BuiltIn::Between => {
+                        let (low, high) = (args.next().unwrap(), args.next().unwrap());
+                        first.iter().zip(low).zip(high).map(|((x, low), high)| (low <= *x && *x <= high) as i64).collect()
+                    },
+                    // Without nullable columns nothing is NULL
+                    BuiltIn::IsNull => vec![0; first.len()],
+                    BuiltIn::Coalesce => first,
+                    BuiltIn::StartsWith | BuiltIn::Like | BuiltIn::Length => unreachable!("Strings aren't supported"),
+                }
+            },
+        })
+    }
+
+    /// The rows of `selection` for which the boolean expression `expr` is true, in the same order
+    fn select(&self, expr: &Expr, selection: &[u32]) -> Result<Vec<u32>, EvalError> {
+        Ok(match expr {
+            Expr::Application(BuiltIn::And, args) if get_type(&args[0], self.column_types) == DataType::Bool => {
+                let mut selection = selection.to_vec();
+                for arg in args {
+                    selection = self.select(arg, &selection)?;
+                }
+                selection
+            },
+            Expr::Application(BuiltIn::Or, args) if get_type(&args[0], self.column_types) == DataType::Bool => {
+                let mut remaining = selection.to_vec();
+                let mut selected = Vec::with_capacity(selection.len());
+                for arg in args {
+                    let arg_selected = self.select(arg, &remaining)?;
+                    let mut arg_selected_iter = arg_selected.iter().peekable();
+                    remaining.retain(|row| arg_selected_iter.next_if_eq(&row).is_none());
+                    selected.extend(arg_selected);
+                }
+                selected.sort_unstable();
+                selected
+            },
+            _ => {
+                let values = self.eval(expr, selection)?;
+                selection.iter().zip(values).filter(|(_, value)| *value != 0).map(|(&row, _)| row).collect()
+            },
+        })
+    }
+}
+
+fn fold(first: Vec<i64>, rest: impl Iterator<Item = Vec<i64>>, op: impl Fn(i64, i64) -> i64) -> Vec<i64> {
+    rest.fold(first, |mut result, arg| {
+        for (a, b) in result.iter_mut().zip(arg) {
+            *a = op(*a, b);
+        }
+        result
+    })
+}
+
+/// Like `fold` for division, whose quotient can be undefined (`checked_div` and `checked_rem`)
+fn try_fold(first: Vec<i64>, mut rest: impl Iterator<Item = Vec<i64>>, op: impl Fn(i64, i64) -> Option<i64>) -> Result<Vec<i64>, EvalError> {
+    rest.try_fold(first, |mut result, arg| {
+        for (a, b) in result.iter_mut().zip(arg) {
+            *a = op(*a, b).ok_or(if b == 0 { EvalError::DivisionByZero } else { EvalError::Overflow })?;
+        }
+        Ok(result)
+    })
+}
+
+/// `(< a b c)` is true if `a` is less than both `b` and `c`
+fn compare_first(first: Vec<i64>, rest: impl Iterator<Item = Vec<i64>>, cmp: impl Fn(i64, i64) -> bool) -> Vec<i64> {
+    let mut result = vec![1; first.len()];
+    for arg in rest {
+        for ((r, a), b) in result.iter_mut().zip(&first).zip(arg) {
+            *r &= cmp(*a, b) as i64;
+        }
+    }
+    result
+}
+
+/// `(in a b c)` is true if `a` is equal to `b` or to `c`
+fn any_equal(first: Vec<i64>, rest: impl Iterator<Item = Vec<i64>>) -> Vec<i64> {
+    let mut result = vec![0; first.len()];
+    for arg in rest {
+        for ((r, a), b) in result.iter_mut().zip(&first).zip(arg) {
+            *r |= (*a == b) as i64;
+        }
+    }
+    result
+}
+
+fn compare_adjacent(first: Vec<i64>, rest: impl Iterator<Item = Vec<i64>>, init: i64, combine: impl Fn(bool, bool) -> bool) -> Vec<i64> {
+    let mut result = vec![init; first.len()];
+    let mut previous = first;
+    for arg in rest {
+        for ((r, a), b) in result.iter_mut().zip(&previous).zip(&arg) {
+            *r = combine(*r != 0, a == b) as i64;
+        }
+        previous = arg;
+    }
+    result
+}
+
+/// Adds the values of the query's expression for the rows of a batch that passed the filter
+fn aggregate(state: &mut AggregateState, values: &[i64], result_consumer: &mut impl FnMut(Atom)) {
+    let aggregate_values = &mut state.values;
+    match state.aggregate {
+        Some(AggregateFunc::Sum) => aggregate_values[0] = values.iter().fold(aggregate_values[0], |a, v| a.wrapping_add(*v)),
+        Some(AggregateFunc::Prod) => aggregate_values[0] = values.iter().fold(aggregate_values[0], |a, v| a.wrapping_mul(*v)),
+        Some(AggregateFunc::Avg) => {
+            aggregate_values[0] = values.iter().fold(aggregate_values[0], |a, v| a.wrapping_add(*v));
+            aggregate_values[1] += values.len() as i64;
+        },
+        Some(AggregateFunc::Max) => aggregate_values[0] = values.iter().copied().fold(aggregate_values[0], i64::max),
+        Some(AggregateFunc::Min) => aggregate_values[0] = values.iter().copied().fold(aggregate_values[0], i64::min),
+        Some(AggregateFunc::Count) | Some(AggregateFunc::CountStar) => aggregate_values[0] += values.len() as i64,
+        Some(AggregateFunc::CountDistinct) => state.distinct.extend(values),
+        Some(AggregateFunc::Any) => aggregate_values[0] |= values.iter().fold(0, |a, v| a | v),
+        Some(AggregateFunc::All) => aggregate_values[0] &= values.iter().fold(1, |a, v| a & v),
+        Some(AggregateFunc::VarPop) | Some(AggregateFunc::VarSamp)
+        | Some(AggregateFunc::StddevPop) | Some(AggregateFunc::StddevSamp) => {
+            aggregate_values[0] += values.len() as i64;
+            for value in values {
+                aggregate_values[1] = aggregate_values[1].wrapping_add(*value);
+                aggregate_values[2] = aggregate_values[2].wrapping_add(value.wrapping_mul(*value));
+            }
+        },
+        None => {
+            for value in values {
+                result_consumer(Atom::Num(*value));
+            }
+        },
+    }
+}
+
+/// Runs a bound query without parameters like `query::run_query` does, with the same results.
+/// Queries with a join or that refer to nullable columns or strings aren't supported and return an
+/// error before any results, just like divisions by zero do once they happen.
+pub fn run_query_vectorized(query: &Query, table: &Table, mut result_consumer: impl FnMut(Atom)) -> Result<(), String> {
+    if query.join.is_some() {
+        return Err("The vectorized interpreter can't run joins".to_string());
+    }
+    let referenced_columns = query.referenced_columns();
+    if referenced_columns.iter().any(|&column| table.schema().columns()[column].nullable) {
+        return Err("The vectorized interpreter doesn't support NULLs".to_string());
+    }
+    if referenced_columns.iter().any(|&column| table.schema().value_type(column) == DataType::Str) {
+        return Err("The vectorized interpreter doesn't support strings".to_string());
+    }
+    let mut state = AggregateState::new(query.aggregate);
+    let mut top_k = query.order_by.as_ref().map(|order_by| TopK::new(query.limit, order_by.descending));
+    // Without an order the scan stops once there are `limit` results
+    let mut remaining = query.limit.filter(|_| query.order_by.is_none());
+    let column_types = table.schema().value_types();
+    let mut batch = Batch { columns: vec![Vec::new(); table.schema().column_count()], column_types: &column_types };
+    let all_rows = (0..BATCH_SIZE as u32).collect::<Vec<_>>();
+    for start in (0..table.rows()).step_by(BATCH_SIZE) {
+        if remaining == Some(0) {
+            break;
+        }
+        let rows: Range<usize> = start..(start + BATCH_SIZE).min(table.rows());
+        for &column in &referenced_columns {
+            batch.columns[column].clear();
+            table.read_column(column, rows.clone(), &mut batch.columns[column]);
+        }
+        let all_rows = &all_rows[..rows.len()];
+        let selection = match &query.filter {
+            Some(filter) => batch.select(filter, all_rows).map_err(|e| e.to_string())?,
+            None => all_rows.to_vec(),
+        };
+        let mut values = batch.eval(&query.expr, &selection).map_err(|e| e.to_string())?;
+        if let (Some(order_by), Some(top_k)) = (&query.order_by, &mut top_k) {
+            // Booleans already are 0 or 1, so they are ordered like in the other engines
+            for (key, value) in batch.eval(&order_by.expr, &selection).map_err(|e| e.to_string())?.into_iter().zip(values) {
+                top_k.add(key, value);
+            }
+            continue;
+        }
+        if let Some(remaining) = &mut remaining {
+            values.truncate(*remaining);
+            *remaining -= values.len();
+        }
+        aggregate(&mut state, &values, &mut result_consumer);
+    }
+    if let Some(top_k) = &mut top_k {
+        top_k.take_sorted().into_iter().for_each(|value| result_consumer(Atom::Num(value)));
+    }
+    state.finish(&mut result_consumer);
+    Ok(())
+}